@@ -0,0 +1,277 @@
+//! Summary statistics over a directory of analysed polyforms, replacing `scripts/summary.js`.
+//!
+//! The layout is the one `scripts/analysis.sh` writes: `analysis/<size>/<p>/` holds a Perseus
+//! `*_betti.txt` and optionally an `analysis` binary `*.sa.txt` (the site perimeter) per sample.
+//! Perseus writes one row per filtration value, the value followed by the Betti numbers, and the
+//! last row describes the whole complex.
+//!
+//! Tables start with the columns `summary.js` prints, the probability and the mean of every column
+//! of the betti files under its headers, so that its output is a prefix of every line. The sample
+//! count and the spread of each column follow, and then the site perimeter.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Mean, sample standard deviation and standard error of the mean of some values
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub std_err: f64,
+}
+
+impl Summary {
+    pub fn of(values: &[f64]) -> Summary {
+        let count = values.len();
+        if count == 0 {
+            return Summary::default();
+        }
+
+        let mean = values.iter().sum::<f64>() / count as f64;
+        let std_dev = if count > 1 {
+            (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (count - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+
+        Summary { count, mean, std_dev, std_err: std_dev / (count as f64).sqrt() }
+    }
+}
+
+/// Statistics for one probability directory
+#[derive(Clone, Debug)]
+pub struct Row {
+    pub probability: String,
+    pub samples: usize,
+    /// The first column of the betti files, which `summary.js` calls connected components
+    pub filtration: Summary,
+    // B0, B1, ... in order
+    pub betti: Vec<Summary>,
+    pub perimeter: Option<Summary>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Format {
+    Tsv,
+    Csv,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "tsv" => Ok(Format::Tsv),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("expected tsv, csv or json, got {:?}", s)),
+        }
+    }
+}
+
+fn invalid(path: &Path, message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message))
+}
+
+/// The filtration value and Betti numbers from the last row of a Perseus betti file
+pub fn read_betti(path: &Path) -> io::Result<(f64, Vec<f64>)> {
+    let contents = fs::read_to_string(path)?;
+    let row = contents
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .ok_or_else(|| invalid(path, "empty betti file".to_string()))?;
+
+    let mut values = row
+        .split_whitespace()
+        .map(|n| n.parse::<f64>().map_err(|e| invalid(path, format!("invalid Betti number {:?}: {}", n, e))))
+        .collect::<io::Result<Vec<f64>>>()?;
+    let filtration = values.remove(0);
+    Ok((filtration, values))
+}
+
+fn read_perimeter(path: &Path) -> io::Result<f64> {
+    let contents = fs::read_to_string(path)?;
+    contents
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| invalid(path, format!("invalid site perimeter: {}", e)))
+}
+
+/// Summarizes one `<p>` directory
+pub fn summarize(probability: &str, dir: &Path) -> io::Result<Row> {
+    let mut filtrations = Vec::new();
+    let mut betti: Vec<Vec<f64>> = Vec::new();
+    let mut perimeters = Vec::new();
+    let mut samples = 0;
+
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with("_betti.txt") {
+            samples += 1;
+            let (filtration, numbers) = read_betti(&entry.path())?;
+            filtrations.push(filtration);
+            for (k, b) in numbers.into_iter().enumerate() {
+                if betti.len() <= k {
+                    betti.push(Vec::new());
+                }
+                betti[k].push(b);
+            }
+        } else if name.ends_with(".sa.txt") {
+            perimeters.push(read_perimeter(&entry.path())?);
+        }
+    }
+
+    Ok(Row {
+        probability: probability.to_string(),
+        samples,
+        filtration: Summary::of(&filtrations),
+        betti: betti.iter().map(|values| Summary::of(values)).collect(),
+        perimeter: if perimeters.is_empty() { None } else { Some(Summary::of(&perimeters)) },
+    })
+}
+
+/// Summarizes every `<p>` directory of `analysis/<size>`, sorted by probability
+pub fn aggregate(dir: &Path) -> io::Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            rows.push(summarize(&entry.file_name().to_string_lossy(), &entry.path())?);
+        }
+    }
+
+    rows.sort_by(|a, b| match (a.probability.parse::<f64>(), b.probability.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.total_cmp(&y),
+        _ => a.probability.cmp(&b.probability),
+    });
+    Ok(rows)
+}
+
+fn json_summary(summary: &Summary) -> String {
+    format!(
+        "{{\"mean\": {}, \"std\": {}, \"stderr\": {}}}",
+        summary.mean, summary.std_dev, summary.std_err
+    )
+}
+
+/// Formats the rows as a table with one column per statistic, or a JSON array of objects
+pub fn format(rows: &[Row], format: &Format) -> String {
+    let separator = match format {
+        Format::Tsv => "\t",
+        Format::Csv => ",",
+        Format::Json => {
+            let objects: Vec<String> = rows
+                .iter()
+                .map(|row| {
+                    format!(
+                        "  {{\"probability\": {:?}, \"samples\": {}, \"filtration\": {}, \"betti\": [{}], \"site_perimeter\": {}}}",
+                        row.probability,
+                        row.samples,
+                        json_summary(&row.filtration),
+                        row.betti.iter().map(json_summary).collect::<Vec<_>>().join(", "),
+                        row.perimeter.as_ref().map(json_summary).unwrap_or_else(|| "null".to_string())
+                    )
+                })
+                .collect();
+            return format!("[\n{}\n]", objects.join(",\n"));
+        }
+    };
+
+    let betti_count = rows.iter().map(|row| row.betti.len()).max().unwrap_or(0);
+    let statistics = |summary: Option<&Summary>, fields: &mut Vec<String>| match summary {
+        Some(s) if s.count > 0 => fields.extend([s.std_dev.to_string(), s.std_err.to_string()]),
+        _ => fields.extend(["".to_string(), "".to_string()]),
+    };
+
+    // the columns of summary.js first
+    let mut header = vec!["Perc. Prob.".to_string(), "Connected Comp.".to_string()];
+    header.extend((0..betti_count).map(|k| format!("B{}", k)));
+    header.extend(["samples".to_string(), "filtration_std".to_string(), "filtration_stderr".to_string()]);
+    for k in 0..betti_count {
+        header.extend([format!("B{}_std", k), format!("B{}_stderr", k)]);
+    }
+    header.extend(["perimeter_mean".to_string(), "perimeter_std".to_string(), "perimeter_stderr".to_string()]);
+
+    let mut lines = vec![header.join(separator)];
+    for row in rows {
+        let mean = |summary: Option<&Summary>| summary.filter(|s| s.count > 0).map_or(String::new(), |s| s.mean.to_string());
+        let mut fields = vec![row.probability.clone(), mean(Some(&row.filtration))];
+        fields.extend((0..betti_count).map(|k| mean(row.betti.get(k))));
+        fields.push(row.samples.to_string());
+        statistics(Some(&row.filtration), &mut fields);
+        for k in 0..betti_count {
+            statistics(row.betti.get(k), &mut fields);
+        }
+        fields.push(mean(row.perimeter.as_ref()));
+        statistics(row.perimeter.as_ref(), &mut fields);
+        lines.push(fields.join(separator));
+    }
+
+    lines.join("\n")
+}
+
+/// Options of the `aggregate` binary and the `polyforms aggregate` subcommand
+#[derive(clap::Args, Debug)]
+pub struct Options {
+    /// Number of cells of the polyforms, the subdirectory of the analysis directory to summarize
+    #[arg(short, long)]
+    pub size: usize,
+
+    /// Directory laid out as <size>/<p>/*_betti.txt, as written by scripts/analysis.sh
+    #[arg(long, default_value = "../../analysis")]
+    pub root: PathBuf,
+
+    /// tsv, csv or json
+    #[arg(long, default_value = "tsv")]
+    pub table: Format,
+}
+
+/// Summarizes the directory the options point to as a table
+pub fn run(options: &Options) -> io::Result<String> {
+    let rows = aggregate(&options.root.join(options.size.to_string()))?;
+    Ok(format(&rows, &options.table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_start_with_the_columns_of_summary_js() {
+        let root = std::env::temp_dir().join(format!("polyforms-aggregate-{}", std::process::id()));
+        for (p, name, row) in [("0.5", "a", "1 1 0 0"), ("0.5", "b", "1 1 2 1"), ("0.5", "c", "1 1 1 0"), ("0.25", "a", "1 1 0 0")] {
+            fs::create_dir_all(root.join("10").join(p)).unwrap();
+            fs::write(root.join("10").join(p).join(format!("{}.txt_betti.txt", name)), format!("{}\n", row)).unwrap();
+        }
+        fs::write(root.join("10/0.5/a.sa.txt"), "12\n").unwrap();
+
+        let table = run(&Options { size: 10, root: root.clone(), table: Format::Tsv }).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        // what summary.js prints for the same directory
+        let expected = ["Perc. Prob.\tConnected Comp.\tB0\tB1\tB2", "0.25\t1\t1\t0\t0", "0.5\t1\t1\t1\t0.3333333333333333"];
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), expected.len());
+        for (line, expected) in lines.iter().zip(expected) {
+            assert!(line.starts_with(&format!("{}\t", expected)), "{:?} doesn't start with {:?}", line, expected);
+        }
+        assert!(lines[2].ends_with("\t12\t0\t0"));
+    }
+
+    #[test]
+    fn summary_uses_the_sample_deviation() {
+        let summary = Summary::of(&[1.0, 2.0, 3.0]);
+        assert_eq!(summary.mean, 2.0);
+        assert_eq!(summary.std_dev, 1.0);
+        assert!((summary.std_err - 1.0 / 3f64.sqrt()).abs() < 1e-12);
+        assert_eq!(Summary::of(&[]), Summary::default());
+    }
+}
@@ -0,0 +1,195 @@
+//! Simulated annealing toward polycubes that maximize or minimize some quantity.
+//!
+//! Moves are proposed as in `shuffle`, so they respect the polycube's connectivity, constraints
+//! and region, and are accepted with probability min(1, w exp(-ΔE / T)), where w is the ratio of
+//! the polycube's own weights, E is the objective (negated when maximizing) and T follows a
+//! temperature schedule. The best state seen is kept and restored into the polycube at the end,
+//! ready to be exported.
+
+use std::str::FromStr;
+
+use crate::storage::CellStorage;
+use crate::Polycube;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Objective {
+    /// First Betti number, the number of independent tunnels
+    B1,
+    /// Second Betti number, the number of enclosed cavities
+    B2,
+    /// Site perimeter
+    Perimeter,
+    /// Volume of the tight bounding box
+    BoundingBoxVolume,
+}
+
+impl Objective {
+    pub fn evaluate<S: CellStorage>(&self, pfm: &Polycube<S>) -> f64 {
+        match self {
+            Objective::B1 => pfm.betti_numbers()[1] as f64,
+            Objective::B2 => pfm.cavity_count() as f64,
+            Objective::Perimeter => pfm.insertable_locations.len() as f64,
+            Objective::BoundingBoxVolume => match pfm.tight_bounds() {
+                Some((min, max)) => {
+                    (max.0 - min.0 + 1) as f64 * (max.1 - min.1 + 1) as f64 * (max.2 - min.2 + 1) as f64
+                }
+                None => 0.0,
+            },
+        }
+    }
+}
+
+impl FromStr for Objective {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Objective, String> {
+        match s {
+            "b1" => Ok(Objective::B1),
+            "b2" => Ok(Objective::B2),
+            "perimeter" => Ok(Objective::Perimeter),
+            "volume" => Ok(Objective::BoundingBoxVolume),
+            _ => Err(format!("expected b1, b2, perimeter or volume, got {:?}", s)),
+        }
+    }
+}
+
+/// How the temperature falls from start to end over the steps of the schedule
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    Linear { start: f64, end: f64 },
+    Geometric { start: f64, end: f64 },
+}
+
+impl Schedule {
+    /// Fails unless both temperatures are finite and positive, which the Boltzmann factor divides
+    /// by and the geometric schedule takes the logarithm of
+    fn check(&self) -> Result<(), String> {
+        let (Schedule::Linear { start, end } | Schedule::Geometric { start, end }) = *self;
+        for (name, temperature) in [("start", start), ("end", end)] {
+            if !(temperature.is_finite() && temperature > 0.0) {
+                return Err(format!("the {} temperature has to be finite and positive, not {}", name, temperature));
+            }
+        }
+        Ok(())
+    }
+
+    /// Temperature at step i of steps
+    pub fn temperature(&self, i: usize, steps: usize) -> f64 {
+        let t = if steps > 1 { i as f64 / (steps - 1) as f64 } else { 1.0 };
+        match *self {
+            Schedule::Linear { start, end } => start + (end - start) * t,
+            Schedule::Geometric { start, end } => start * (end / start).powf(t),
+        }
+    }
+}
+
+pub struct Annealer {
+    objective: Objective,
+    maximize: bool,
+    schedule: Schedule,
+
+    // number of temperatures, and shuffles at each temperature
+    steps: usize,
+    shuffles_per_step: usize,
+}
+
+impl Annealer {
+    /// Fails if a temperature of the schedule isn't finite and positive
+    pub fn new(objective: Objective, maximize: bool, schedule: Schedule, steps: usize, shuffles_per_step: usize) -> Result<Annealer, String> {
+        schedule.check()?;
+        Ok(Annealer { objective, maximize, schedule, steps, shuffles_per_step })
+    }
+
+    fn energy<S: CellStorage>(&self, pfm: &Polycube<S>) -> f64 {
+        let value = self.objective.evaluate(pfm);
+        if self.maximize {
+            -value
+        } else {
+            value
+        }
+    }
+
+    /// Anneals the polycube in place, leaving it in the best state seen. Returns the value of the
+    /// objective in that state
+    pub fn run<S: CellStorage>(&self, pfm: &mut Polycube<S>) -> f64 {
+        let mut energy = self.energy(pfm);
+        let mut best_energy = energy;
+        let mut best: Vec<(i32, i32, i32)> = pfm.complex.cells().collect();
+
+        for step in 0..self.steps {
+            let temperature = self.schedule.temperature(step, self.steps);
+
+            for _ in 0..self.shuffles_per_step {
+                // the objective joins the polycube's own weights in a single Metropolis test
+                let mut proposed = energy;
+                let mut boltzmann = |pfm: &Polycube<S>| {
+                    proposed = self.energy(pfm);
+                    (-(proposed - energy) / temperature).exp()
+                };
+                if pfm.step(Some(&mut boltzmann)).is_none() {
+                    continue;
+                }
+
+                energy = proposed;
+                if energy < best_energy {
+                    best_energy = energy;
+                    best = pfm.complex.cells().collect();
+                }
+            }
+        }
+
+        pfm.set_cells(best);
+
+        if self.maximize {
+            -best_energy
+        } else {
+            best_energy
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng, Dist};
+
+    fn annealer(objective: Objective, maximize: bool) -> Annealer {
+        Annealer::new(objective, maximize, Schedule::Geometric { start: 2.0, end: 0.05 }, 20, 100).unwrap()
+    }
+
+    #[test]
+    fn temperatures_are_finite_and_positive() {
+        for (start, end) in [(-1.0, 0.05), (0.0, 0.05), (2.0, 0.0), (f64::NAN, 0.05), (2.0, f64::INFINITY)] {
+            assert!(Annealer::new(Objective::B2, false, Schedule::Geometric { start, end }, 20, 100).is_err());
+            assert!(Annealer::new(Objective::B2, false, Schedule::Linear { start, end }, 20, 100).is_err());
+        }
+        let schedule = Schedule::Geometric { start: 2.0, end: 0.05 };
+        assert!((schedule.temperature(19, 20) - 0.05).abs() < 1e-12);
+    }
+
+    #[test]
+    fn keeps_the_best_state_and_counts_every_move() {
+        rng::seed(33);
+        let mut pfm = Polycube::new(12, Dist::Bernoulli(0.5));
+        let initial = Objective::Perimeter.evaluate(&pfm);
+        let best = annealer(Objective::Perimeter, false).run(&mut pfm);
+
+        assert!(best <= initial);
+        assert_eq!(best, Objective::Perimeter.evaluate(&pfm));
+        assert!(pfm.is_connected());
+
+        let stats = pfm.stats;
+        assert_eq!(stats.proposed, 2000);
+        assert_eq!(stats.proposed, stats.accepted + stats.rejected_by_weight + stats.rejected_as_invalid);
+        assert!(stats.accepted > 0);
+    }
+
+    #[test]
+    fn maximizes() {
+        rng::seed(33);
+        let mut pfm = Polycube::new(8, Dist::Uniform);
+        let best = annealer(Objective::BoundingBoxVolume, true).run(&mut pfm);
+        assert_eq!(best, Objective::BoundingBoxVolume.evaluate(&pfm));
+        assert!(best > 8.0);
+    }
+}
@@ -0,0 +1,17 @@
+use blocks::aggregate::{self, Options};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[command(flatten)]
+    options: Options,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    match aggregate::run(&args.options) {
+        Ok(table) => println!("{}", table),
+        Err(e) => eprintln!("Error {}", e),
+    }
+}
@@ -14,8 +14,8 @@ fn main() {
 
     let analysis = fs::read(args.file).expect("Couldn't read file");
     let analysis_string = &std::str::from_utf8(&analysis).expect("Expected UTF8 Encoding"); 
-    let pfm = match Polycube::import_analysis(&analysis_string) {
-        Ok(pfm_res) => pfm_res.1,
+    let pfm: Polycube = match Polycube::import_analysis(&analysis_string) {
+        Ok(pfm) => pfm,
         Err(e) => {
             eprintln!("Error {}", e);
             return;
@@ -0,0 +1,712 @@
+use blocks::*;
+use blocks::aggregate;
+use blocks::bond::BondAnimal;
+use blocks::container::{self, Container, Sample};
+use blocks::cli::{CameraOptions, Export, GridOptions, Output, SampleOptions, Storage};
+use blocks::storage::{BitGrid, CellSet, CellStorage};
+use blocks::voxel::Palette;
+use clap::{Parser, Subcommand};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+enum Lattice {
+    /// Polycubes, sets of cells connected through faces
+    Site,
+    /// Bond animals, sets of edges connected through endpoints
+    Bond
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+enum Format {
+    Scad,
+    Tuples,
+    Analysis,
+    /// ASCII STL mesh of the surface
+    Stl,
+    /// Binary STL mesh of the surface
+    StlBinary,
+    /// Wavefront OBJ mesh of the surface
+    Obj,
+    /// MagicaVoxel model
+    Vox,
+    /// Run-length encoded voxel cube
+    Binvox,
+    /// JSON with the sampling parameters and observables
+    Json,
+    /// JSON on one line per polyform
+    Ndjson,
+    /// NumPy occupancy array, laid out by --grid-size, --center and --perimeter-channel
+    Npy,
+    /// Orthographic projection, shaded by depth
+    Svg,
+    /// Bond animals only, one edge per line
+    Edges,
+    /// Bond animals only, CHomP cubical complex
+    Cubical
+}
+
+/// Options every subcommand understands
+#[derive(clap::Args, Debug)]
+struct Common {
+    /// Seed the random number generator, so that the run can be reproduced
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+
+    #[arg(long, global = true, value_enum, default_value = "site")]
+    lattice: Lattice,
+
+    /// Weight polyforms by p^n (1-p)^t like bernoulli percolation, instead of uniformly
+    #[arg(short, long, global = true, value_parser = cli::parse_probability)]
+    bernoulli: Option<f64>,
+
+    /// How polyforms are printed
+    #[arg(short, long, global = true, value_enum, default_value = "tuples")]
+    format: Format,
+
+    /// Reject analysis files with duplicate or disconnected cells
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Pin the cells of analysis files born at time 1 when the rest are born at time 2, which is
+    /// how analysis exports write pinned cells
+    #[arg(long, global = true)]
+    pinned_births: bool,
+
+    #[command(flatten)]
+    grid: GridOptions,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "polyforms", about = "Sample, render, convert and analyze polyforms")]
+struct Cli {
+    #[command(flatten)]
+    common: Common,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Shuffle a polyform and print it. While rendering, space pauses and resumes, n steps one
+    /// move, and s, t, a and m save it as SCAD, tuples, analysis or STL
+    Sample {
+        #[command(flatten)]
+        options: Box<SampleOptions>,
+
+        /// Render every given number of shuffles, and print once the shuffles run out
+        #[arg(long)]
+        live: Option<usize>,
+
+        /// Render the polyform after printing it
+        #[arg(long)]
+        render: bool,
+
+        /// Without a window, write a PNG frame to this directory every --frame-every shuffles,
+        /// for animations
+        #[arg(long)]
+        frames: Option<PathBuf>,
+
+        #[arg(long, default_value_t = 1000)]
+        frame_every: usize,
+
+        #[command(flatten)]
+        camera: CameraOptions,
+    },
+    /// Render a polyform read from a file in the tuple, analysis, .vox or .binvox format
+    Render {
+        input: PathBuf,
+
+        /// Write a PNG image here instead of opening a window
+        #[arg(long)]
+        png: Option<PathBuf>,
+
+        #[command(flatten)]
+        camera: CameraOptions,
+    },
+    /// Print a polyform read from a file in another format
+    Convert {
+        input: PathBuf,
+
+        /// How vox exports color the cells: uniform, pinned, components or inserted:x,y,z
+        #[arg(long, default_value = "pinned")]
+        palette: Palette,
+
+        /// The axis svg projections look down, x, y or z
+        #[arg(long, default_value = "z", value_parser = cli::parse_axis)]
+        axis: usize,
+
+        /// Pixels per cell in svg projections
+        #[arg(long, default_value_t = 16)]
+        scale: usize,
+    },
+    /// Write a polyform read from a file as a stack of z-slices, marking occupied cells in blue,
+    /// the site perimeter in green and enclosed cavities in red
+    Slices {
+        input: PathBuf,
+
+        /// Directory the slices are written to, as slice-000.png and so on from the lowest z
+        #[arg(long)]
+        dir: PathBuf,
+
+        /// Write grayscale PGMs instead of PNGs: occupied cells are 255, cavities 170 and the
+        /// site perimeter 85
+        #[arg(long)]
+        pgm: bool,
+
+        /// Pixels per cell
+        #[arg(long, default_value_t = 16)]
+        scale: usize,
+
+        /// Don't mark the site perimeter
+        #[arg(long)]
+        no_perimeter: bool,
+
+        /// Don't mark cavities
+        #[arg(long)]
+        no_cavities: bool,
+    },
+    /// Pack every polycube in files, directories of them or containers into one binary
+    /// container. Samples keep the distribution, seed and shuffles their file records, and
+    /// --bernoulli, --seed and --steps fill in what it doesn't
+    Pack {
+        inputs: Vec<PathBuf>,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Shuffles each sample was produced with, if its file doesn't record them
+        #[arg(long)]
+        steps: Option<u64>,
+    },
+    /// Write the occupancy arrays of polycube files, directories of them or containers to a
+    /// NumPy .npz archive. With --grid-size they are stacked into one array named occupancy,
+    /// otherwise each is its own array, arr_0, arr_1 and so on
+    Npz {
+        inputs: Vec<PathBuf>,
+
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Print the samples of a container in --format, or write each to its own file
+    Unpack {
+        input: PathBuf,
+
+        /// Only the sample at this position, counting from 0
+        #[arg(long)]
+        index: Option<usize>,
+
+        /// Write sample i to <dir>/<i>.<extension> instead of printing
+        #[arg(long)]
+        dir: Option<PathBuf>,
+
+        /// Print the metadata of every sample instead
+        #[arg(long)]
+        list: bool,
+    },
+    /// Print the size, site perimeter, Betti numbers and shape statistics of a polyform read
+    /// from a file
+    Analyze {
+        input: PathBuf,
+    },
+    /// Print the volume, surface and cells of every enclosed cavity of a polyform read from a file
+    Cavities {
+        input: PathBuf,
+
+        /// Fill the cavities and print the filled polyform in --format instead
+        #[arg(long)]
+        fill: bool,
+    },
+    /// Print a table of the shape statistics of polycube files, directories of them or
+    /// containers, one row per polycube
+    Observables {
+        inputs: Vec<PathBuf>,
+
+        /// tsv, csv or json
+        #[arg(long, default_value = "tsv")]
+        table: aggregate::Format,
+    },
+    /// Count the fixed polyforms of every size up to the given one
+    Enumerate {
+        size: usize,
+
+        /// Print every polyform of the given size instead of the counts
+        #[arg(long)]
+        list: bool,
+    },
+    /// Time shuffles
+    Bench {
+        #[arg(short, long, default_value_t = 1000)]
+        length: usize,
+
+        #[arg(short, long, default_value_t = 1_000_000)]
+        shuffles: usize,
+
+        #[arg(long, value_enum, default_value = "hash")]
+        storage: Storage,
+    },
+    /// Summarize the Betti numbers and site perimeters under <root>/<size>/<p>/
+    Aggregate(aggregate::Options),
+    /// Run the units of a sweep described by a TOML or JSON spec that aren't complete yet
+    Experiment {
+        spec: PathBuf,
+
+        /// Number of units sampled at once, by default one per core
+        #[arg(long)]
+        threads: Option<usize>,
+
+        /// Print how many units are complete, and which shard each missing one belongs to,
+        /// instead of running the rest
+        #[arg(long)]
+        status: bool,
+
+        /// Only run every Nth unit starting at the ith (from 0). Defaults to the slice of the
+        /// SLURM array task, if running in one. Sharded runs need a seed, in the spec or from
+        /// --seed, so that a unit that is rerun comes out the same
+        #[arg(long)]
+        shard: Option<experiment::Shard>,
+    },
+}
+
+impl Common {
+    fn dist(&self) -> Dist {
+        match self.bernoulli {
+            Some(p) => Dist::Bernoulli(p),
+            None => Dist::Uniform,
+        }
+    }
+
+    /// The export for polycubes, or None if the format is for bond animals
+    fn export(&self) -> Option<Export> {
+        match self.format {
+            Format::Scad => Some(Export::Scad),
+            Format::Tuples => Some(Export::Tuples),
+            Format::Analysis => Some(Export::Analysis),
+            Format::Stl => Some(Export::Stl),
+            Format::StlBinary => Some(Export::StlBinary),
+            Format::Obj => Some(Export::Obj),
+            Format::Vox => Some(Export::Vox),
+            Format::Binvox => Some(Export::Binvox),
+            Format::Json => Some(Export::Json),
+            Format::Ndjson => Some(Export::Ndjson),
+            Format::Npy => Some(Export::Npy),
+            Format::Svg => Some(Export::Svg),
+            Format::Edges | Format::Cubical => None,
+        }
+    }
+
+    fn bond_export(&self, animal: &BondAnimal) -> Option<String> {
+        match self.format {
+            Format::Edges => Some(animal.export_edges()),
+            Format::Cubical => Some(animal.export_cubical()),
+            _ => None,
+        }
+    }
+}
+
+/// Every file among the paths, descending into directories, in sorted order
+fn files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut found = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)
+                .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+            entries.sort();
+            found.extend(files(&entries)?);
+        } else {
+            found.push(path.clone());
+        }
+    }
+    Ok(found)
+}
+
+/// The polycubes in files, directories of them, or containers, each with where it was read from
+fn polycubes(inputs: &[PathBuf], common: &Common) -> Result<Vec<(String, Polycube)>, String> {
+    let mut polycubes = Vec::new();
+    for input in files(inputs)? {
+        let read = read_all(&input, common)?;
+        // files holding several polycubes label each with its position
+        let several = read.len() > 1;
+        for (i, pfm) in read.into_iter().enumerate() {
+            let label = if several { format!("{}#{}", input.display(), i) } else { input.display().to_string() };
+            polycubes.push((label, pfm));
+        }
+    }
+    Ok(polycubes)
+}
+
+/// What an input records, or else what the command line gives. Fails if they disagree
+fn reconcile<T: PartialEq + std::fmt::Debug>(what: &str, recorded: Option<T>, given: Option<T>) -> Result<Option<T>, String> {
+    match (recorded, given) {
+        (Some(recorded), Some(given)) if recorded != given => {
+            Err(format!("the input records the {} {:?}, but the command line gives {:?}", what, recorded, given))
+        }
+        (recorded, given) => Ok(recorded.or(given)),
+    }
+}
+
+/// A sample in the format given by --format, as bytes
+fn unpack(common: &Common, sample: &Sample) -> Result<Vec<u8>, String> {
+    match sample.metadata.lattice {
+        container::Lattice::Site => {
+            let export = common.export().ok_or_else(|| format!("{:?} isn't a format for polycubes", common.format))?;
+            let mut pfm: Polycube = sample.polycube()?;
+            // the container knows how the sample was produced, unlike the polycube rebuilt from it
+            let record = || {
+                let mut record = pfm.record(true);
+                record.seed = sample.metadata.seed;
+                record.shuffles = sample.metadata.steps;
+                record
+            };
+            match export {
+                Export::Json => Ok(record().to_json().into_bytes()),
+                Export::Ndjson => Ok(record().to_ndjson().into_bytes()),
+                _ => cli::export(&mut pfm, &export, &common.grid.grid()),
+            }
+        }
+        container::Lattice::Bond => {
+            let mut animal = BondAnimal::new(0, sample.metadata.dist.clone());
+            animal.bonds.extend(sample.bonds());
+            animal.compute_perimeter();
+            let edges = common.bond_export(&animal).ok_or_else(|| format!("{:?} isn't a format for bond animals", common.format))?;
+            Ok(edges.into_bytes())
+        }
+    }
+}
+
+/// Reads a polyform from a file in any format it can be imported from, reading analysis files
+/// as --strict and --pinned-births say
+fn read(input: &Path, common: &Common) -> Result<Polycube, String> {
+    match read_analysis(input, common)? {
+        Some(pfm) => Ok(pfm),
+        None => Polycube::load(input),
+    }
+}
+
+/// Every polycube in a file, as `Polycube::load_all` reads them
+fn read_all(input: &Path, common: &Common) -> Result<Vec<Polycube>, String> {
+    match read_analysis(input, common)? {
+        Some(pfm) => Ok(vec![pfm]),
+        None => Polycube::load_all(input),
+    }
+}
+
+/// With --strict, reads an analysis file rejecting duplicate and disconnected cells, and with
+/// --pinned-births, pinning the cells born first. None for other formats, which are always
+/// strict, or without either flag
+fn read_analysis(input: &Path, common: &Common) -> Result<Option<Polycube>, String> {
+    if !common.strict && !common.pinned_births {
+        return Ok(None);
+    }
+
+    let bytes = fs::read(input).map_err(|e| format!("Couldn't read {}: {}", input.display(), e))?;
+    if load::Format::detect(&bytes) != load::Format::Analysis {
+        return Ok(None);
+    }
+    let analysis = String::from_utf8(bytes).map_err(|e| format!("{} isn't text: {}", input.display(), e))?;
+    let options = perseus::Options { reject_duplicates: common.strict, reject_disconnected: common.strict, pinned_births: common.pinned_births };
+    match Polycube::parse_analysis(&analysis, &options) {
+        Ok((pfm, _)) => Ok(Some(pfm)),
+        Err(e) => Err(format!("Couldn't parse {}: {}", input.display(), e)),
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let common = &cli.common;
+
+    if let Some(seed) = common.seed {
+        rng::seed(seed);
+    }
+
+    if let Err(e) = run(common, cli.command) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(common: &Common, command: Command) -> Result<(), String> {
+    let site_only = |what: &str| format!("{} is only supported on the site lattice", what);
+    let wrong_format = || format!("{:?} isn't a format for the {:?} lattice", common.format, common.lattice);
+
+    match command {
+        Command::Sample { options, live, render, frames, frame_every, camera } => match common.lattice {
+            Lattice::Site => {
+                let export = common.export().ok_or_else(wrong_format)?;
+                let output = match (live, frames) {
+                    (_, Some(dir)) => Output::Frames { every: frame_every, dir, camera: camera.camera(), export },
+                    (Some(render_step), None) => Output::Live(render_step, export),
+                    (None, None) if render => Output::PrintAndRender(export),
+                    (None, None) => Output::Print(export),
+                };
+                cli::sample(&options, common.dist(), &common.grid.grid(), output);
+            }
+            Lattice::Bond => {
+                let flag = options.site_only_flag().or(live.map(|_| "--live")).or(render.then_some("--render")).or(frames.map(|_| "--frames"));
+                if let Some(flag) = flag {
+                    return Err(site_only(&format!("Sampling with {}", flag)));
+                }
+                let mut animal = BondAnimal::new(options.length.unwrap_or(0), common.dist());
+                animal.shuffle(options.shuffles.unwrap_or(0));
+                println!("{}", common.bond_export(&animal).ok_or_else(wrong_format)?);
+            }
+        },
+        Command::Render { input, png, camera } => {
+            if common.lattice == Lattice::Bond {
+                return Err(site_only("Rendering"));
+            }
+            let pfm = read(&input, common)?;
+            match png {
+                Some(path) => pfm.render_image(&camera.camera(), None).save_png(&path).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?,
+                None => pfm.render(),
+            }
+        }
+        Command::Convert { input, palette, axis, scale } => {
+            if common.lattice == Lattice::Bond {
+                return Err(site_only("Converting"));
+            }
+            let export = common.export().ok_or_else(wrong_format)?;
+            let mut pfm = read(&input, common)?;
+            match export {
+                Export::Vox => cli::print_bytes(&pfm.export_vox(&palette)?, &export)?,
+                Export::Svg => cli::print_bytes(pfm.export_svg(axis, scale).as_bytes(), &export)?,
+                _ => cli::print(&mut pfm, &export, &common.grid.grid())?,
+            }
+        }
+        Command::Pack { inputs, output, steps } => {
+            if common.lattice == Lattice::Bond {
+                return Err(site_only("Packing"));
+            }
+            let file = File::create(&output).map_err(|e| format!("Couldn't create {}: {}", output.display(), e))?;
+            let write_error = |e: std::io::Error| format!("Couldn't write {}: {}", output.display(), e);
+            let mut writer = container::Writer::new(BufWriter::new(file)).map_err(write_error)?;
+            let mut packed = 0;
+            for input in files(&inputs)? {
+                let read = match read_analysis(&input, common)? {
+                    Some(pfm) => vec![(pfm, load::Provenance::default())],
+                    None => Polycube::load_provenance(&input)?,
+                };
+                for (mut pfm, provenance) in read {
+                    let conflict = |e: String| format!("Couldn't pack {}: {}", input.display(), e);
+                    let dist = reconcile("distribution", provenance.dist, common.bernoulli.map(Dist::Bernoulli)).map_err(conflict)?;
+                    let seed = reconcile("seed", provenance.seed, common.seed).map_err(conflict)?;
+                    let steps = reconcile("shuffles", provenance.steps, steps).map_err(conflict)?;
+                    pfm.dist = dist.unwrap_or(Dist::Uniform);
+                    writer.write(&Sample::of_polycube(&pfm, seed, steps.unwrap_or(0))).map_err(write_error)?;
+                    packed += 1;
+                }
+            }
+            writer.finish().map_err(write_error)?;
+            eprintln!("Packed {} samples into {}", packed, output.display());
+        }
+        Command::Npz { inputs, output } => {
+            let grid = common.grid.grid();
+            let arrays = polycubes(&inputs, common)?.iter().map(|(_, pfm)| pfm.occupancy(&grid)).collect::<Result<Vec<_>, String>>()?;
+
+            let files = match grid.size {
+                Some(_) => vec![("occupancy.npy".to_string(), npy::Array::stack(&arrays)?.encode_npy())],
+                None => arrays.iter().enumerate().map(|(i, array)| (format!("arr_{}.npy", i), array.encode_npy())).collect(),
+            };
+            fs::write(&output, npy::npz(&files)).map_err(|e| format!("Couldn't write {}: {}", output.display(), e))?;
+            eprintln!("Wrote {} arrays to {}", arrays.len(), output.display());
+        }
+        Command::Unpack { input, index, dir, list } => {
+            let read_error = |e: std::io::Error| format!("Couldn't read {}: {}", input.display(), e);
+            let file = File::open(&input).map_err(read_error)?;
+            let mut container = Container::open(BufReader::new(file)).map_err(read_error)?;
+            let indices = match index {
+                Some(index) => vec![index],
+                None => (0..container.len()).collect(),
+            };
+
+            for i in indices {
+                let sample = container.get(i).map_err(read_error)?;
+                if list {
+                    let metadata = &sample.metadata;
+                    let seed = metadata.seed.map_or("-".to_string(), |seed| seed.to_string());
+                    println!("{}\t{:?}\t{}\t{:?}\t{}\t{}", i, metadata.lattice, sample.cells.len(), metadata.dist, seed, metadata.steps);
+                    continue;
+                }
+
+                let bytes = unpack(common, &sample)?;
+                match &dir {
+                    Some(dir) => {
+                        let extension = common.export().map_or("txt", |export| export.extension());
+                        let path = dir.join(format!("{}.{}", i, extension));
+                        fs::create_dir_all(dir).and_then(|_| fs::write(&path, &bytes)).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+                    }
+                    None => match common.export() {
+                        Some(export) => cli::print_bytes(&bytes, &export)?,
+                        None => println!("{}", String::from_utf8_lossy(&bytes)),
+                    },
+                }
+            }
+        }
+        Command::Slices { input, dir, pgm, scale, no_perimeter, no_cavities } => {
+            if common.lattice == Lattice::Bond {
+                return Err(site_only("Slicing"));
+            }
+            let pfm = read(&input, common)?;
+            let style = slices::SliceStyle { perimeter: !no_perimeter, cavities: !no_cavities, scale };
+            let format = if pgm { slices::SliceFormat::Pgm } else { slices::SliceFormat::Png };
+            let written = pfm.write_slices(&dir, &style, format).map_err(|e| format!("Couldn't write slices to {}: {}", dir.display(), e))?;
+            eprintln!("Wrote {} slices to {}", written, dir.display());
+        }
+        Command::Analyze { input } => {
+            if common.lattice == Lattice::Bond {
+                return Err(site_only("Analyzing"));
+            }
+            let pfm = read(&input, common)?;
+            let [b0, b1, b2] = pfm.betti_numbers();
+            let observables = pfm.observables();
+            for (column, value) in observables::Observables::COLUMNS.iter().zip(observables.values()) {
+                println!("{}\t{}", column, value);
+            }
+            println!("betti\t{} {} {}", b0, b1, b2);
+        }
+        Command::Cavities { input, fill } => {
+            if common.lattice == Lattice::Bond {
+                return Err(site_only("Finding cavities"));
+            }
+            let mut pfm = read(&input, common)?;
+            if fill {
+                let export = common.export().ok_or_else(wrong_format)?;
+                let filled = pfm.fill_cavities();
+                eprintln!("Filled {} cells", filled);
+                cli::print(&mut pfm, &export, &common.grid.grid())?;
+                return Ok(());
+            }
+
+            println!("cavity\tvolume\tsurface\tcells");
+            for (i, cavity) in pfm.cavities().iter().enumerate() {
+                let cells: Vec<String> = cavity.cells.iter().map(|cell| format!("({}, {}, {})", cell.0, cell.1, cell.2)).collect();
+                println!("{}\t{}\t{}\t[{}]", i, cavity.volume(), cavity.surface, cells.join(", "));
+            }
+        }
+        Command::Observables { inputs, table } => {
+            let rows: Vec<(String, observables::Observables)> = polycubes(&inputs, common)?.into_iter().map(|(path, pfm)| (path, pfm.observables())).collect();
+            let separator = match table {
+                aggregate::Format::Tsv => "\t",
+                aggregate::Format::Csv => ",",
+                aggregate::Format::Json => {
+                    let objects: Vec<serde_json::Value> = rows.iter().map(|(path, observables)| {
+                        let mut object = serde_json::json!(observables);
+                        object["path"] = serde_json::json!(path);
+                        object
+                    }).collect();
+                    println!("{}", serde_json::to_string_pretty(&objects).map_err(|e| e.to_string())?);
+                    return Ok(());
+                }
+            };
+            println!("path{}{}", separator, observables::Observables::COLUMNS.join(separator));
+            for (path, observables) in rows {
+                println!("{}{}{}", path, separator, observables.values().join(separator));
+            }
+        }
+        Command::Enumerate { size, list } => {
+            if list {
+                match common.lattice {
+                    Lattice::Site => {
+                        let export = common.export().ok_or_else(wrong_format)?;
+                        let grid = common.grid.grid();
+                        enumerate::polycubes(size, |cells| {
+                            if cells.len() == size {
+                                let mut pfm = Polycube::new(1, common.dist());
+                                pfm.set_cells(cells.iter().copied());
+                                if let Err(e) = cli::print(&mut pfm, &export, &grid) {
+                                    eprintln!("{}", e);
+                                }
+                            }
+                        });
+                    }
+                    Lattice::Bond => {
+                        if common.export().is_some() {
+                            return Err(wrong_format());
+                        }
+                        enumerate::bond_animals(size, |bonds| {
+                            if bonds.len() == size {
+                                let mut animal = BondAnimal::new(0, common.dist());
+                                animal.bonds.extend(bonds.iter().copied());
+                                animal.compute_perimeter();
+                                println!("{}", common.bond_export(&animal).expect("format was checked"));
+                            }
+                        });
+                    }
+                }
+            } else {
+                let counts = match common.lattice {
+                    Lattice::Site => enumerate::count_polycubes(size),
+                    Lattice::Bond => enumerate::count_bond_animals(size),
+                };
+                for (k, count) in counts.iter().enumerate() {
+                    println!("{}\t{}", k + 1, count);
+                }
+            }
+        }
+        Command::Bench { length, shuffles, storage } => {
+            let elapsed = match (&common.lattice, storage) {
+                (Lattice::Site, Storage::Hash) => bench_site::<CellSet>(length, shuffles, common.dist()),
+                (Lattice::Site, Storage::Grid) => bench_site::<BitGrid>(length, shuffles, common.dist()),
+                (Lattice::Bond, _) => {
+                    let mut animal = BondAnimal::new(length, common.dist());
+                    let start = Instant::now();
+                    animal.shuffle(shuffles);
+                    start.elapsed().as_secs_f64()
+                }
+            };
+            println!("{} shuffles of {} in {:.3}s, {:.0} shuffles/s", shuffles, length, elapsed, shuffles as f64 / elapsed);
+        }
+        Command::Aggregate(options) => {
+            println!("{}", aggregate::run(&options).map_err(|e| e.to_string())?);
+        }
+        Command::Experiment { spec, threads, status, shard } => {
+            let mut spec = experiment::Spec::load(&spec)?;
+            spec.grid = common.grid.grid();
+            let shard = match shard {
+                Some(shard) => Some(shard),
+                None => experiment::Shard::from_slurm()?,
+            };
+            let units = spec.units();
+
+            if status {
+                let completed = spec.completed().map_err(|e| e.to_string())?;
+                let done = units.iter().filter(|unit| completed.contains(&unit.path(&spec.export))).count();
+                println!("{} of {} units complete", done, units.len());
+                for (position, unit) in units.iter().enumerate().filter(|(_, unit)| !completed.contains(&unit.path(&spec.export))) {
+                    match &shard {
+                        Some(shard) => println!("missing\t{}\tshard {}/{}", unit.path(&spec.export).display(), position % shard.count, shard.count),
+                        None => println!("missing\t{}", unit.path(&spec.export).display()),
+                    }
+                }
+                return Ok(());
+            }
+
+            let units = match &shard {
+                Some(shard) => {
+                    spec.seed = spec.seed.or(common.seed);
+                    if spec.seed.is_none() {
+                        return Err("Sharded runs need a seed: set seed in the spec or pass --seed".to_string());
+                    }
+                    shard.select(&units)
+                }
+                None => units,
+            };
+            let threads = threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            let ran = spec.run(&units, threads, &spec.manifest_path(shard.as_ref()))?;
+            eprintln!("Ran {} units, {} were already complete", ran, units.len() - ran);
+        }
+    }
+
+    Ok(())
+}
+
+fn bench_site<S: CellStorage>(length: usize, shuffles: usize, dist: Dist) -> f64 {
+    let mut pfm = Polycube::<S>::with_storage(length, dist);
+    let start = Instant::now();
+    pfm.shuffle(shuffles);
+    start.elapsed().as_secs_f64()
+}
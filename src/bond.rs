@@ -0,0 +1,289 @@
+//! Bond animals: connected sets of lattice edges, the clusters of bond percolation.
+//!
+//! Two bonds are adjacent when they share an endpoint, so every bond has 10 neighboring bonds
+//! (5 more at each end). The bond perimeter is the set of vacant bonds adjacent to the animal,
+//! and under Bernoulli bond percolation an animal with perimeter t has weight p^n (1-p)^t.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::BuildHasherDefault;
+
+use rand::Rng;
+use rand::distributions::{Bernoulli, Distribution};
+
+use crate::rng;
+use crate::{perimeter_probability, Dist};
+
+/// The lattice edge from the vertex (x, y, z) to (x, y, z) plus the unit vector along the axis
+/// (0 for x, 1 for y, 2 for z)
+pub type Bond = ((i32, i32, i32), u8);
+
+/// A `HashSet` of bonds with a fixed hasher, like `storage::CellSet`
+pub type BondSet = HashSet<Bond, BuildHasherDefault<DefaultHasher>>;
+
+fn step(vertex: &(i32, i32, i32), axis: u8, by: i32) -> (i32, i32, i32) {
+    match axis {
+        0 => (vertex.0 + by, vertex.1, vertex.2),
+        1 => (vertex.0, vertex.1 + by, vertex.2),
+        _ => (vertex.0, vertex.1, vertex.2 + by),
+    }
+}
+
+/// Both endpoints of a bond
+pub fn endpoints(bond: &Bond) -> ((i32, i32, i32), (i32, i32, i32)) {
+    (bond.0, step(&bond.0, bond.1, 1))
+}
+
+// O(1)
+/// All 6 bonds incident to a vertex
+fn incident(vertex: &(i32, i32, i32)) -> [Bond; 6] {
+    [
+        (*vertex, 0),
+        (*vertex, 1),
+        (*vertex, 2),
+        (step(vertex, 0, -1), 0),
+        (step(vertex, 1, -1), 1),
+        (step(vertex, 2, -1), 2),
+    ]
+}
+
+// O(1)
+/// All 10 bonds that share an endpoint with the given bond
+pub(crate) fn adjacent(bond: &Bond) -> Vec<Bond> {
+    let (a, b) = endpoints(bond);
+    incident(&a)
+        .into_iter()
+        .chain(incident(&b))
+        .filter(|other| other != bond)
+        .collect()
+}
+
+fn has_neighbor(set: &BondSet, bond: &Bond) -> bool {
+    adjacent(bond).iter().any(|other| set.contains(other))
+}
+
+fn get_random(set: &BondSet) -> Bond {
+    let index = rng::thread_rng().gen_range(0..set.len());
+    *set.iter().nth(index).expect("index is in range")
+}
+
+/// Represents a bond animal on the cubic lattice
+pub struct BondAnimal {
+    // The actual animal
+    pub bonds: BondSet,
+
+    // keeps track of every vacant bond that shares an endpoint with the animal, i.e. the bond
+    // perimeter. Moves insert into this set, and its size drives the Bernoulli weighting
+    pub perimeter: BondSet,
+
+    pub dist: Dist,
+}
+
+impl BondAnimal {
+    // O(n)
+    /// A straight line of len bonds along z
+    pub fn new(len: usize, dist: Dist) -> BondAnimal {
+        let mut animal = BondAnimal {
+            bonds: BondSet::default(),
+            perimeter: BondSet::default(),
+            dist,
+        };
+
+        for i in 0..len {
+            animal.insert(((0, 0, i as i32), 2));
+        }
+
+        animal
+    }
+
+    // O(1)
+    fn insert(&mut self, bond: Bond) -> bool {
+        self.perimeter.remove(&bond);
+
+        for neighbor in adjacent(&bond) {
+            if !self.bonds.contains(&neighbor) {
+                self.perimeter.insert(neighbor);
+            }
+        }
+
+        self.bonds.insert(bond)
+    }
+
+    // O(1)
+    fn remove(&mut self, bond: &Bond) -> bool {
+        let removal = self.bonds.remove(bond);
+
+        if has_neighbor(&self.bonds, bond) {
+            self.perimeter.insert(*bond);
+        }
+
+        // perimeter bonds that were only touching the removed bond leave the perimeter
+        for neighbor in adjacent(bond) {
+            if self.perimeter.contains(&neighbor) && !has_neighbor(&self.bonds, &neighbor) {
+                self.perimeter.remove(&neighbor);
+            }
+        }
+
+        removal
+    }
+
+    /// Recomputes the bond perimeter from scratch
+    // O(n)
+    pub fn compute_perimeter(&mut self) {
+        let mut perimeter = BondSet::default();
+        for bond in &self.bonds {
+            for neighbor in adjacent(bond) {
+                if !self.bonds.contains(&neighbor) {
+                    perimeter.insert(neighbor);
+                }
+            }
+        }
+        self.perimeter = perimeter;
+    }
+
+    /// Stack + DFS over bonds that share endpoints
+    pub fn is_connected(&self) -> bool {
+        let first = match self.bonds.iter().next() {
+            Some(first) => *first,
+            None => return true,
+        };
+
+        let mut stack = vec![first];
+        let mut visited = HashSet::new();
+        visited.insert(first);
+
+        while let Some(bond) = stack.pop() {
+            for neighbor in adjacent(&bond) {
+                if self.bonds.contains(&neighbor) && visited.insert(neighbor) {
+                    stack.push(neighbor);
+                }
+            }
+        }
+
+        visited.len() == self.bonds.len()
+    }
+
+    /// Same move set as `Polycube::shuffle`: remove a random bond, insert it at a random
+    /// perimeter bond, and undo the move if the animal is disconnected or the Bernoulli coin flip
+    /// rejects the change in perimeter
+    pub fn shuffle(&mut self, times: usize) -> Option<(Bond, Bond)> {
+        // a lone bond leaves no perimeter to insert into once it's removed, and there's nothing
+        // to remove from an empty animal
+        if self.bonds.len() < 2 {
+            return None;
+        }
+
+        let mut last_shuffled = None;
+
+        for _ in 0..times {
+            let perimeter_old = self.perimeter.len();
+
+            let removed = get_random(&self.bonds);
+            self.remove(&removed);
+            let inserted = get_random(&self.perimeter);
+            self.insert(inserted);
+
+            let accepted = match self.dist {
+                Dist::Bernoulli(p) => {
+                    let probability = perimeter_probability(perimeter_old, self.perimeter.len(), p);
+                    Bernoulli::new(probability).unwrap().sample(&mut rng::thread_rng())
+                }
+                Dist::Uniform => true,
+            };
+
+            if accepted && self.is_connected() {
+                last_shuffled = Some((inserted, removed));
+            } else {
+                self.remove(&inserted);
+                self.insert(removed);
+            }
+        }
+
+        last_shuffled
+    }
+
+    /// Exports the animal as a graph, one edge per line as "x1 y1 z1 x2 y2 z2"
+    pub fn export_edges(&self) -> String {
+        let mut export = String::new();
+
+        for bond in &self.bonds {
+            let (a, b) = endpoints(bond);
+            export.push_str(&format!("{} {} {} {} {} {}\n", a.0, a.1, a.2, b.0, b.1, b.2));
+        }
+
+        export
+    }
+
+    /// Exports the animal as a cubical 1-complex for homology software such as CHomP: one
+    /// elementary cube per line, written as a product of elementary intervals like
+    /// "[0,1]x[2]x[3]". Faces (the vertices) are implied by the edges
+    pub fn export_cubical(&self) -> String {
+        let mut export = String::new();
+
+        for bond in &self.bonds {
+            let (a, _) = endpoints(bond);
+            let coordinates = [a.0, a.1, a.2];
+            let intervals: Vec<String> = (0..3)
+                .map(|axis| {
+                    if axis == bond.1 as usize {
+                        format!("[{},{}]", coordinates[axis], coordinates[axis] + 1)
+                    } else {
+                        format!("[{}]", coordinates[axis])
+                    }
+                })
+                .collect();
+            export.push_str(&intervals.join("x"));
+            export.push('\n');
+        }
+
+        export
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_have_four_perimeter_bonds_per_vertex_and_two_more_at_the_ends() {
+        for len in 1..6 {
+            assert_eq!(BondAnimal::new(len, Dist::Uniform).perimeter.len(), 4 * len + 6);
+        }
+        assert_eq!(adjacent(&((0, 0, 0), 0)).len(), 10);
+    }
+
+    #[test]
+    fn animals_too_small_to_move_stay_put() {
+        for len in 0..2 {
+            let mut animal = BondAnimal::new(len, Dist::Uniform);
+            assert_eq!(animal.shuffle(10), None);
+            assert_eq!(animal.bonds.len(), len);
+            assert_eq!(animal.perimeter.len(), if len == 0 { 0 } else { 10 });
+        }
+    }
+
+    #[test]
+    fn shuffles_keep_the_perimeter_and_connectivity() {
+        rng::seed(28);
+        let mut animal = BondAnimal::new(25, Dist::Bernoulli(0.4));
+        for _ in 0..40 {
+            animal.shuffle(50);
+            let incremental = animal.perimeter.clone();
+            animal.compute_perimeter();
+            assert_eq!(incremental, animal.perimeter);
+            assert!(animal.is_connected());
+            assert_eq!(animal.bonds.len(), 25);
+        }
+    }
+
+    #[test]
+    fn exports_every_bond() {
+        let animal = BondAnimal::new(2, Dist::Uniform);
+        let mut edges: Vec<String> = animal.export_edges().lines().map(str::to_string).collect();
+        edges.sort_unstable();
+        assert_eq!(edges, ["0 0 0 0 0 1", "0 0 1 0 0 2"]);
+        let mut cubes: Vec<String> = animal.export_cubical().lines().map(str::to_string).collect();
+        cubes.sort_unstable();
+        assert_eq!(cubes, ["[0]x[0]x[0,1]", "[0]x[0]x[1,2]"]);
+    }
+}
@@ -0,0 +1,121 @@
+//! Finding the enclosed cavities that B2 counts, and filling them.
+//!
+//! `enclosed_cells` floods the complement from a corner of the bounding box padded by one, which
+//! reaches every vacant cell outside, and keeps the vacant cells it didn't reach. Those split into
+//! cavities by face-connectivity, the same connectivity the complement has in `cavity_count`.
+
+use std::collections::HashSet;
+
+use crate::storage::CellStorage;
+use crate::{get_neighbors, get_vacant_neighbors, Polycube};
+
+type Cell = (i32, i32, i32);
+
+/// One enclosed void
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cavity {
+    /// The vacant cells of the cavity, sorted
+    pub cells: Vec<Cell>,
+    /// Faces between the cavity and the polycube around it
+    pub surface: usize,
+}
+
+impl Cavity {
+    pub fn volume(&self) -> usize {
+        self.cells.len()
+    }
+}
+
+impl<S: CellStorage> Polycube<S> {
+    // O(volume of the bounding box)
+    /// Every enclosed cavity, ordered by their lowest cell
+    pub fn cavities(&self) -> Vec<Cavity> {
+        let enclosed = self.enclosed_cells();
+        let mut starts: Vec<Cell> = enclosed.iter().copied().collect();
+        starts.sort_unstable();
+
+        let mut visited = HashSet::new();
+        let mut cavities = Vec::new();
+        for start in starts {
+            if !visited.insert(start) {
+                continue;
+            }
+            let mut cells = vec![start];
+            let mut stack = vec![start];
+            while let Some(cell) = stack.pop() {
+                // a cavity's vacant neighbors are all in the cavity, by the flood fill
+                for neighbor in get_vacant_neighbors(&self.complex, &cell) {
+                    if enclosed.contains(&neighbor) && visited.insert(neighbor) {
+                        cells.push(neighbor);
+                        stack.push(neighbor);
+                    }
+                }
+            }
+            cells.sort_unstable();
+            let surface = cells.iter().map(|cell| get_neighbors(&self.complex, cell).len()).sum();
+            cavities.push(Cavity { cells, surface });
+        }
+        cavities
+    }
+
+    // O(volume of the bounding box)
+    /// Fills every cavity with cells, which adds to the size of the polycube, and returns how many
+    /// cells were added
+    pub fn fill_cavities(&mut self) -> usize {
+        let mut enclosed: Vec<Cell> = self.enclosed_cells().into_iter().collect();
+        enclosed.sort_unstable();
+        for cell in &enclosed {
+            self.insert(*cell);
+        }
+        enclosed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{block, hollow_cube, polycube};
+    use crate::observables::Moments;
+
+    #[test]
+    fn a_hollow_cube_has_one_cavity_of_one_cell() {
+        let hollow = hollow_cube();
+        assert_eq!(hollow.cavities(), [Cavity { cells: vec![(1, 1, 1)], surface: 6 }]);
+        assert_eq!(hollow.cavities()[0].volume(), 1);
+        assert_eq!(hollow.betti_numbers()[2], 1);
+    }
+
+    #[test]
+    fn cavities_split_by_face_connectivity() {
+        // two voids in one box, and one of two cells that touch through a face
+        let cells = block(7, 3, 3).filter(|cell| ![(1, 1, 1), (3, 1, 1), (5, 1, 1)].contains(cell));
+        let mut pfm = polycube(cells);
+        pfm.remove(&(4, 1, 1));
+        let cavities = pfm.cavities();
+        assert_eq!(cavities.iter().map(Cavity::volume).collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(cavities[1].cells, [(3, 1, 1), (4, 1, 1), (5, 1, 1)]);
+        // 4 faces around each of the three cells and the two ends
+        assert_eq!(cavities[1].surface, 14);
+        assert_eq!(cavities.len(), pfm.cavity_count());
+
+        // a void that only touches the outside through an edge is still enclosed
+        let mut edge = hollow_cube();
+        edge.remove(&(2, 2, 1));
+        edge.remove(&(2, 1, 2));
+        assert_eq!(edge.cavities().len(), 1);
+    }
+
+    #[test]
+    fn filling_removes_every_cavity() {
+        let mut hollow = polycube(block(4, 4, 4).filter(|cell| !(1..3).contains(&cell.0) || !(1..3).contains(&cell.1) || !(1..3).contains(&cell.2)));
+        assert_eq!(hollow.cavities()[0].volume(), 8);
+        assert_eq!(hollow.fill_cavities(), 8);
+        assert!(hollow.cavities().is_empty());
+        assert_eq!(hollow.betti_numbers(), [1, 0, 0]);
+        assert_eq!(hollow.complex.len(), 64);
+        // filling goes through insert, so the incremental counts stay up to date
+        assert_eq!(hollow.moments, Moments::of(hollow.complex.cells()));
+        assert_eq!(hollow.surface_area(), 6 * 16);
+        assert_eq!(hollow.fill_cavities(), 0);
+    }
+}
@@ -0,0 +1,598 @@
+//! Command line options and the sampling pipeline shared by the `main` and `polyforms` binaries.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::anneal::{Annealer, Objective, Schedule};
+use crate::headless::Camera;
+use crate::nfold::{NFoldWay, TimeAverage};
+use crate::npy::Grid;
+use crate::region::{Region, Shape};
+use crate::storage::{BitGrid, CellSet, CellStorage};
+use crate::voxel::Palette;
+use crate::{energy, Constraint, Dist, Polycube};
+
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Export {
+    Scad,
+    Tuples,
+    Analysis,
+    /// ASCII STL mesh of the surface, with coplanar faces merged
+    Stl,
+    /// Binary STL mesh of the surface, with coplanar faces merged
+    StlBinary,
+    /// Wavefront OBJ mesh of the surface, with coplanar faces merged
+    Obj,
+    /// MagicaVoxel model, with pinned cells in orange
+    Vox,
+    /// Run-length encoded voxel cube
+    Binvox,
+    /// Indented JSON object with the cells, sampling parameters, acceptance statistics and
+    /// observables
+    Json,
+    /// The JSON object on one line, for streams of samples
+    Ndjson,
+    /// NumPy uint8 occupancy array, cropped to the bounding box
+    Npy,
+    /// Orthographic SVG projection looking down the z axis, shaded by depth
+    Svg
+}
+
+impl Export {
+    /// File extension for files in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Export::Scad => "scad",
+            Export::Tuples => "tuples.txt",
+            Export::Analysis => "txt",
+            Export::Stl | Export::StlBinary => "stl",
+            Export::Obj => "obj",
+            Export::Vox => "vox",
+            Export::Binvox => "binvox",
+            Export::Json => "json",
+            Export::Ndjson => "ndjson",
+            Export::Npy => "npy",
+            Export::Svg => "svg",
+        }
+    }
+
+    /// Whether the format is bytes rather than text
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Export::StlBinary | Export::Vox | Export::Binvox | Export::Npy)
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+pub enum Storage {
+    Hash,
+    Grid
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub enum Restrict {
+    Tree,
+    NoCavities
+}
+
+/// What to do with the polycube once it's sampled
+#[derive(Clone, Debug)]
+pub enum Output {
+    /// Print it in the given format
+    Print(Export),
+    /// Print it, then render it
+    PrintAndRender(Export),
+    /// Render every given number of shuffles while sampling, and print it in the given format
+    /// once the shuffles run out
+    Live(usize, Export),
+    /// Write a PNG frame to the directory every given number of shuffles without a window, then
+    /// print it in the given format
+    Frames { every: usize, dir: PathBuf, camera: Camera, export: Export },
+}
+
+/// Camera for images rendered without a window
+#[derive(clap::Args, Debug)]
+pub struct CameraOptions {
+    /// Rotation of the camera around the z axis, in degrees
+    #[arg(long, default_value_t = 45.0)]
+    pub yaw: f32,
+
+    /// Elevation of the camera above the xy plane, in degrees. The default is isometric
+    #[arg(long, default_value_t = 35.264)]
+    pub pitch: f32,
+
+    /// Image width in pixels
+    #[arg(long, default_value_t = 512)]
+    pub width: usize,
+
+    /// Image height in pixels
+    #[arg(long, default_value_t = 512)]
+    pub height: usize,
+
+    /// Pixels per cell, instead of fitting the polycube in the image
+    #[arg(long)]
+    pub scale: Option<f32>,
+}
+
+impl CameraOptions {
+    pub fn camera(&self) -> Camera {
+        Camera { yaw: self.yaw, pitch: self.pitch, width: self.width, height: self.height, scale: self.scale }
+    }
+}
+
+/// Layout of NumPy occupancy arrays
+#[derive(clap::Args, Debug)]
+pub struct GridOptions {
+    /// Pad the array to X,Y,Z cells, or N cells along every axis, instead of cropping it to the
+    /// bounding box
+    #[arg(long, global = true, value_parser = parse_size)]
+    pub grid_size: Option<[usize; 3]>,
+
+    /// Put the centroid in the middle of the array
+    #[arg(long, global = true)]
+    pub center: bool,
+
+    /// Add the site perimeter as a second channel, making the array [channel, x, y, z]
+    #[arg(long, global = true)]
+    pub perimeter_channel: bool,
+}
+
+impl GridOptions {
+    pub fn grid(&self) -> Grid {
+        Grid { size: self.grid_size, center: self.center, perimeter: self.perimeter_channel }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SampleOptions {
+    #[arg(short, long)]
+    pub shuffles: Option<usize>,
+
+    #[arg(short, long, required_unless_present = "from")]
+    pub length: Option<usize>,
+
+    /// Start from the polycube in this file, in any format it can be imported from, instead of a
+    /// rod of the given length
+    #[arg(long)]
+    pub from: Option<PathBuf>,
+
+    /// Check connectivity with the dynamic connectivity structure instead of a DFS per shuffle
+    #[arg(long)]
+    pub dynamic_connectivity: bool,
+
+    /// How the cells of the polyform are stored
+    #[arg(long, value_enum, default_value = "hash")]
+    pub storage: Storage,
+
+    /// Only sample polycubes of the given class, may be repeated
+    #[arg(long, value_enum)]
+    pub restrict: Vec<Restrict>,
+
+    /// Confine the polycube to box:LxMxK, slab:AXIS:MIN:MAX or wall (z >= 0, touching z = 0)
+    #[arg(long)]
+    pub confine: Option<Shape>,
+
+    /// Energy of each face touching the walls of the confining region, in units of kT
+    #[arg(long, default_value_t = 0.0)]
+    pub contact_energy: f64,
+
+    /// Pin the cell at x,y,z so that it never moves, may be repeated
+    #[arg(long, value_parser = parse_cell)]
+    pub pin: Vec<(i32, i32, i32)>,
+
+    /// Weight moves by exp(-ΔE), with E a linear combination like perimeter=0.5,contacts=-1 of
+    /// the perimeter, contacts and surface energy models
+    #[arg(long)]
+    pub energy: Option<String>,
+
+    /// Instead of sampling, anneal toward polycubes that minimize b1, b2, perimeter or volume
+    /// (of the bounding box) over the given number of shuffles, and export the best one
+    #[arg(long)]
+    pub anneal: Option<Objective>,
+
+    /// Anneal toward the maximum of the objective instead of the minimum
+    #[arg(long)]
+    pub maximize: bool,
+
+    /// Initial annealing temperature
+    #[arg(long, default_value_t = 2.0, value_parser = parse_temperature)]
+    pub temperature_start: f64,
+
+    /// Final annealing temperature, reached geometrically
+    #[arg(long, default_value_t = 0.05, value_parser = parse_temperature)]
+    pub temperature_end: f64,
+
+    /// Number of temperatures the shuffles are spread over
+    #[arg(long, default_value_t = 100, value_parser = parse_positive)]
+    pub anneal_steps: usize,
+
+    /// Sample with the rejection-free n-fold way instead of shuffling, running for as long as
+    /// the given number of shuffles would have taken, and report time-weighted averages
+    #[arg(long)]
+    pub rejection_free: bool,
+}
+
+impl SampleOptions {
+    /// The first flag that is set and that only polycubes support, if any. Bond animals only
+    /// take a length and a number of shuffles
+    pub fn site_only_flag(&self) -> Option<&'static str> {
+        [
+            (self.from.is_some(), "--from"),
+            (self.dynamic_connectivity, "--dynamic-connectivity"),
+            (self.storage != Storage::Hash, "--storage"),
+            (!self.restrict.is_empty(), "--restrict"),
+            (self.confine.is_some(), "--confine"),
+            (self.contact_energy != 0.0, "--contact-energy"),
+            (!self.pin.is_empty(), "--pin"),
+            (self.energy.is_some(), "--energy"),
+            (self.anneal.is_some(), "--anneal"),
+            (self.rejection_free, "--rejection-free"),
+        ]
+        .into_iter()
+        .find(|(set, _)| *set)
+        .map(|(_, flag)| flag)
+    }
+}
+
+pub fn parse_cell(s: &str) -> Result<(i32, i32, i32), String> {
+    let coordinates = s.split(',').map(|c| c.trim().parse::<i32>().map_err(|e| e.to_string())).collect::<Result<Vec<i32>, String>>()?;
+    match coordinates.as_slice() {
+        [x, y, z] => Ok((*x, *y, *z)),
+        _ => Err(format!("expected x,y,z, got {:?}", s))
+    }
+}
+
+/// Parses an integer of at least 1
+pub fn parse_positive(s: &str) -> Result<usize, String> {
+    match s.trim().parse::<usize>().map_err(|e| e.to_string())? {
+        0 => Err("expected at least 1, got 0".to_string()),
+        n => Ok(n),
+    }
+}
+
+/// Parses a probability strictly between 0 and 1
+pub fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>().map_err(|e| e.to_string())? {
+        p if p > 0.0 && p < 1.0 => Ok(p),
+        p => Err(format!("expected a probability between 0 and 1, got {}", p)),
+    }
+}
+
+/// Parses a finite temperature above 0
+pub fn parse_temperature(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>().map_err(|e| e.to_string())? {
+        t if t.is_finite() && t > 0.0 => Ok(t),
+        t => Err(format!("expected a finite temperature above 0, got {}", t)),
+    }
+}
+
+/// Parses N or X,Y,Z
+pub fn parse_size(s: &str) -> Result<[usize; 3], String> {
+    let sizes = s.split(',').map(|n| n.trim().parse::<usize>().map_err(|e| e.to_string())).collect::<Result<Vec<usize>, String>>()?;
+    match sizes.as_slice() {
+        [n] => Ok([*n; 3]),
+        [x, y, z] => Ok([*x, *y, *z]),
+        _ => Err(format!("expected N or X,Y,Z, got {:?}", s))
+    }
+}
+
+/// Parses x, y or z as 0, 1 or 2
+pub fn parse_axis(s: &str) -> Result<usize, String> {
+    match s {
+        "x" => Ok(0),
+        "y" => Ok(1),
+        "z" => Ok(2),
+        _ => Err(format!("expected x, y or z, got {:?}", s))
+    }
+}
+
+/// Exports in the given format, laying out NumPy arrays on the grid. Only fails for formats that
+/// can't hold every polycube
+pub fn export<S: CellStorage>(pfm: &mut Polycube<S>, export: &Export, grid: &Grid) -> Result<Vec<u8>, String> {
+    Ok(match export {
+        Export::Scad => pfm.export_scad().into_bytes(),
+        Export::Tuples => pfm.export().into_bytes(),
+        Export::Analysis => pfm.export_analysis().into_bytes(),
+        Export::Stl => pfm.export_stl().into_bytes(),
+        Export::StlBinary => pfm.export_stl_binary(),
+        Export::Obj => pfm.export_obj().into_bytes(),
+        Export::Vox => pfm.export_vox(&Palette::Pinned)?,
+        Export::Binvox => pfm.export_binvox(),
+        Export::Json => pfm.export_json(true).into_bytes(),
+        Export::Ndjson => pfm.export_ndjson(true).into_bytes(),
+        Export::Npy => pfm.export_npy(grid)?,
+        Export::Svg => pfm.export_svg(2, 16).into_bytes()
+    })
+}
+
+/// Writes an export to stdout, ending text formats with a newline like println
+pub fn print_bytes(bytes: &[u8], export: &Export) -> Result<(), String> {
+    let mut stdout = std::io::stdout().lock();
+    let written = stdout.write_all(bytes).and_then(|()| if export.is_binary() { Ok(()) } else { stdout.write_all(b"\n") });
+    written.and_then(|()| stdout.flush()).map_err(|e| format!("Couldn't print: {}", e))
+}
+
+/// Exports in the given format and writes it to stdout
+pub fn print<S: CellStorage>(pfm: &mut Polycube<S>, export_type: &Export, grid: &Grid) -> Result<(), String> {
+    print_bytes(&export(pfm, export_type, grid)?, export_type)
+}
+
+/// Samples a polycube and outputs it, laying out NumPy exports on the grid
+pub fn sample(options: &SampleOptions, dist: Dist, grid: &Grid, output: Output) {
+    match options.storage {
+        Storage::Hash => sample_with::<CellSet>(options, dist, grid, output),
+        Storage::Grid => sample_with::<BitGrid>(options, dist, grid, output),
+    }
+}
+
+/// Builds the polycube the options describe, before any shuffling. Prints why on failure
+pub fn build<S: CellStorage>(options: &SampleOptions, dist: Dist) -> Option<Polycube<S>> {
+    let length = options.length.unwrap_or(0);
+    let mut pfm = match (&options.from, options.confine.clone()) {
+        (Some(path), confine) => {
+            let mut pfm = match Polycube::<S>::load(path) {
+                Ok(pfm) => pfm,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return None;
+                }
+            };
+            pfm.dist = dist;
+
+            if let Some(shape) = confine {
+                let region = Region { shape, contact_energy: options.contact_energy };
+                if !pfm.complex.cells().all(|cell| region.contains(&cell)) || !region.is_attached(pfm.complex.cells()) {
+                    eprintln!("The polycube in {} isn't inside the region", path.display());
+                    return None;
+                }
+                pfm.region = Some(region);
+                pfm.compute_insertable();
+            }
+            pfm
+        },
+        (None, Some(shape)) => {
+            let region = Region { shape, contact_energy: options.contact_energy };
+            match Polycube::<S>::confined(length, dist, region) {
+                Some(pfm) => pfm,
+                None => {
+                    eprintln!("The region is too small for a polycube of length {}", length);
+                    return None;
+                }
+            }
+        },
+        (None, None) => Polycube::<S>::with_storage(length, dist)
+    };
+
+    pfm.constraints = options.restrict.iter().map(|restrict| match restrict {
+        Restrict::Tree => Constraint::Tree,
+        Restrict::NoCavities => Constraint::NoCavities,
+    }).collect();
+
+    if !pfm.satisfies_constraints() {
+        eprintln!("The initial polycube doesn't satisfy --restrict, so every move from it would be rejected");
+        return None;
+    }
+
+    if options.anneal.is_some() && options.shuffles.unwrap_or(0) < options.anneal_steps {
+        eprintln!("--anneal needs at least one shuffle per temperature, so --shuffles must be at least --anneal-steps ({})", options.anneal_steps);
+        return None;
+    }
+
+    if let Some(energy) = &options.energy {
+        match energy.parse::<energy::Linear>() {
+            Ok(energy) => pfm.energy = Some(Box::new(energy)),
+            Err(e) => {
+                eprintln!("Invalid --energy: {}", e);
+                return None;
+            }
+        }
+    }
+
+    for cell in &options.pin {
+        if !pfm.pin(*cell) {
+            eprintln!("Can't pin {:?}, it isn't part of the initial polycube", cell);
+            return None;
+        }
+    }
+    if !options.pin.is_empty() && pfm.pinned().len() == pfm.complex.len() {
+        eprintln!("Every cell of the polycube is pinned, so none of them can move");
+        return None;
+    }
+
+    if options.dynamic_connectivity {
+        pfm.enable_dynamic_connectivity();
+    }
+
+    Some(pfm)
+}
+
+fn sample_with<S: CellStorage + 'static>(options: &SampleOptions, dist: Dist, grid: &Grid, output: Output) {
+    // live rendering and frames show the plain shuffle chain
+    if let Output::Live(..) | Output::Frames { .. } = output {
+        if options.anneal.is_some() || options.rejection_free {
+            eprintln!("--anneal and --rejection-free can't be combined with --live or --frames");
+            return;
+        }
+    }
+
+    let mut pfm = match build::<S>(options, dist) {
+        Some(pfm) => pfm,
+        None => return,
+    };
+
+    // if you specify both, you'll get a pre-shuffled polyform so the less interesting shuffles
+    // happen quickly
+
+    let export_type = match &output {
+        Output::Live(render_step, export_type) => {
+            pfm.render_shuffle_as(*render_step, options.shuffles, Some(export_type.clone()), grid);
+            return;
+        },
+        Output::Print(export_type) | Output::PrintAndRender(export_type) => export_type.clone(),
+        Output::Frames { every, dir, camera, export: export_type } => {
+            let shuffles = options.shuffles.unwrap_or(0);
+            match pfm.shuffle_frames(shuffles, *every, camera, dir) {
+                Ok(frames) => eprintln!("Wrote {} frames to {}", frames, dir.display()),
+                Err(e) => {
+                    eprintln!("Couldn't write frames to {}: {}", dir.display(), e);
+                    return;
+                }
+            }
+            if let Err(e) = print(&mut pfm, export_type, grid) {
+                eprintln!("{}", e);
+            }
+            return;
+        },
+    };
+
+    let shuffles = match options.shuffles {
+        Some(shuffles) => shuffles,
+        None => {
+            eprintln!("Use --shuffles <count> to supply the number of shuffles.");
+            return;
+        }
+    };
+
+    match &options.anneal {
+        Some(objective) => {
+            let schedule = Schedule::Geometric { start: options.temperature_start, end: options.temperature_end };
+            let annealer = match Annealer::new(objective.clone(), options.maximize, schedule, options.anneal_steps, shuffles / options.anneal_steps) {
+                Ok(annealer) => annealer,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+            let best = annealer.run(&mut pfm);
+            eprintln!("Best {:?}: {}", objective, best);
+        },
+        None if options.rejection_free => {
+            let mut sampler = match NFoldWay::new(pfm) {
+                Ok(sampler) => sampler,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+            let mut perimeter = TimeAverage::default();
+            let moves = sampler.run(shuffles as f64, |pfm, dt| perimeter.add(pfm.insertable_locations.len() as f64, dt));
+            eprintln!("{} moves, mean site perimeter {}", moves, perimeter.mean());
+            pfm = sampler.pfm;
+        },
+        None => {
+            pfm.shuffle(shuffles);
+        }
+    }
+
+    if let Err(e) = print(&mut pfm, &export_type, grid) {
+        eprintln!("{}", e);
+        return;
+    }
+    if let Output::PrintAndRender(_) = output {
+        // technically does n+1 shuffles, there's an easy fix here but it's not super important.
+        // It was printed already, so don't print it again when the shuffle stops
+        pfm.render_shuffle_as(1, Some(1), None, grid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hollow_cube;
+    use clap::Parser;
+    use std::fs;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        options: SampleOptions,
+    }
+
+    fn options(args: &[&str]) -> SampleOptions {
+        Args::parse_from(std::iter::once("test").chain(args.iter().copied())).options
+    }
+
+    #[derive(Parser)]
+    struct Layout {
+        #[command(flatten)]
+        grid: GridOptions,
+    }
+
+    fn grid(args: &[&str]) -> Grid {
+        Layout::parse_from(std::iter::once("test").chain(args.iter().copied())).grid.grid()
+    }
+
+    /// The shape in the header of a .npy file
+    fn shape(npy: &[u8]) -> String {
+        let header = String::from_utf8_lossy(&npy[10..]);
+        let start = header.find("'shape': (").unwrap() + "'shape': (".len();
+        header[start..start + header[start..].find(')').unwrap()].to_string()
+    }
+
+    #[test]
+    fn npy_exports_follow_the_grid_flags() {
+        let mut pfm = Polycube::new(5, Dist::Uniform);
+        let npy = |pfm: &mut Polycube, args: &[&str]| shape(&export(pfm, &Export::Npy, &grid(args)).unwrap());
+        assert_eq!(npy(&mut pfm, &[]), "1, 1, 5");
+        assert_eq!(npy(&mut pfm, &["--grid-size", "16"]), "16, 16, 16");
+        assert_eq!(npy(&mut pfm, &["--grid-size", "8,9,10", "--perimeter-channel"]), "2, 8, 9, 10");
+        assert_eq!(npy(&mut pfm, &["--perimeter-channel"]), "2, 3, 3, 7");
+        assert!(export(&mut pfm, &Export::Npy, &grid(&["--grid-size", "4"])).is_err());
+    }
+
+    #[test]
+    fn build_rejects_a_start_that_breaks_the_restriction() {
+        let shell = hollow_cube();
+        let path = std::env::temp_dir().join(format!("polyforms-restrict-{}.txt", std::process::id()));
+        fs::write(&path, shell.export()).unwrap();
+        let from = path.to_str().unwrap();
+
+        assert!(build::<CellSet>(&options(&["--from", from]), Dist::Uniform).is_some());
+        assert!(build::<CellSet>(&options(&["--from", from, "--restrict", "no-cavities"]), Dist::Uniform).is_none());
+        assert!(build::<CellSet>(&options(&["--from", from, "--restrict", "tree"]), Dist::Uniform).is_none());
+        assert!(build::<CellSet>(&options(&["--length", "10", "--restrict", "tree", "--restrict", "no-cavities"]), Dist::Uniform).is_some());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn build_rejects_pinning_every_cell() {
+        assert!(build::<CellSet>(&options(&["--length", "2", "--pin", "0,0,0"]), Dist::Uniform).is_some());
+        assert!(build::<CellSet>(&options(&["--length", "2", "--pin", "0,0,0", "--pin", "0,0,1"]), Dist::Uniform).is_none());
+
+        let mut pfm = Polycube::new(2, Dist::Uniform);
+        pfm.pin((0, 0, 0));
+        pfm.pin((0, 0, 1));
+        assert_eq!(pfm.shuffle(10), None);
+    }
+
+    #[test]
+    fn finds_flags_only_polycubes_support() {
+        assert_eq!(options(&["--length", "5", "--shuffles", "10"]).site_only_flag(), None);
+        assert_eq!(options(&["--length", "5", "--restrict", "tree"]).site_only_flag(), Some("--restrict"));
+        assert_eq!(options(&["--length", "5", "--storage", "grid"]).site_only_flag(), Some("--storage"));
+        assert_eq!(options(&["--length", "5", "--rejection-free"]).site_only_flag(), Some("--rejection-free"));
+        assert_eq!(options(&["--length", "5", "--pin", "0,0,0"]).site_only_flag(), Some("--pin"));
+    }
+
+    #[test]
+    fn probabilities_are_strictly_between_0_and_1() {
+        assert_eq!(parse_probability("0.3"), Ok(0.3));
+        for p in ["0", "1", "1.5", "-0.2", "NaN", "p"] {
+            assert!(parse_probability(p).is_err(), "{}", p);
+        }
+    }
+
+    #[test]
+    fn anneal_temperatures_are_positive() {
+        assert!(Args::try_parse_from(["test", "--length", "5", "--anneal", "b2", "--temperature-start=-1"]).is_err());
+        assert!(Args::try_parse_from(["test", "--length", "5", "--anneal", "b2", "--temperature-end", "0"]).is_err());
+        assert!(Args::try_parse_from(["test", "--length", "5", "--anneal", "b2", "--temperature-end", "0.01"]).is_ok());
+    }
+
+    #[test]
+    fn anneal_needs_a_shuffle_per_temperature() {
+        assert!(Args::try_parse_from(["test", "--length", "5", "--anneal", "b1", "--anneal-steps", "0"]).is_err());
+        assert!(build::<CellSet>(&options(&["--length", "5", "--anneal", "b1", "--shuffles", "10"]), Dist::Uniform).is_none());
+        assert!(build::<CellSet>(&options(&["--length", "5", "--anneal", "b1", "--shuffles", "100"]), Dist::Uniform).is_some());
+    }
+}
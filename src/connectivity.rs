@@ -0,0 +1,650 @@
+//! Fully dynamic connectivity for the adjacency graph of a polycube.
+//!
+//! This is the Holm–de Lichtenberg–Thorup structure: every edge has a level, and for every level
+//! i there is a spanning forest F_i of the edges with level >= i, stored as Euler tour trees. Tree
+//! edge deletions search for a replacement edge in the smaller of the two halves, pushing the
+//! edges they look at up one level so that the total work is amortized O(log^2 n) per update.
+//!
+//! Connectivity of the whole polycube is then just a component counter, so a shuffle no longer
+//! needs a DFS to find out whether it broke the polyform into pieces. The treap priorities come
+//! from a generator of their own rather than `rng`, so a seeded run makes the same moves whichever
+//! backend checks them.
+
+use std::collections::{HashMap, HashSet};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const NIL: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+enum NodeKind {
+    Vertex(u32),
+    Arc(u32, u32),
+}
+
+struct Node {
+    left: usize,
+    right: usize,
+    parent: usize,
+    priority: u32,
+    kind: NodeKind,
+
+    // number of nodes in the subtree (used for splitting by position)
+    count: usize,
+    // number of vertex nodes in the subtree (the size of the tree it represents)
+    vertices: usize,
+
+    // vertex nodes: this vertex has non-tree edges on this level
+    nontree: bool,
+    // arc nodes: this is the canonical arc of a tree edge whose level is exactly this level
+    tree: bool,
+
+    // subtree aggregates of the two flags above
+    any_nontree: bool,
+    any_tree: bool,
+}
+
+/// A forest of Euler tour trees, each stored as a treap keyed by position in the tour.
+///
+/// Every vertex has exactly one vertex node, and every tree edge (u, v) has two arc nodes, u->v
+/// and v->u. Rotating the tour (rerooting) and cutting out the part between the two arcs of an
+/// edge are then a handful of splits and merges.
+struct EulerTourForest {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    vertex_nodes: Vec<usize>,
+    arcs: HashMap<(u32, u32), usize>,
+    // treap priorities, independent of the generator that picks moves
+    priorities: StdRng,
+}
+
+impl EulerTourForest {
+    fn new() -> EulerTourForest {
+        EulerTourForest {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            vertex_nodes: Vec::new(),
+            arcs: HashMap::new(),
+            priorities: StdRng::seed_from_u64(0),
+        }
+    }
+
+    fn alloc(&mut self, kind: NodeKind) -> usize {
+        let node = Node {
+            left: NIL,
+            right: NIL,
+            parent: NIL,
+            priority: self.priorities.gen(),
+            kind,
+            count: 1,
+            vertices: if let NodeKind::Vertex(_) = kind { 1 } else { 0 },
+            nontree: false,
+            tree: false,
+            any_nontree: false,
+            any_tree: false,
+        };
+
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    // vertex nodes are created lazily, a vertex without one is a singleton tree
+    fn vertex_node(&mut self, v: u32) -> usize {
+        let v = v as usize;
+        if v >= self.vertex_nodes.len() {
+            self.vertex_nodes.resize(v + 1, NIL);
+        }
+        if self.vertex_nodes[v] == NIL {
+            self.vertex_nodes[v] = self.alloc(NodeKind::Vertex(v as u32));
+        }
+        self.vertex_nodes[v]
+    }
+
+    /// Drops the vertex node of an isolated vertex
+    fn remove_vertex(&mut self, v: u32) {
+        if let Some(&node) = self.vertex_nodes.get(v as usize) {
+            if node != NIL {
+                debug_assert!(self.nodes[node].count == 1 && self.nodes[node].parent == NIL);
+                self.vertex_nodes[v as usize] = NIL;
+                self.free.push(node);
+            }
+        }
+    }
+
+    fn update(&mut self, x: usize) {
+        let (left, right) = (self.nodes[x].left, self.nodes[x].right);
+        let node = &self.nodes[x];
+        let mut count = 1;
+        let mut vertices = if let NodeKind::Vertex(_) = node.kind { 1 } else { 0 };
+        let mut any_nontree = node.nontree;
+        let mut any_tree = node.tree;
+
+        for child in [left, right] {
+            if child != NIL {
+                let child = &self.nodes[child];
+                count += child.count;
+                vertices += child.vertices;
+                any_nontree |= child.any_nontree;
+                any_tree |= child.any_tree;
+            }
+        }
+
+        let node = &mut self.nodes[x];
+        node.count = count;
+        node.vertices = vertices;
+        node.any_nontree = any_nontree;
+        node.any_tree = any_tree;
+    }
+
+    // recomputes aggregates from x up to the root after one of its flags changed
+    fn update_to_root(&mut self, mut x: usize) {
+        while x != NIL {
+            self.update(x);
+            x = self.nodes[x].parent;
+        }
+    }
+
+    fn count(&self, x: usize) -> usize {
+        if x == NIL {
+            0
+        } else {
+            self.nodes[x].count
+        }
+    }
+
+    fn root(&self, mut x: usize) -> usize {
+        while self.nodes[x].parent != NIL {
+            x = self.nodes[x].parent;
+        }
+        x
+    }
+
+    // 0-indexed position of x in its tour
+    fn position(&self, mut x: usize) -> usize {
+        let mut position = self.count(self.nodes[x].left);
+        while self.nodes[x].parent != NIL {
+            let parent = self.nodes[x].parent;
+            if self.nodes[parent].right == x {
+                position += self.count(self.nodes[parent].left) + 1;
+            }
+            x = parent;
+        }
+        position
+    }
+
+    fn merge(&mut self, a: usize, b: usize) -> usize {
+        let root = self.merge_rec(a, b);
+        if root != NIL {
+            self.nodes[root].parent = NIL;
+        }
+        root
+    }
+
+    fn merge_rec(&mut self, a: usize, b: usize) -> usize {
+        if a == NIL {
+            return b;
+        }
+        if b == NIL {
+            return a;
+        }
+
+        if self.nodes[a].priority > self.nodes[b].priority {
+            let right = self.merge_rec(self.nodes[a].right, b);
+            self.nodes[a].right = right;
+            self.nodes[right].parent = a;
+            self.update(a);
+            a
+        } else {
+            let left = self.merge_rec(a, self.nodes[b].left);
+            self.nodes[b].left = left;
+            self.nodes[left].parent = b;
+            self.update(b);
+            b
+        }
+    }
+
+    /// Splits the treap rooted at t into its first k nodes and the rest
+    fn split(&mut self, t: usize, k: usize) -> (usize, usize) {
+        if t == NIL {
+            return (NIL, NIL);
+        }
+        self.nodes[t].parent = NIL;
+
+        let left = self.nodes[t].left;
+        let left_count = self.count(left);
+        if left_count >= k {
+            let (a, b) = self.split(left, k);
+            self.nodes[t].left = b;
+            if b != NIL {
+                self.nodes[b].parent = t;
+            }
+            self.update(t);
+            (a, t)
+        } else {
+            let (a, b) = self.split(self.nodes[t].right, k - left_count - 1);
+            self.nodes[t].right = a;
+            if a != NIL {
+                self.nodes[a].parent = t;
+            }
+            self.update(t);
+            (t, b)
+        }
+    }
+
+    // rotates the tour containing v so that it starts at v, returns the new root
+    fn reroot(&mut self, v: u32) -> usize {
+        let x = self.vertex_node(v);
+        let root = self.root(x);
+        let position = self.position(x);
+        let (before, after) = self.split(root, position);
+        self.merge(after, before)
+    }
+
+    fn connected(&mut self, u: u32, v: u32) -> bool {
+        let (x, y) = (self.vertex_node(u), self.vertex_node(v));
+        self.root(x) == self.root(y)
+    }
+
+    fn tree_size(&mut self, v: u32) -> usize {
+        let x = self.vertex_node(v);
+        let root = self.root(x);
+        self.nodes[root].vertices
+    }
+
+    /// Joins the trees of u and v with the edge (u, v). The trees must be distinct
+    fn link(&mut self, u: u32, v: u32) {
+        let tour_u = self.reroot(u);
+        let tour_v = self.reroot(v);
+
+        let uv = self.alloc(NodeKind::Arc(u, v));
+        let vu = self.alloc(NodeKind::Arc(v, u));
+        self.arcs.insert((u, v), uv);
+        self.arcs.insert((v, u), vu);
+
+        let tour = self.merge(tour_u, uv);
+        let tour = self.merge(tour, tour_v);
+        self.merge(tour, vu);
+    }
+
+    /// Removes the tree edge (u, v), splitting its tree in two
+    fn cut(&mut self, u: u32, v: u32) {
+        let mut first = self.arcs.remove(&(u, v)).expect("cut a tree edge");
+        let mut second = self.arcs.remove(&(v, u)).expect("cut a tree edge");
+
+        let root = self.root(first);
+        let mut first_position = self.position(first);
+        let mut second_position = self.position(second);
+        if first_position > second_position {
+            std::mem::swap(&mut first, &mut second);
+            std::mem::swap(&mut first_position, &mut second_position);
+        }
+
+        // tour = before, first, inside, second, after. inside is one tree, before + after is
+        // the other
+        let (before, rest) = self.split(root, first_position);
+        let (_, rest) = self.split(rest, 1);
+        let (_, rest) = self.split(rest, second_position - first_position - 1);
+        let (_, after) = self.split(rest, 1);
+        self.merge(before, after);
+
+        self.free.push(first);
+        self.free.push(second);
+    }
+
+    fn set_nontree(&mut self, v: u32, flag: bool) {
+        let x = self.vertex_node(v);
+        if self.nodes[x].nontree != flag {
+            self.nodes[x].nontree = flag;
+            self.update_to_root(x);
+        }
+    }
+
+    fn set_tree(&mut self, u: u32, v: u32, flag: bool) {
+        let x = self.arcs[&(u.min(v), u.max(v))];
+        if self.nodes[x].tree != flag {
+            self.nodes[x].tree = flag;
+            self.update_to_root(x);
+        }
+    }
+
+    // finds some vertex in the tree of v with non-tree edges on this level
+    fn find_nontree(&mut self, v: u32) -> Option<u32> {
+        let x = self.vertex_node(v);
+        let root = self.root(x);
+        match self.find_flagged(root, |node| node.nontree, |node| node.any_nontree) {
+            Some(node) => match self.nodes[node].kind {
+                NodeKind::Vertex(w) => Some(w),
+                NodeKind::Arc(..) => unreachable!(),
+            },
+            None => None,
+        }
+    }
+
+    // finds some tree edge in the tree of v whose level is exactly this level
+    fn find_tree(&mut self, v: u32) -> Option<(u32, u32)> {
+        let x = self.vertex_node(v);
+        let root = self.root(x);
+        match self.find_flagged(root, |node| node.tree, |node| node.any_tree) {
+            Some(node) => match self.nodes[node].kind {
+                NodeKind::Arc(a, b) => Some((a, b)),
+                NodeKind::Vertex(_) => unreachable!(),
+            },
+            None => None,
+        }
+    }
+
+    fn find_flagged(&self, mut x: usize, own: fn(&Node) -> bool, any: fn(&Node) -> bool) -> Option<usize> {
+        if !any(&self.nodes[x]) {
+            return None;
+        }
+
+        loop {
+            let node = &self.nodes[x];
+            if own(node) {
+                return Some(x);
+            }
+            if node.left != NIL && any(&self.nodes[node.left]) {
+                x = node.left;
+            } else {
+                x = node.right;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct EdgeInfo {
+    level: usize,
+    tree: bool,
+}
+
+/// Dynamic connectivity over the cells of a polycube, where two cells are adjacent when they
+/// share a face. Every update is amortized O(log^2 n) and querying whether the polycube is
+/// connected is O(1).
+pub struct DynamicConnectivity {
+    ids: HashMap<(i32, i32, i32), u32>,
+    free_ids: Vec<u32>,
+    next_id: u32,
+
+    edges: HashMap<(u32, u32), EdgeInfo>,
+
+    // forests[i] spans the edges with level >= i
+    forests: Vec<EulerTourForest>,
+    // nontree[i][v] are the endpoints of the non-tree edges at v with level i
+    nontree: Vec<HashMap<u32, HashSet<u32>>>,
+
+    components: usize,
+}
+
+impl Default for DynamicConnectivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DynamicConnectivity {
+    pub fn new() -> DynamicConnectivity {
+        DynamicConnectivity {
+            ids: HashMap::new(),
+            free_ids: Vec::new(),
+            next_id: 0,
+            edges: HashMap::new(),
+            forests: vec![EulerTourForest::new()],
+            nontree: vec![HashMap::new()],
+            components: 0,
+        }
+    }
+
+    /// Builds the structure for an existing set of cells
+    pub fn from_cells(cells: impl IntoIterator<Item = (i32, i32, i32)>) -> DynamicConnectivity {
+        let mut connectivity = DynamicConnectivity::new();
+        for cell in cells {
+            connectivity.insert(cell);
+        }
+        connectivity
+    }
+
+    /// Number of connected components of the cells
+    pub fn components(&self) -> usize {
+        self.components
+    }
+
+    /// Whether the cells form a single strongly connected polyform. The empty polyform counts as
+    /// connected, like in the DFS check
+    pub fn is_connected(&self) -> bool {
+        self.components <= 1
+    }
+
+    pub fn connected(&mut self, a: &(i32, i32, i32), b: &(i32, i32, i32)) -> bool {
+        match (self.ids.get(a), self.ids.get(b)) {
+            (Some(&a), Some(&b)) => self.forests[0].connected(a, b),
+            _ => false,
+        }
+    }
+
+    /// Adds a cell along with its edges to all adjacent cells
+    pub fn insert(&mut self, cell: (i32, i32, i32)) {
+        if self.ids.contains_key(&cell) {
+            return;
+        }
+
+        let id = match self.free_ids.pop() {
+            Some(id) => id,
+            None => {
+                self.next_id += 1;
+                self.next_id - 1
+            }
+        };
+        self.ids.insert(cell, id);
+        self.components += 1;
+
+        for neighbor in self.neighbor_ids(&cell) {
+            self.insert_edge(id, neighbor);
+        }
+    }
+
+    /// Removes a cell along with all of its edges
+    pub fn remove(&mut self, cell: &(i32, i32, i32)) {
+        let id = match self.ids.get(cell) {
+            Some(&id) => id,
+            None => return,
+        };
+
+        for neighbor in self.neighbor_ids(cell) {
+            self.delete_edge(id, neighbor);
+        }
+
+        self.ids.remove(cell);
+        for forest in &mut self.forests {
+            forest.remove_vertex(id);
+        }
+        self.free_ids.push(id);
+        self.components -= 1;
+    }
+
+    fn neighbor_ids(&self, cell: &(i32, i32, i32)) -> Vec<u32> {
+        [
+            (cell.0, cell.1, cell.2 + 1),
+            (cell.0, cell.1, cell.2 - 1),
+            (cell.0, cell.1 + 1, cell.2),
+            (cell.0, cell.1 - 1, cell.2),
+            (cell.0 + 1, cell.1, cell.2),
+            (cell.0 - 1, cell.1, cell.2),
+        ]
+        .iter()
+        .filter_map(|neighbor| self.ids.get(neighbor).copied())
+        .collect()
+    }
+
+    fn ensure_level(&mut self, level: usize) {
+        while self.forests.len() <= level {
+            self.forests.push(EulerTourForest::new());
+            self.nontree.push(HashMap::new());
+        }
+    }
+
+    fn add_nontree(&mut self, u: u32, v: u32, level: usize) {
+        self.ensure_level(level);
+        for (a, b) in [(u, v), (v, u)] {
+            self.nontree[level].entry(a).or_default().insert(b);
+            self.forests[level].set_nontree(a, true);
+        }
+    }
+
+    fn remove_nontree(&mut self, u: u32, v: u32, level: usize) {
+        for (a, b) in [(u, v), (v, u)] {
+            let now_empty = match self.nontree[level].get_mut(&a) {
+                Some(set) => {
+                    set.remove(&b);
+                    set.is_empty()
+                }
+                None => true,
+            };
+            if now_empty {
+                self.nontree[level].remove(&a);
+                self.forests[level].set_nontree(a, false);
+            }
+        }
+    }
+
+    // makes (u, v) a tree edge of the given level, linking it into F_0..=F_level
+    fn add_tree(&mut self, u: u32, v: u32, level: usize) {
+        self.ensure_level(level);
+        for forest in &mut self.forests[..=level] {
+            forest.link(u, v);
+        }
+        self.forests[level].set_tree(u, v, true);
+    }
+
+    fn insert_edge(&mut self, u: u32, v: u32) {
+        let key = (u.min(v), u.max(v));
+        if self.forests[0].connected(u, v) {
+            self.edges.insert(key, EdgeInfo { level: 0, tree: false });
+            self.add_nontree(u, v, 0);
+        } else {
+            self.edges.insert(key, EdgeInfo { level: 0, tree: true });
+            self.add_tree(u, v, 0);
+            self.components -= 1;
+        }
+    }
+
+    fn delete_edge(&mut self, u: u32, v: u32) {
+        let key = (u.min(v), u.max(v));
+        let info = match self.edges.remove(&key) {
+            Some(info) => info,
+            None => return,
+        };
+
+        if !info.tree {
+            self.remove_nontree(u, v, info.level);
+            return;
+        }
+
+        for forest in &mut self.forests[..=info.level] {
+            forest.cut(u, v);
+        }
+
+        if !self.replace(u, v, info.level) {
+            self.components += 1;
+        }
+    }
+
+    // looks for an edge reconnecting the trees of u and v, starting at the level of the deleted
+    // edge and working down
+    fn replace(&mut self, u: u32, v: u32, level: usize) -> bool {
+        for i in (0..=level).rev() {
+            let (small, big) = if self.forests[i].tree_size(u) <= self.forests[i].tree_size(v) {
+                (u, v)
+            } else {
+                (v, u)
+            };
+
+            // the smaller tree now fits on the next level, so push its tree edges up to keep
+            // the searches below from revisiting the same edges forever
+            while let Some((a, b)) = self.forests[i].find_tree(small) {
+                self.forests[i].set_tree(a, b, false);
+                self.ensure_level(i + 1);
+                self.forests[i + 1].link(a, b);
+                self.forests[i + 1].set_tree(a, b, true);
+                self.edges.insert((a.min(b), a.max(b)), EdgeInfo { level: i + 1, tree: true });
+            }
+
+            while let Some(x) = self.forests[i].find_nontree(small) {
+                let candidates: Vec<u32> = self.nontree[i][&x].iter().copied().collect();
+                for y in candidates {
+                    self.remove_nontree(x, y, i);
+                    let key = (x.min(y), x.max(y));
+
+                    if self.forests[i].connected(y, big) {
+                        self.edges.insert(key, EdgeInfo { level: i, tree: true });
+                        self.add_tree(x, y, i);
+                        return true;
+                    }
+
+                    // both endpoints are in the small tree
+                    self.edges.insert(key, EdgeInfo { level: i + 1, tree: false });
+                    self.add_nontree(x, y, i + 1);
+                }
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{rng, Dist, Polycube};
+    use rand::seq::SliceRandom;
+
+    #[test]
+    fn agrees_with_dfs_after_every_update() {
+        rng::seed(26);
+        let cells: Vec<(i32, i32, i32)> = (0..4).flat_map(|x| (0..4).flat_map(move |y| (0..3).map(move |z| (x, y, z)))).collect();
+        let mut pfm = Polycube::new(0, Dist::Uniform);
+        pfm.enable_dynamic_connectivity();
+
+        for _ in 0..2000 {
+            let cell = *cells.choose(&mut rng::thread_rng()).unwrap();
+            if pfm.complex.contains(&cell) {
+                pfm.remove(&cell);
+            } else {
+                pfm.insert(cell);
+            }
+
+            let components = pfm.components();
+            let connectivity = pfm.connectivity.as_ref().unwrap();
+            assert_eq!(connectivity.is_connected(), pfm.dfs());
+            assert_eq!(connectivity.components(), components.len());
+
+            let (a, b) = (*cells.choose(&mut rng::thread_rng()).unwrap(), *cells.choose(&mut rng::thread_rng()).unwrap());
+            let same = components.iter().any(|component| component.contains(&a) && component.contains(&b));
+            assert_eq!(pfm.connectivity.as_mut().unwrap().connected(&a, &b), same);
+        }
+    }
+
+    #[test]
+    fn backend_does_not_change_the_chain() {
+        let run = |dynamic: bool| {
+            rng::seed(7);
+            let mut pfm = Polycube::new(30, Dist::Bernoulli(0.5));
+            if dynamic {
+                pfm.enable_dynamic_connectivity();
+            }
+            pfm.shuffle(3000);
+            let mut cells: Vec<_> = pfm.complex.iter().copied().collect();
+            cells.sort_unstable();
+            cells
+        };
+        assert_eq!(run(false), run(true));
+    }
+}
@@ -0,0 +1,561 @@
+//! A binary container holding many samples in one file, instead of one text file per sample.
+//!
+//! The file starts with the magic bytes `PFMS` and a little-endian u16 version. Each sample
+//! follows as a record: the tag byte `S`, a u32 length, and a payload with the metadata (lattice,
+//! distribution, seed, number of steps and, since version 2, the observables of polycubes) and
+//! then the cells. Cells are sorted and stored as
+//! differences from the previous cell, each coordinate zigzag and varint encoded, so neighboring
+//! cells take 3 bytes. Bonds are stored as the doubled coordinates of their midpoints.
+//!
+//! `Writer::finish` appends an index, the tag `I` followed by the offset of every record, and
+//! ends the file with the offset of the index, so `Container` can seek straight to any sample. A
+//! file whose writer never finished has no index, but can still be read from the start.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::bond::Bond;
+use crate::observables::Observables;
+use crate::storage::CellStorage;
+use crate::{Dist, Polycube};
+
+type Cell = (i32, i32, i32);
+
+pub const MAGIC: &[u8; 4] = b"PFMS";
+pub const VERSION: u16 = 2;
+const SAMPLE: u8 = b'S';
+const INDEX: u8 = b'I';
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lattice {
+    Site,
+    Bond,
+}
+
+/// How a sample was produced
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub lattice: Lattice,
+    pub dist: Dist,
+    pub seed: Option<u64>,
+    /// Shuffles, or moves of the n-fold way
+    pub steps: u64,
+    /// The shape statistics of a polycube when it was packed. None for bond animals and samples
+    /// of version 1 files
+    pub observables: Option<Observables>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub metadata: Metadata,
+    /// Cells of a polycube, or doubled bond midpoints of a bond animal
+    pub cells: Vec<Cell>,
+}
+
+fn midpoint_bond(midpoint: &Cell) -> Bond {
+    // the odd coordinate is the axis
+    let axis = if midpoint.0 & 1 == 1 { 0 } else if midpoint.1 & 1 == 1 { 1 } else { 2 };
+    ((midpoint.0.div_euclid(2), midpoint.1.div_euclid(2), midpoint.2.div_euclid(2)), axis)
+}
+
+impl Sample {
+    pub fn of_polycube<S: CellStorage>(pfm: &Polycube<S>, seed: Option<u64>, steps: u64) -> Sample {
+        let metadata = Metadata { lattice: Lattice::Site, dist: pfm.dist.clone(), seed, steps, observables: Some(pfm.observables()) };
+        Sample { metadata, cells: pfm.complex.cells().collect() }
+    }
+
+    /// The polycube of a site sample
+    pub fn polycube<S: CellStorage>(&self) -> Result<Polycube<S>, String> {
+        if self.metadata.lattice != Lattice::Site {
+            return Err("the sample is a bond animal".to_string());
+        }
+        Polycube::try_from_cells(self.cells.iter().copied(), self.metadata.dist.clone())
+    }
+
+    /// The bonds of a bond sample
+    pub fn bonds(&self) -> Vec<Bond> {
+        self.cells.iter().map(midpoint_bond).collect()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.push(match self.metadata.lattice {
+            Lattice::Site => 0,
+            Lattice::Bond => 1,
+        });
+        match self.metadata.dist {
+            Dist::Uniform => payload.push(0),
+            Dist::Bernoulli(p) => {
+                payload.push(1);
+                payload.extend_from_slice(&p.to_le_bytes());
+            }
+        }
+        match self.metadata.seed {
+            None => payload.push(0),
+            Some(seed) => {
+                payload.push(1);
+                payload.extend_from_slice(&seed.to_le_bytes());
+            }
+        }
+        write_varint(&mut payload, self.metadata.steps);
+        match &self.metadata.observables {
+            None => payload.push(0),
+            Some(observables) => {
+                payload.push(1);
+                write_observables(&mut payload, observables);
+            }
+        }
+
+        let mut cells = self.cells.clone();
+        cells.sort_unstable();
+        write_varint(&mut payload, cells.len() as u64);
+        let mut previous = (0, 0, 0);
+        for cell in cells {
+            for delta in [cell.0 - previous.0, cell.1 - previous.1, cell.2 - previous.2] {
+                write_varint(&mut payload, zigzag(delta));
+            }
+            previous = cell;
+        }
+        payload
+    }
+
+    fn decode(payload: &[u8], version: u16) -> io::Result<Sample> {
+        let mut bytes = payload;
+        let lattice = match read_u8(&mut bytes)? {
+            0 => Lattice::Site,
+            1 => Lattice::Bond,
+            other => return Err(invalid(format!("unknown lattice {}", other))),
+        };
+        let dist = match read_u8(&mut bytes)? {
+            0 => Dist::Uniform,
+            1 => Dist::Bernoulli(f64::from_le_bytes(read_array(&mut bytes)?)),
+            other => return Err(invalid(format!("unknown distribution {}", other))),
+        };
+        let seed = match read_u8(&mut bytes)? {
+            0 => None,
+            _ => Some(u64::from_le_bytes(read_array(&mut bytes)?)),
+        };
+        let steps = read_varint(&mut bytes)?;
+        let observables = match version {
+            1 => None,
+            _ => match read_u8(&mut bytes)? {
+                0 => None,
+                _ => Some(read_observables(&mut bytes)?),
+            },
+        };
+
+        // every cell takes at least a byte per coordinate
+        let count = read_varint(&mut bytes)?;
+        if count > bytes.len() as u64 / 3 {
+            return Err(invalid(format!("{} cells don't fit in the {} bytes left of the sample", count, bytes.len())));
+        }
+        let mut cells = Vec::with_capacity(count as usize);
+        let mut previous: Cell = (0, 0, 0);
+        for _ in 0..count {
+            let mut next = |coordinate: i32| {
+                let delta = unzigzag(read_varint(&mut bytes)?);
+                coordinate.checked_add(delta).ok_or_else(|| invalid("a cell is out of the range of i32".to_string()))
+            };
+            previous = (next(previous.0)?, next(previous.1)?, next(previous.2)?);
+            cells.push(previous);
+        }
+
+        Ok(Sample { metadata: Metadata { lattice, dist, seed, steps, observables }, cells })
+    }
+}
+
+// counts as varints and the rest as f64, in the order of `Observables::COLUMNS`
+fn write_observables(bytes: &mut Vec<u8>, observables: &Observables) {
+    for count in [observables.cells, observables.site_perimeter] {
+        write_varint(bytes, count as u64);
+    }
+    let [l1, l2, l3] = observables.gyration_eigenvalues;
+    for value in [observables.radius_of_gyration, l1, l2, l3, observables.asphericity, observables.prolateness] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    let [x, y, z] = observables.bounding_box;
+    for count in [x, y, z, observables.surface_area, observables.contacts, observables.cycle_rank] {
+        write_varint(bytes, count as u64);
+    }
+}
+
+fn read_observables(bytes: &mut &[u8]) -> io::Result<Observables> {
+    let count = |bytes: &mut &[u8]| read_varint(bytes).map(|count| count as usize);
+    let value = |bytes: &mut &[u8]| read_array(bytes).map(f64::from_le_bytes);
+    // fields are read in the order they're written
+    Ok(Observables {
+        cells: count(bytes)?,
+        site_perimeter: count(bytes)?,
+        radius_of_gyration: value(bytes)?,
+        gyration_eigenvalues: [value(bytes)?, value(bytes)?, value(bytes)?],
+        asphericity: value(bytes)?,
+        prolateness: value(bytes)?,
+        bounding_box: [count(bytes)?, count(bytes)?, count(bytes)?],
+        surface_area: count(bytes)?,
+        contacts: count(bytes)?,
+        cycle_rank: count(bytes)?,
+    })
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+fn unzigzag(value: u64) -> i32 {
+    let value = value as u32;
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(bytes)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint longer than 64 bits".to_string()))
+}
+
+fn read_u8(bytes: &mut &[u8]) -> io::Result<u8> {
+    Ok(read_array::<1>(bytes)?[0])
+}
+
+fn read_array<const N: usize>(bytes: &mut &[u8]) -> io::Result<[u8; N]> {
+    if bytes.len() < N {
+        return Err(invalid("sample ends early".to_string()));
+    }
+    let (head, rest) = bytes.split_at(N);
+    *bytes = rest;
+    Ok(head.try_into().expect("split at N"))
+}
+
+/// Writes samples one at a time, then the index
+pub struct Writer<W: Write> {
+    inner: W,
+    position: u64,
+    offsets: Vec<u64>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut inner: W) -> io::Result<Writer<W>> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&VERSION.to_le_bytes())?;
+        Ok(Writer { inner, position: 6, offsets: Vec::new() })
+    }
+
+    pub fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        let payload = sample.encode();
+        self.offsets.push(self.position);
+        self.inner.write_all(&[SAMPLE])?;
+        self.inner.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&payload)?;
+        self.position += 5 + payload.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the index and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let index = self.position;
+        self.inner.write_all(&[INDEX])?;
+        self.inner.write_all(&(self.offsets.len() as u64).to_le_bytes())?;
+        for offset in &self.offsets {
+            self.inner.write_all(&offset.to_le_bytes())?;
+        }
+        self.inner.write_all(&index.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+fn read_header(inner: &mut impl Read) -> io::Result<u16> {
+    let mut header = [0; 6];
+    let read = inner.read_exact(&mut header);
+    if read.is_err() || &header[..4] != MAGIC {
+        return Err(invalid("not a sample container".to_string()));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > VERSION {
+        return Err(invalid(format!("version {} is newer than this reader, which reads up to {}", version, VERSION)));
+    }
+    Ok(version)
+}
+
+/// Reads the record at the current position, or None at the index or the end of the file
+fn read_record(inner: &mut impl Read, version: u16) -> io::Result<Option<Sample>> {
+    let mut tag = [0];
+    if inner.read(&mut tag)? == 0 || tag[0] == INDEX {
+        return Ok(None);
+    }
+    if tag[0] != SAMPLE {
+        return Err(invalid(format!("unknown record {:?}", tag[0] as char)));
+    }
+    let mut length = [0; 4];
+    inner.read_exact(&mut length)?;
+    let length = u32::from_le_bytes(length) as u64;
+    // grows with the bytes actually there, so a corrupted length can't allocate more than the file
+    let mut payload = Vec::new();
+    if inner.take(length).read_to_end(&mut payload)? as u64 != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file ends inside a sample"));
+    }
+    Sample::decode(&payload, version).map(Some)
+}
+
+/// Reads samples in order, without seeking
+pub struct Reader<R: Read> {
+    inner: R,
+    pub version: u16,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut inner: R) -> io::Result<Reader<R>> {
+        let version = read_header(&mut inner)?;
+        Ok(Reader { inner, version })
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Sample>;
+
+    fn next(&mut self) -> Option<io::Result<Sample>> {
+        read_record(&mut self.inner, self.version).transpose()
+    }
+}
+
+/// Reads samples by index
+pub struct Container<R: Read + Seek> {
+    inner: R,
+    offsets: Vec<u64>,
+    pub version: u16,
+}
+
+impl<R: Read + Seek> Container<R> {
+    /// Opens a container, reading its index, or finding every record if it has none
+    pub fn open(mut inner: R) -> io::Result<Container<R>> {
+        let version = read_header(&mut inner)?;
+        let offsets = match Self::read_index(&mut inner)? {
+            Some(offsets) => offsets,
+            None => Self::scan(&mut inner)?,
+        };
+        Ok(Container { inner, offsets, version })
+    }
+
+    fn read_index(inner: &mut R) -> io::Result<Option<Vec<u64>>> {
+        let end = inner.seek(SeekFrom::End(0))?;
+        if end < 6 + 17 {
+            return Ok(None);
+        }
+        let mut bytes = [0; 8];
+        inner.seek(SeekFrom::End(-8))?;
+        inner.read_exact(&mut bytes)?;
+        let index = u64::from_le_bytes(bytes);
+        if index < 6 || index > end - 17 {
+            return Ok(None);
+        }
+
+        inner.seek(SeekFrom::Start(index))?;
+        let mut header = [0; 9];
+        inner.read_exact(&mut header)?;
+        let count = u64::from_le_bytes(header[1..].try_into().expect("8 bytes"));
+        // the offsets fill the index exactly, which also bounds the count by the length of the file
+        if header[0] != INDEX || count.checked_mul(8).and_then(|length| length.checked_add(index + 17)) != Some(end) {
+            return Ok(None);
+        }
+
+        let mut offsets = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            inner.read_exact(&mut bytes)?;
+            let offset = u64::from_le_bytes(bytes);
+            if offset < 6 || offset > index - 5 {
+                return Err(invalid(format!("the index points to offset {}, outside the records", offset)));
+            }
+            offsets.push(offset);
+        }
+        Ok(Some(offsets))
+    }
+
+    // walks the records of a file without an index, stopping at the first incomplete one
+    fn scan(inner: &mut R) -> io::Result<Vec<u64>> {
+        let end = inner.seek(SeekFrom::End(0))?;
+        let mut offsets = Vec::new();
+        let mut position = 6;
+        let mut header = [0; 5];
+        while position + 5 <= end {
+            inner.seek(SeekFrom::Start(position))?;
+            inner.read_exact(&mut header)?;
+            let length = u32::from_le_bytes(header[1..].try_into().expect("4 bytes")) as u64;
+            let next = match (position + 5).checked_add(length) {
+                Some(next) if header[0] == SAMPLE && next <= end => next,
+                _ => break,
+            };
+            offsets.push(position);
+            position = next;
+        }
+        Ok(offsets)
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    pub fn get(&mut self, index: usize) -> io::Result<Sample> {
+        let offset = *self.offsets.get(index).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("sample {} of {}", index, self.offsets.len()))
+        })?;
+        self.inner.seek(SeekFrom::Start(offset))?;
+        read_record(&mut self.inner, self.version)?.ok_or_else(|| invalid(format!("no sample at offset {}", offset)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::rng;
+
+    fn samples() -> Vec<Sample> {
+        rng::seed(45);
+        let mut pfm: Polycube = Polycube::new(30, Dist::Bernoulli(0.25));
+        pfm.shuffle(1000);
+        let bonds = Sample {
+            metadata: Metadata { lattice: Lattice::Bond, dist: Dist::Uniform, seed: None, steps: 0, observables: None },
+            cells: vec![(1, 0, 0), (2, 1, 0), (-2, 0, -1)],
+        };
+        vec![Sample::of_polycube(&pfm, Some(45), 1000), bonds]
+    }
+
+    fn packed(samples: &[Sample]) -> Vec<u8> {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for sample in samples {
+            writer.write(sample).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn sorted(sample: &Sample) -> Vec<Cell> {
+        let mut cells = sample.cells.clone();
+        cells.sort_unstable();
+        cells
+    }
+
+    fn same(a: &Sample, b: &Sample) -> bool {
+        let (x, y) = (&a.metadata, &b.metadata);
+        let dist = match (&x.dist, &y.dist) {
+            (Dist::Uniform, Dist::Uniform) => true,
+            (Dist::Bernoulli(p), Dist::Bernoulli(q)) => p == q,
+            _ => false,
+        };
+        x.lattice == y.lattice && dist && x.seed == y.seed && x.steps == y.steps && x.observables == y.observables && sorted(a) == sorted(b)
+    }
+
+    #[test]
+    fn pack_and_unpack_round_trip() {
+        let samples = samples();
+        let bytes = packed(&samples);
+
+        let mut container = Container::open(Cursor::new(&bytes)).unwrap();
+        assert_eq!(container.len(), 2);
+        for (i, sample) in samples.iter().enumerate() {
+            assert!(same(&container.get(i).unwrap(), sample));
+        }
+        assert!(container.get(2).is_err());
+
+        let read = Reader::new(Cursor::new(&bytes)).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        assert!(read.iter().zip(&samples).all(|(a, b)| same(a, b)) && read.len() == 2);
+        assert_eq!(read[1].bonds(), vec![((-1, 0, -1), 2), ((0, 0, 0), 0), ((1, 0, 0), 1)]);
+    }
+
+    #[test]
+    fn keeps_the_observables_of_polycubes() {
+        let samples = samples();
+        let read = Reader::new(Cursor::new(packed(&samples))).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        let pfm: Polycube = read[0].polycube().unwrap();
+        assert_eq!(read[0].metadata.observables, Some(pfm.observables()));
+        assert_eq!(read[1].metadata.observables, None);
+
+        // version 1 payloads end the metadata at the steps
+        let mut version_1 = MAGIC.to_vec();
+        version_1.extend_from_slice(&1u16.to_le_bytes());
+        version_1.extend_from_slice(&[SAMPLE, 8, 0, 0, 0, 0, 0, 0, 7, 1, 2, 0, 0]);
+        let read = Reader::new(Cursor::new(version_1)).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!((read[0].metadata.steps, read[0].metadata.observables.clone()), (7, None));
+        assert_eq!(read[0].cells, vec![(1, 0, 0)]);
+    }
+
+    #[test]
+    fn reads_files_without_an_index() {
+        let samples = samples();
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for sample in &samples {
+            writer.write(sample).unwrap();
+        }
+        let mut bytes = writer.inner;
+        // a record cut short by a writer that stopped is left out
+        bytes.extend_from_slice(&[SAMPLE, 200, 0, 0, 0, 1, 2]);
+
+        let mut container = Container::open(Cursor::new(&bytes)).unwrap();
+        assert_eq!(container.len(), 2);
+        assert!(same(&container.get(1).unwrap(), &samples[1]));
+    }
+
+    #[test]
+    fn corrupted_files_are_errors() {
+        let samples = samples();
+        let bytes = packed(&samples);
+        let first = 6 + 5;
+
+        // a count of cells far beyond the payload
+        let mut payload = vec![0, 0, 0, 0];
+        write_varint(&mut payload, u64::MAX >> 1);
+        assert_eq!(Sample::decode(&payload, VERSION).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a cell past the range of i32
+        let mut payload = vec![0, 0, 0, 0, 2];
+        for delta in [i32::MAX, 0, 0, 1, 0, 0] {
+            write_varint(&mut payload, zigzag(delta));
+        }
+        assert_eq!(Sample::decode(&payload, VERSION).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a record length of 4 GB
+        let mut huge = bytes.clone();
+        huge[first - 4..first].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Reader::new(Cursor::new(&huge)).unwrap().next().unwrap().is_err());
+
+        // an index count that overflows when multiplied into bytes falls back to scanning
+        let index = u64::from_le_bytes(bytes[bytes.len() - 8..].try_into().unwrap()) as usize;
+        let mut count = bytes.clone();
+        count[index + 1..index + 9].copy_from_slice(&(u64::MAX / 4).to_le_bytes());
+        assert_eq!(Container::open(Cursor::new(&count)).unwrap().len(), 2);
+
+        // an offset outside the records
+        let mut offset = bytes.clone();
+        offset[index + 9..index + 17].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(Container::open(Cursor::new(&offset)).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // every truncation either reads or fails, without panicking
+        for length in 0..bytes.len() {
+            if let Ok(mut container) = Container::open(Cursor::new(&bytes[..length])) {
+                for i in 0..container.len() {
+                    let _ = container.get(i);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Energy models for Boltzmann-weighted sampling.
+//!
+//! A shuffle proposes moving one cell somewhere else, and accepts the move with probability
+//! min(1, exp(-ΔE)), energies being in units of kT. Models compute ΔE incrementally from the
+//! move instead of from the whole polycube, so they cost O(1) per shuffle.
+//!
+//! `Dist::Bernoulli(p)` is the special case `SitePerimeter` scaled by -ln(1-p).
+
+use std::str::FromStr;
+
+/// A move, seen after it has been applied to the polycube
+pub struct Move<'a> {
+    pub removed: (i32, i32, i32),
+    pub inserted: (i32, i32, i32),
+
+    // site perimeter before and after the move
+    pub perimeter: (usize, usize),
+
+    // number of pairs of cells sharing a face before and after the move
+    pub contacts: (usize, usize),
+
+    // whether a cell is occupied after the move, for models that look at the neighborhood
+    pub occupied: &'a dyn Fn(&(i32, i32, i32)) -> bool,
+}
+
+impl Move<'_> {
+    pub fn perimeter_delta(&self) -> f64 {
+        self.perimeter.1 as f64 - self.perimeter.0 as f64
+    }
+
+    pub fn contacts_delta(&self) -> f64 {
+        self.contacts.1 as f64 - self.contacts.0 as f64
+    }
+}
+
+pub trait EnergyModel: Send + Sync {
+    /// Change in energy caused by the move, in units of kT
+    fn delta(&self, m: &Move) -> f64;
+}
+
+/// Energy of 1 per empty cell that shares a face with the polycube
+pub struct SitePerimeter;
+
+impl EnergyModel for SitePerimeter {
+    fn delta(&self, m: &Move) -> f64 {
+        m.perimeter_delta()
+    }
+}
+
+/// Energy of 1 per pair of cells sharing a face. A negative coefficient favours compact, collapsed
+/// animals, like the interacting self-avoiding walk
+pub struct Contacts;
+
+impl EnergyModel for Contacts {
+    fn delta(&self, m: &Move) -> f64 {
+        m.contacts_delta()
+    }
+}
+
+/// Energy of 1 per exposed face. Every cell has 6 faces and every contact hides 2 of them, so the
+/// surface area is 6n - 2 * contacts
+pub struct SurfaceArea;
+
+impl EnergyModel for SurfaceArea {
+    fn delta(&self, m: &Move) -> f64 {
+        -2.0 * m.contacts_delta()
+    }
+}
+
+/// A linear combination of energy models
+#[derive(Default)]
+pub struct Linear {
+    pub terms: Vec<(f64, Box<dyn EnergyModel>)>,
+}
+
+impl Linear {
+    pub fn new() -> Linear {
+        Linear::default()
+    }
+
+    pub fn with(mut self, coefficient: f64, model: impl EnergyModel + 'static) -> Linear {
+        self.terms.push((coefficient, Box::new(model)));
+        self
+    }
+}
+
+impl EnergyModel for Linear {
+    fn delta(&self, m: &Move) -> f64 {
+        self.terms.iter().map(|(coefficient, model)| coefficient * model.delta(m)).sum()
+    }
+}
+
+impl FromStr for Linear {
+    type Err = String;
+
+    /// Parses comma separated terms like "perimeter=0.5,contacts=-1". The built-in models are
+    /// perimeter, contacts and surface
+    fn from_str(s: &str) -> Result<Linear, String> {
+        let mut linear = Linear::new();
+
+        for term in s.split(',').filter(|term| !term.trim().is_empty()) {
+            let (name, coefficient) = term
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=coefficient, got {:?}", term))?;
+            let coefficient = coefficient
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| format!("invalid coefficient {:?}: {}", coefficient, e))?;
+
+            linear = match name.trim() {
+                "perimeter" => linear.with(coefficient, SitePerimeter),
+                "contacts" => linear.with(coefficient, Contacts),
+                "surface" => linear.with(coefficient, SurfaceArea),
+                other => return Err(format!("unknown energy model {:?}, expected perimeter, contacts or surface", other)),
+            };
+        }
+
+        Ok(linear)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng, Dist, Polycube};
+
+    #[test]
+    fn parses_linear_combinations() {
+        let linear: Linear = "perimeter=0.5, contacts=-1,surface=2".parse().unwrap();
+        let occupied = |_: &(i32, i32, i32)| false;
+        let m = Move { removed: (0, 0, 0), inserted: (1, 0, 0), perimeter: (10, 14), contacts: (5, 4), occupied: &occupied };
+        assert_eq!(linear.delta(&m), 0.5 * 4.0 + 1.0 + 2.0 * 2.0);
+        assert_eq!("".parse::<Linear>().unwrap().delta(&m), 0.0);
+
+        for invalid in ["perimeter", "perimeter=x", "volume=1"] {
+            assert!(invalid.parse::<Linear>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn attractive_contacts_make_compact_polycubes() {
+        let mean_contacts = |energy: Option<&str>| {
+            rng::seed(32);
+            let mut pfm = Polycube::new(30, Dist::Uniform);
+            pfm.energy = energy.map(|energy| Box::new(energy.parse::<Linear>().unwrap()) as Box<dyn EnergyModel>);
+            pfm.shuffle(5000);
+            (0..100).map(|_| {
+                pfm.shuffle(50);
+                pfm.contact_count() as f64
+            }).sum::<f64>() / 100.0
+        };
+        assert!(mean_contacts(Some("contacts=-2")) > mean_contacts(None) + 10.0);
+    }
+}
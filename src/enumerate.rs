@@ -0,0 +1,152 @@
+//! Exhaustive enumeration of fixed polycubes and bond animals, with Redelmeier's algorithm.
+//!
+//! Every animal is counted once, translated so that its smallest element (in tuple order) is the
+//! origin. The search grows animals one element at a time from an untried set, only ever adding
+//! elements greater than the origin, and never offers the same element twice along a branch, so
+//! it needs no canonical forms or hashing of whole animals.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+
+use crate::bond::{adjacent, Bond};
+
+fn redelmeier<T: Copy + Eq + Hash + Ord>(
+    max: usize,
+    origin: T,
+    neighbors: &impl Fn(&T) -> Vec<T>,
+    visit: &mut impl FnMut(&[T]),
+) {
+    fn grow<T: Copy + Eq + Hash + Ord>(
+        max: usize,
+        origin: T,
+        mut untried: Vec<T>,
+        animal: &mut Vec<T>,
+        seen: &mut HashSet<T>,
+        neighbors: &impl Fn(&T) -> Vec<T>,
+        visit: &mut impl FnMut(&[T]),
+    ) {
+        while let Some(element) = untried.pop() {
+            animal.push(element);
+            visit(animal);
+
+            if animal.len() < max {
+                let mut added = Vec::new();
+                for neighbor in neighbors(&element) {
+                    if neighbor > origin && seen.insert(neighbor) {
+                        added.push(neighbor);
+                    }
+                }
+
+                let mut next = untried.clone();
+                next.extend(added.iter().copied());
+                grow(max, origin, next, animal, seen, neighbors, visit);
+
+                for neighbor in added {
+                    seen.remove(&neighbor);
+                }
+            }
+
+            animal.pop();
+        }
+    }
+
+    let mut seen = HashSet::from([origin]);
+    grow(max, origin, vec![origin], &mut Vec::new(), &mut seen, neighbors, visit);
+}
+
+/// Visits every fixed polycube with at most max cells
+pub fn polycubes(max: usize, mut visit: impl FnMut(&[(i32, i32, i32)])) {
+    let neighbors = |c: &(i32, i32, i32)| {
+        vec![
+            (c.0 + 1, c.1, c.2),
+            (c.0 - 1, c.1, c.2),
+            (c.0, c.1 + 1, c.2),
+            (c.0, c.1 - 1, c.2),
+            (c.0, c.1, c.2 + 1),
+            (c.0, c.1, c.2 - 1),
+        ]
+    };
+    redelmeier(max, (0, 0, 0), &neighbors, &mut visit);
+}
+
+/// Visits every fixed bond animal with at most max bonds
+pub fn bond_animals(max: usize, mut visit: impl FnMut(&[Bond])) {
+    // the smallest bond of an animal can point along any axis
+    for axis in 0..3 {
+        redelmeier(max, ((0, 0, 0), axis), &adjacent, &mut visit);
+    }
+}
+
+fn count(max: usize, enumerate: impl FnOnce(usize, &mut dyn FnMut(usize))) -> Vec<u64> {
+    let mut counts = vec![0; max];
+    enumerate(max, &mut |size| counts[size - 1] += 1);
+    counts
+}
+
+/// Number of fixed polycubes with 1, 2, ..., max cells
+pub fn count_polycubes(max: usize) -> Vec<u64> {
+    count(max, |max, tally| polycubes(max, |animal| tally(animal.len())))
+}
+
+/// Number of fixed bond animals with 1, 2, ..., max bonds
+pub fn count_bond_animals(max: usize) -> Vec<u64> {
+    count(max, |max, tally| bond_animals(max, |animal| tally(animal.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    // grows every animal of each size from every animal one smaller, translating each so its
+    // smallest element is at the origin
+    fn naive<T: Copy + Ord>(sizes: usize, seeds: Vec<T>, neighbors: impl Fn(&T) -> Vec<T>, translate: impl Fn(&T, &T) -> T) -> Vec<u64> {
+        let normalize = |animal: BTreeSet<T>| {
+            let first = *animal.first().expect("animals aren't empty");
+            animal.iter().map(|element| translate(element, &first)).collect::<BTreeSet<T>>()
+        };
+        let mut animals: BTreeSet<BTreeSet<T>> = seeds.into_iter().map(|seed| BTreeSet::from([seed])).collect();
+        let mut counts = vec![animals.len() as u64];
+        for _ in 1..sizes {
+            let mut grown = BTreeSet::new();
+            for animal in &animals {
+                for element in animal {
+                    for neighbor in neighbors(element).into_iter().filter(|neighbor| !animal.contains(neighbor)) {
+                        let mut bigger = animal.clone();
+                        bigger.insert(neighbor);
+                        grown.insert(normalize(bigger));
+                    }
+                }
+            }
+            counts.push(grown.len() as u64);
+            animals = grown;
+        }
+        counts
+    }
+
+    #[test]
+    fn counts_fixed_polycubes() {
+        // OEIS A001931
+        assert_eq!(count_polycubes(6), [1, 3, 15, 86, 534, 3481]);
+    }
+
+    #[test]
+    fn counts_agree_with_growing_every_animal() {
+        let cell_neighbors = |c: &(i32, i32, i32)| {
+            vec![(c.0 + 1, c.1, c.2), (c.0 - 1, c.1, c.2), (c.0, c.1 + 1, c.2), (c.0, c.1 - 1, c.2), (c.0, c.1, c.2 + 1), (c.0, c.1, c.2 - 1)]
+        };
+        let shift = |c: &(i32, i32, i32), by: &(i32, i32, i32)| (c.0 - by.0, c.1 - by.1, c.2 - by.2);
+        assert_eq!(count_polycubes(5), naive(5, vec![(0, 0, 0)], cell_neighbors, shift));
+
+        let bond_shift = |bond: &Bond, by: &Bond| (shift(&bond.0, &by.0), bond.1);
+        assert_eq!(count_bond_animals(4), naive(4, vec![((0, 0, 0), 0), ((0, 0, 0), 1), ((0, 0, 0), 2)], adjacent, bond_shift));
+    }
+
+    #[test]
+    fn visits_connected_animals() {
+        polycubes(4, |cells| {
+            let pfm: crate::Polycube = crate::Polycube::with_cells(cells.iter().copied(), crate::Dist::Uniform);
+            assert!(pfm.is_connected());
+        });
+    }
+}
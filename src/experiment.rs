@@ -0,0 +1,480 @@
+//! Declarative sweeps over lengths and probabilities.
+//!
+//! A spec in TOML or JSON lists the lengths, probabilities and number of samples of a sweep, the
+//! number of shuffles as a formula in n, and the directory the samples go to. It expands into one
+//! work unit per sample, written to `<output>/<n>/<p>/<sample>.<extension>` in the format of
+//! `export`, `txt` for the default analysis format. Every finished unit is appended to
+//! `<output>/manifest.tsv`, so a sweep that is stopped picks up where it left off.
+//!
+//! A sweep can also be split into shards, like the tasks of a SLURM job array, that each run every
+//! Nth unit and keep their own manifest.
+//!
+//! ```toml
+//! lengths = [500, 1000]
+//! probabilities = [0.2, 0.4, 0.6, 0.8]
+//! samples = 100
+//! shuffles = "n^3"
+//! output = "results"
+//! ```
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::cli::{self, Export};
+use crate::npy::Grid;
+use crate::{rng, Dist, Polycube};
+
+/// A number of shuffles, either fixed or a formula in n like "n^3" or "10 * n * n"
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Shuffles {
+    Count(u64),
+    Formula(String),
+}
+
+impl Shuffles {
+    pub fn evaluate(&self, n: usize) -> Result<u64, String> {
+        match self {
+            Shuffles::Count(count) => Ok(*count),
+            Shuffles::Formula(formula) => {
+                let mut parser = Formula { chars: formula.chars().filter(|c| !c.is_whitespace()).collect(), at: 0, n: n as f64 };
+                let value = parser.sum()?;
+                if parser.at != parser.chars.len() {
+                    return Err(format!("unexpected {:?} in {:?}", parser.chars[parser.at], formula));
+                }
+                if !(value >= 0.0 && value.is_finite()) {
+                    return Err(format!("{:?} is {} for n = {}", formula, value, n));
+                }
+                Ok(value.round() as u64)
+            }
+        }
+    }
+}
+
+// recursive descent over sums of products of powers of numbers, n and parenthesized formulas
+struct Formula {
+    chars: Vec<char>,
+    at: usize,
+    n: f64,
+}
+
+impl Formula {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.at).copied()
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.at += 1;
+            let rhs = self.product()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.at += 1;
+            let rhs = self.power()?;
+            value = if op == '*' { value * rhs } else { value / rhs };
+        }
+        Ok(value)
+    }
+
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.at += 1;
+            return Ok(base.powf(self.power()?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('n') => {
+                self.at += 1;
+                Ok(self.n)
+            }
+            Some('(') => {
+                self.at += 1;
+                let value = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err("unbalanced parenthesis".to_string());
+                }
+                self.at += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.at;
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '.' || c == 'e') {
+                    self.at += 1;
+                }
+                let number: String = self.chars[start..self.at].iter().collect();
+                number.parse::<f64>().map_err(|e| format!("invalid number {:?}: {}", number, e))
+            }
+            Some(c) => Err(format!("unexpected {:?}", c)),
+            None => Err("unexpected end of formula".to_string()),
+        }
+    }
+}
+
+/// The slice of a sweep one process runs: the units whose position in the sweep is index mod count
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    /// Parses i/N, with i counting from 0
+    fn from_str(s: &str) -> Result<Shard, String> {
+        let (index, count) = s.split_once('/').ok_or_else(|| format!("expected i/N, got {:?}", s))?;
+        let index = index.trim().parse::<usize>().map_err(|e| format!("invalid shard index {:?}: {}", index, e))?;
+        let count = count.trim().parse::<usize>().map_err(|e| format!("invalid shard count {:?}: {}", count, e))?;
+        if index >= count {
+            return Err(format!("shard {} doesn't exist when there are {}", index, count));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl Shard {
+    /// The shard of the current SLURM array task, if there is one. Assumes the array is a range
+    /// like 1-500 or 0-99:2, so the tasks are numbered by their offset from SLURM_ARRAY_TASK_MIN
+    pub fn from_slurm() -> Result<Option<Shard>, String> {
+        let var = |name: &str| match std::env::var(name) {
+            Ok(value) => value.parse::<usize>().map(Some).map_err(|e| format!("invalid {} {:?}: {}", name, value, e)),
+            Err(_) => Ok(None),
+        };
+
+        let (id, count) = match (var("SLURM_ARRAY_TASK_ID")?, var("SLURM_ARRAY_TASK_COUNT")?) {
+            (Some(id), Some(count)) => (id, count),
+            _ => return Ok(None),
+        };
+        let min = var("SLURM_ARRAY_TASK_MIN")?.unwrap_or(0);
+        let step = var("SLURM_ARRAY_TASK_STEP")?.unwrap_or(1).max(1);
+
+        let index = id.checked_sub(min).map(|offset| offset / step).filter(|index| *index < count);
+        match index {
+            Some(index) => Ok(Some(Shard { index, count })),
+            None => Err(format!("SLURM array task {} isn't one of the {} tasks starting at {}", id, count, min)),
+        }
+    }
+
+    pub fn contains(&self, position: usize) -> bool {
+        position % self.count == self.index
+    }
+
+    /// The units of the shard, dealt out in turn so every shard gets a mix of lengths
+    pub fn select(&self, units: &[Unit]) -> Vec<Unit> {
+        units.iter().enumerate().filter(|(position, _)| self.contains(*position)).map(|(_, unit)| unit.clone()).collect()
+    }
+}
+
+fn default_export() -> Export {
+    Export::Analysis
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Spec {
+    pub lengths: Vec<usize>,
+
+    /// Bernoulli probabilities, or uniform sampling if empty
+    #[serde(default)]
+    pub probabilities: Vec<f64>,
+
+    /// Samples per length and probability
+    pub samples: usize,
+
+    pub shuffles: Shuffles,
+
+    pub output: PathBuf,
+
+    #[serde(default = "default_export")]
+    pub export: Export,
+
+    /// Seeds every unit with a seed derived from this one and the unit, for reproducible sweeps
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// Layout of NumPy exports, from the command line rather than the spec
+    #[serde(skip)]
+    pub grid: Grid,
+}
+
+/// One sample of a sweep
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unit {
+    pub length: usize,
+    pub probability: Option<f64>,
+    pub sample: usize,
+}
+
+impl Unit {
+    pub fn dist(&self) -> Dist {
+        match self.probability {
+            Some(p) => Dist::Bernoulli(p),
+            None => Dist::Uniform,
+        }
+    }
+
+    fn probability_name(&self) -> String {
+        match self.probability {
+            Some(p) => p.to_string(),
+            None => "uniform".to_string(),
+        }
+    }
+
+    /// Where the unit is written in the given format, relative to the output directory
+    pub fn path(&self, export: &Export) -> PathBuf {
+        Path::new(&self.length.to_string()).join(self.probability_name()).join(format!("{}.{}", self.sample, export.extension()))
+    }
+
+    /// A seed that depends only on the base seed and the unit itself, not on the rest of the spec
+    pub fn seed(&self, base: u64) -> u64 {
+        let mut seed = splitmix(base);
+        for part in [self.length as u64, self.probability.map_or(u64::MAX, f64::to_bits), self.sample as u64] {
+            seed = splitmix(seed ^ part);
+        }
+        seed
+    }
+}
+
+fn splitmix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Spec {
+    /// Reads a spec, as JSON if the file ends in .json and as TOML otherwise
+    pub fn load(path: &Path) -> Result<Spec, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let spec = if path.extension().is_some_and(|extension| extension == "json") {
+            serde_json::from_str::<Spec>(&contents).map_err(|e| e.to_string())
+        } else {
+            toml::from_str::<Spec>(&contents).map_err(|e| e.to_string())
+        };
+        spec.map_err(|e| format!("Invalid spec {}: {}", path.display(), e))
+    }
+
+    /// Every unit of the sweep, by length, then probability, then sample
+    pub fn units(&self) -> Vec<Unit> {
+        let probabilities: Vec<Option<f64>> = if self.probabilities.is_empty() {
+            vec![None]
+        } else {
+            self.probabilities.iter().map(|p| Some(*p)).collect()
+        };
+
+        let mut units = Vec::new();
+        for &length in &self.lengths {
+            for &probability in &probabilities {
+                for sample in 0..self.samples {
+                    units.push(Unit { length, probability, sample });
+                }
+            }
+        }
+        units
+    }
+
+    /// The manifest a run appends to. Shards each get their own, so that tasks sharing a
+    /// filesystem never write to the same file
+    pub fn manifest_path(&self, shard: Option<&Shard>) -> PathBuf {
+        match shard {
+            Some(shard) => self.output.join(format!("manifest-{}-of-{}.tsv", shard.index, shard.count)),
+            None => self.output.join("manifest.tsv"),
+        }
+    }
+
+    /// Paths of the units any manifest in the output directory records as complete
+    pub fn completed(&self) -> io::Result<HashSet<PathBuf>> {
+        let entries = match fs::read_dir(&self.output) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut completed = HashSet::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if !(name.starts_with("manifest") && name.ends_with(".tsv")) {
+                continue;
+            }
+
+            let file = fs::File::open(self.output.join(name.as_ref()))?;
+            for line in BufReader::new(file).lines().skip(1) {
+                if let Some(path) = line?.rsplit('\t').next() {
+                    // a unit only counts if its output survived too
+                    if self.output.join(path).exists() {
+                        completed.insert(PathBuf::from(path));
+                    }
+                }
+            }
+        }
+        Ok(completed)
+    }
+
+    /// Samples and writes one unit, then records it in the manifest
+    fn run_unit(&self, unit: &Unit, manifest: &Mutex<fs::File>) -> Result<(), String> {
+        let shuffles = self.shuffles.evaluate(unit.length)?;
+        let seed = self.seed.map(|base| unit.seed(base));
+        if let Some(seed) = seed {
+            rng::seed(seed);
+        }
+
+        let mut pfm = Polycube::new(unit.length, unit.dist());
+        pfm.shuffle(shuffles as usize);
+
+        let path = self.output.join(unit.path(&self.export));
+        let io_error = |e: io::Error| format!("Couldn't write {}: {}", path.display(), e);
+        fs::create_dir_all(path.parent().expect("units are in a directory")).map_err(io_error)?;
+
+        // write then rename, so a unit interrupted halfway is never mistaken for a complete one
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        let bytes = cli::export(&mut pfm, &self.export, &self.grid).map_err(|e| format!("Couldn't export {}: {}", path.display(), e))?;
+        fs::write(&partial, bytes).map_err(io_error)?;
+        fs::rename(&partial, &path).map_err(io_error)?;
+
+        let record = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            unit.length,
+            unit.probability_name(),
+            unit.sample,
+            shuffles,
+            seed.map_or("-".to_string(), |seed| seed.to_string()),
+            unit.path(&self.export).display()
+        );
+        manifest.lock().expect("manifest lock").write_all(record.as_bytes()).map_err(io_error)
+    }
+
+    /// Runs the given units that aren't complete yet on a pool of threads, recording them in the
+    /// given manifest. Returns the number of units run
+    pub fn run(&self, units: &[Unit], threads: usize, manifest_path: &Path) -> Result<usize, String> {
+        let io_error = |e: io::Error| format!("{}: {}", manifest_path.display(), e);
+        fs::create_dir_all(&self.output).map_err(io_error)?;
+
+        let completed = self.completed().map_err(io_error)?;
+        let pending: Vec<&Unit> = units.iter().filter(|unit| !completed.contains(&unit.path(&self.export))).collect();
+
+        let new_manifest = !manifest_path.exists();
+        let mut manifest = OpenOptions::new().create(true).append(true).open(manifest_path).map_err(io_error)?;
+        if new_manifest {
+            manifest.write_all(b"length\tprobability\tsample\tshuffles\tseed\tpath\n").map_err(io_error)?;
+        }
+        let manifest = Mutex::new(manifest);
+
+        let next = AtomicUsize::new(0);
+        let errors = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                scope.spawn(|| {
+                    while let Some(unit) = pending.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if let Err(e) = self.run_unit(unit, &manifest) {
+                            errors.lock().expect("error lock").push(e);
+                        }
+                    }
+                });
+            }
+        });
+
+        let errors = errors.into_inner().expect("error lock");
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        Ok(pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(toml: &str) -> Spec {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn evaluates_shuffle_formulas() {
+        let evaluate = |formula: &str, n| Shuffles::Formula(formula.to_string()).evaluate(n);
+        assert_eq!(evaluate("n^3", 10), Ok(1000));
+        assert_eq!(evaluate("10 * n * n", 3), Ok(90));
+        assert_eq!(evaluate("2^n^2 / (n + 1)", 2), Ok(5));
+        assert_eq!(evaluate("1.5e2 - n", 50), Ok(100));
+        assert_eq!(Shuffles::Count(7).evaluate(100), Ok(7));
+        for invalid in ["n^", "(n + 1", "n n", "m", "1 - n"] {
+            assert!(evaluate(invalid, 2).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn sweeps_every_length_probability_and_sample() {
+        let sweep = spec("lengths = [10, 20]\nprobabilities = [0.2, 0.4, 0.6]\nsamples = 4\nshuffles = 5\noutput = \"out\"");
+        let units = sweep.units();
+        assert_eq!(units.len(), 24);
+        assert_eq!(units[5], Unit { length: 10, probability: Some(0.4), sample: 1 });
+        assert!(matches!(sweep.export, Export::Analysis));
+
+        let uniform = spec("lengths = [10]\nsamples = 2\nshuffles = \"n\"\noutput = \"out\"");
+        assert_eq!(uniform.units()[1].path(&uniform.export), Path::new("10/uniform/1.txt"));
+    }
+
+    #[test]
+    fn shards_split_a_sweep() {
+        assert_eq!("2/5".parse(), Ok(Shard { index: 2, count: 5 }));
+        for invalid in ["5/5", "2", "a/5"] {
+            assert!(invalid.parse::<Shard>().is_err(), "{}", invalid);
+        }
+
+        let sweep = spec("lengths = [10, 20, 30]\nprobabilities = [0.5]\nsamples = 5\nshuffles = 5\noutput = \"out\"");
+        let units = sweep.units();
+        let shards: Vec<Vec<Unit>> = (0..4).map(|index| Shard { index, count: 4 }.select(&units)).collect();
+        assert_eq!(shards.iter().map(Vec::len).sum::<usize>(), units.len());
+        assert!(units.iter().all(|unit| shards.iter().filter(|shard| shard.contains(unit)).count() == 1));
+        assert_ne!(sweep.manifest_path(Some(&Shard { index: 0, count: 4 })), sweep.manifest_path(Some(&Shard { index: 1, count: 4 })));
+    }
+
+    #[test]
+    fn unit_seeds_depend_only_on_the_unit() {
+        let unit = Unit { length: 10, probability: Some(0.5), sample: 3 };
+        assert_eq!(unit.seed(1), Unit { length: 10, probability: Some(0.5), sample: 3 }.seed(1));
+        assert_ne!(unit.seed(1), unit.seed(2));
+        assert_ne!(unit.seed(1), Unit { sample: 4, ..unit.clone() }.seed(1));
+        assert_ne!(unit.seed(1), Unit { probability: None, ..unit.clone() }.seed(1));
+    }
+
+    #[test]
+    fn units_are_written_with_the_extension_of_their_format() {
+        let output = std::env::temp_dir().join(format!("polyforms-experiment-{}", std::process::id()));
+        let spec: Spec = toml::from_str(&format!(
+            "lengths = [4]\nprobabilities = [0.5]\nsamples = 2\nshuffles = \"n\"\nexport = \"vox\"\nseed = 1\noutput = {:?}",
+            output.display().to_string()
+        ))
+        .unwrap();
+        let units = spec.units();
+        assert_eq!(units[1].path(&spec.export), Path::new("4/0.5/1.vox"));
+        assert_eq!(units[1].path(&Export::Analysis), Path::new("4/0.5/1.txt"));
+
+        let manifest = spec.manifest_path(None);
+        assert_eq!(spec.run(&units, 2, &manifest).unwrap(), 2);
+        let written = fs::read(output.join("4/0.5/0.vox")).unwrap();
+        assert!(written.starts_with(b"VOX "));
+        assert_eq!(spec.completed().unwrap().len(), 2);
+        assert_eq!(spec.run(&units, 2, &manifest).unwrap(), 0);
+        fs::remove_dir_all(&output).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+//! Polycubes the tests of several modules share.
+
+use crate::{rng, Dist, Polycube};
+
+type Cell = (i32, i32, i32);
+
+/// Every cell of an x by y by z box with a corner at the origin
+pub fn block(x: i32, y: i32, z: i32) -> impl Iterator<Item = Cell> {
+    (0..x).flat_map(move |i| (0..y).flat_map(move |j| (0..z).map(move |k| (i, j, k))))
+}
+
+/// A uniformly weighted polycube of the cells, connected or not
+pub fn polycube(cells: impl IntoIterator<Item = Cell>) -> Polycube {
+    Polycube::with_cells(cells, Dist::Uniform)
+}
+
+/// A 3x3x3 cube without its middle cell, which is the smallest cavity
+pub fn hollow_cube() -> Polycube {
+    polycube(block(3, 3, 3).filter(|cell| *cell != (1, 1, 1)))
+}
+
+/// 40 cells shuffled 2000 times under Bernoulli(0.5), with the thread's generator seeded first
+pub fn shuffled(seed: u64) -> Polycube {
+    rng::seed(seed);
+    let mut pfm = Polycube::new(40, Dist::Bernoulli(0.5));
+    pfm.shuffle(2000);
+    pfm
+}
+
+/// The cells of a polycube, sorted
+pub fn sorted(pfm: &Polycube) -> Vec<Cell> {
+    let mut cells: Vec<Cell> = pfm.complex.iter().copied().collect();
+    cells.sort_unstable();
+    cells
+}
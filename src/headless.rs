@@ -0,0 +1,303 @@
+//! Rendering to images on the CPU, for machines without a display or OpenGL.
+//!
+//! The exposed faces of the cells are projected orthographically from a camera orbiting the
+//! polycube, rasterized with a depth buffer, and shaded by a light that moves with the camera, with
+//! their edges drawn darker so that the cubes stand out. The last move is highlighted like the live renderer does:
+//! the inserted cell in green and the outline of the removed cell in red.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::image::Image;
+use crate::storage::CellStorage;
+use crate::Polycube;
+
+type Cell = (i32, i32, i32);
+
+const BACKGROUND: [u8; 3] = [0, 0, 0];
+const CELL: [f32; 3] = [0.4, 0.5, 0.9];
+const PINNED: [f32; 3] = [1.0, 0.6, 0.0];
+const INSERTED: [f32; 3] = [0.0, 1.0, 0.0];
+const REMOVED: [u8; 3] = [255, 0, 0];
+
+#[derive(Clone, Debug)]
+pub struct Camera {
+    /// Rotation around the z axis, in degrees
+    pub yaw: f32,
+    /// Elevation above the xy plane, in degrees
+    pub pitch: f32,
+    pub width: usize,
+    pub height: usize,
+    /// Pixels per cell, or None to fit the polycube in the image
+    pub scale: Option<f32>,
+}
+
+impl Default for Camera {
+    /// An isometric view
+    fn default() -> Camera {
+        Camera { yaw: 45.0, pitch: 35.264, width: 512, height: 512, scale: None }
+    }
+}
+
+// the camera's axes in world coordinates
+struct View {
+    right: [f32; 3],
+    up: [f32; 3],
+    /// towards the viewer
+    forward: [f32; 3],
+    centre: [f32; 3],
+    scale: f32,
+    offset: (f32, f32),
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+impl View {
+    fn new(camera: &Camera, cells: &[Cell]) -> View {
+        let (yaw, pitch) = (camera.yaw.to_radians(), camera.pitch.to_radians());
+        let right = [yaw.cos(), -yaw.sin(), 0.0];
+        let forward = [-yaw.sin() * pitch.cos(), -yaw.cos() * pitch.cos(), pitch.sin()];
+        let up = [yaw.sin() * pitch.sin(), yaw.cos() * pitch.sin(), pitch.cos()];
+
+        let mut view = View { right, up, forward, centre: [0.0; 3], scale: 1.0, offset: (camera.width as f32 / 2.0, camera.height as f32 / 2.0) };
+        if cells.is_empty() {
+            return view;
+        }
+
+        // centre the bounding box, and fit its projection with a margin if there is no scale
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for cell in cells {
+            let cell = [cell.0 as f32, cell.1 as f32, cell.2 as f32];
+            for axis in 0..3 {
+                min[axis] = min[axis].min(cell[axis]);
+                max[axis] = max[axis].max(cell[axis] + 1.0);
+            }
+        }
+        view.centre = [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0, (min[2] + max[2]) / 2.0];
+
+        view.scale = match camera.scale {
+            Some(scale) => scale,
+            None => {
+                let (mut width, mut height) = (0f32, 0f32);
+                for corner in 0..8 {
+                    let point = [
+                        if corner & 1 == 0 { min[0] } else { max[0] },
+                        if corner & 2 == 0 { min[1] } else { max[1] },
+                        if corner & 4 == 0 { min[2] } else { max[2] },
+                    ];
+                    let (x, y, _) = view.project_unscaled(point);
+                    width = width.max(2.0 * x.abs());
+                    height = height.max(2.0 * y.abs());
+                }
+                0.9 * (camera.width as f32 / width).min(camera.height as f32 / height)
+            }
+        };
+        view
+    }
+
+    fn project_unscaled(&self, point: [f32; 3]) -> (f32, f32, f32) {
+        let p = [point[0] - self.centre[0], point[1] - self.centre[1], point[2] - self.centre[2]];
+        (dot(p, self.right), dot(p, self.up), dot(p, self.forward))
+    }
+
+    /// Pixel coordinates and depth, larger depths being closer to the viewer
+    fn project(&self, point: Cell) -> (f32, f32, f32) {
+        let (x, y, depth) = self.project_unscaled([point.0 as f32, point.1 as f32, point.2 as f32]);
+        (self.offset.0 + x * self.scale, self.offset.1 - y * self.scale, depth)
+    }
+}
+
+struct Raster {
+    image: Image,
+    depth: Vec<f32>,
+}
+
+impl Raster {
+    /// Fills the triangle a b c, where uv gives the position of each vertex on its face for
+    /// drawing edges
+    fn triangle(&mut self, vertices: [(f32, f32, f32); 3], uv: [(f32, f32); 3], color: [f32; 3], scale: f32) {
+        let [a, b, c] = vertices;
+        let area = (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+        if area.abs() < f32::EPSILON {
+            return;
+        }
+
+        let x_min = a.0.min(b.0).min(c.0).floor().max(0.0) as usize;
+        let y_min = a.1.min(b.1).min(c.1).floor().max(0.0) as usize;
+        let x_max = (a.0.max(b.0).max(c.0).ceil() as usize).min(self.image.width);
+        let y_max = (a.1.max(b.1).max(c.1).ceil() as usize).min(self.image.height);
+
+        for y in y_min..y_max {
+            for x in x_min..x_max {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                // barycentric coordinates of the pixel centre
+                let wa = ((b.0 - px) * (c.1 - py) - (b.1 - py) * (c.0 - px)) / area;
+                let wb = ((c.0 - px) * (a.1 - py) - (c.1 - py) * (a.0 - px)) / area;
+                let wc = 1.0 - wa - wb;
+                if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                    continue;
+                }
+
+                let depth = wa * a.2 + wb * b.2 + wc * c.2;
+                let index = y * self.image.width + x;
+                if depth <= self.depth[index] {
+                    continue;
+                }
+                self.depth[index] = depth;
+
+                let u = wa * uv[0].0 + wb * uv[1].0 + wc * uv[2].0;
+                let v = wa * uv[0].1 + wb * uv[1].1 + wc * uv[2].1;
+                let edge = u.min(1.0 - u).min(v).min(1.0 - v) * scale;
+                let shade = if edge < 1.0 { 0.5 } else { 1.0 };
+                self.image.set(x, y, color.map(|channel| (channel * shade * 255.0).clamp(0.0, 255.0) as u8));
+            }
+        }
+    }
+
+    /// Draws a line on top of everything
+    fn line(&mut self, from: (f32, f32), to: (f32, f32), color: [u8; 3]) {
+        let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).ceil().max(1.0) as usize;
+        for step in 0..=steps {
+            let t = step as f32 / steps as f32;
+            let (x, y) = (from.0 + t * (to.0 - from.0), from.1 + t * (to.1 - from.1));
+            if x >= 0.0 && y >= 0.0 && (x as usize) < self.image.width && (y as usize) < self.image.height {
+                self.image.set(x as usize, y as usize, color);
+            }
+        }
+    }
+}
+
+impl<S: CellStorage> Polycube<S> {
+    // O(n + pixels)
+    /// Draws the polycube as seen from the camera, highlighting the last (inserted, removed) move
+    /// like `shuffle` returns it
+    pub fn render_image(&self, camera: &Camera, last_shuffled: Option<(Cell, Cell)>) -> Image {
+        let cells: Vec<Cell> = self.complex.cells().collect();
+        let view = View::new(camera, &cells);
+        let mut raster = Raster { image: Image::new(camera.width, camera.height, BACKGROUND), depth: vec![f32::MIN; camera.width * camera.height] };
+
+        // light from over the viewer's left shoulder, so that the three visible sides differ
+        let light = {
+            let light: [f32; 3] = std::array::from_fn(|axis| 0.8 * view.forward[axis] + 0.5 * view.up[axis] - 0.3 * view.right[axis]);
+            let norm = dot(light, light).sqrt();
+            light.map(|c| c / norm)
+        };
+
+        for face in self.surface_faces() {
+            let normal = face.normal();
+            let normal = [normal.0 as f32, normal.1 as f32, normal.2 as f32];
+            if dot(normal, view.forward) <= 0.0 {
+                continue;
+            }
+
+            let base = if last_shuffled.is_some_and(|(inserted, _)| inserted == face.cell) {
+                INSERTED
+            } else if self.is_pinned(&face.cell) {
+                PINNED
+            } else {
+                CELL
+            };
+            let brightness = 0.45 + 0.55 * dot(normal, light).max(0.0);
+            let color = base.map(|channel| channel * brightness);
+
+            let [a, b, c, d] = face.corners().map(|corner| view.project(corner));
+            raster.triangle([a, b, c], [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)], color, view.scale);
+            raster.triangle([a, c, d], [(0.0, 0.0), (1.0, 1.0), (0.0, 1.0)], color, view.scale);
+        }
+
+        if let Some((_, removed)) = last_shuffled {
+            for (from, to) in cube_edges(removed) {
+                let (from, to) = (view.project(from), view.project(to));
+                raster.line((from.0, from.1), (to.0, to.1), REMOVED);
+            }
+        }
+
+        raster.image
+    }
+
+    /// Shuffles, writing a frame to dir/frame-00000.png, dir/frame-00001.png, ... before the
+    /// first shuffle and then every shuffles_per_frame shuffles. Unless the camera has a scale,
+    /// the first frame fixes it, like the live renderer places its camera from the initial
+    /// polycube, so that the polycube doesn't appear to grow and shrink. Returns the number of
+    /// frames
+    pub fn shuffle_frames(&mut self, shuffles: usize, shuffles_per_frame: usize, camera: &Camera, dir: &Path) -> io::Result<usize> {
+        fs::create_dir_all(dir)?;
+
+        let mut camera = camera.clone();
+        if camera.scale.is_none() {
+            let cells: Vec<Cell> = self.complex.cells().collect();
+            camera.scale = Some(View::new(&camera, &cells).scale);
+        }
+
+        let shuffles_per_frame = shuffles_per_frame.max(1);
+        let mut frames = 0;
+        let mut last_shuffled = None;
+        let mut done = 0;
+        loop {
+            let path = dir.join(format!("frame-{:05}.png", frames));
+            self.render_image(&camera, last_shuffled).save_png(&path)?;
+            frames += 1;
+
+            if done >= shuffles {
+                return Ok(frames);
+            }
+            let batch = shuffles_per_frame.min(shuffles - done);
+            last_shuffled = self.shuffle(batch);
+            done += batch;
+        }
+    }
+}
+
+fn cube_edges(cell: Cell) -> Vec<(Cell, Cell)> {
+    let corner = |i: i32| (cell.0 + (i & 1), cell.1 + ((i >> 1) & 1), cell.2 + ((i >> 2) & 1));
+    let mut edges = Vec::new();
+    for i in 0..8 {
+        for bit in [1, 2, 4] {
+            if i & bit == 0 {
+                edges.push((corner(i), corner(i | bit)));
+            }
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng, Dist};
+
+    fn drawn(image: &Image) -> usize {
+        image.pixels.iter().filter(|pixel| **pixel != BACKGROUND).count()
+    }
+
+    #[test]
+    fn renders_inside_the_image() {
+        let pfm = Polycube::new(5, Dist::Uniform);
+        let camera = Camera { width: 64, height: 48, ..Camera::default() };
+        let image = pfm.render_image(&camera, None);
+        assert_eq!((image.width, image.height), (64, 48));
+        // fitted to the image, with a margin
+        assert!(drawn(&image) > 64 * 48 / 20);
+        assert!((0..64).all(|x| image.get(x, 0) == BACKGROUND && image.get(x, 47) == BACKGROUND));
+
+        // the removed cell is outlined in red
+        let moved = pfm.render_image(&camera, Some(((0, 0, 4), (1, 0, 0))));
+        assert!(moved.pixels.contains(&REMOVED));
+    }
+
+    #[test]
+    fn writes_a_frame_before_the_first_shuffle_and_after_every_batch() {
+        rng::seed(40);
+        let dir = std::env::temp_dir().join(format!("polyforms-frames-{}", std::process::id()));
+        let mut pfm = Polycube::new(6, Dist::Uniform);
+        let camera = Camera { width: 16, height: 16, ..Camera::default() };
+        assert_eq!(pfm.shuffle_frames(25, 10, &camera, &dir).unwrap(), 4);
+        assert!(dir.join("frame-00003.png").exists() && !dir.join("frame-00004.png").exists());
+        assert_eq!(pfm.stats.proposed, 25);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
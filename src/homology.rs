@@ -0,0 +1,180 @@
+//! Betti numbers of a polycube, without calling out to Perseus.
+//!
+//! A polycube is a union of closed unit cubes in R^3, so its Betti numbers are determined by
+//! three counts:
+//! - B0, the number of connected components of the union. Cubes touching at a vertex or an edge
+//!   are connected here, unlike in the face-connectivity used for shuffling
+//! - B2, the number of enclosed cavities, by Alexander duality. The complement is connected
+//!   through faces only, since cubes around a shared edge or vertex close it off
+//! - the Euler characteristic V - E + F - C of the cubical complex, from which B1 = B0 + B2 - χ
+
+use std::collections::HashSet;
+
+use crate::storage::CellStorage;
+use crate::Polycube;
+
+impl<S: CellStorage> Polycube<S> {
+    // O(n)
+    /// Euler characteristic of the union of the cubes, counting every vertex, edge, face and cube
+    /// of the cubical complex once
+    pub fn euler_characteristic(&self) -> i64 {
+        // cells of the complex are identified by doubled coordinates, so the centre of the unit
+        // cube at (x, y, z) is (2x+1, 2y+1, 2z+1), and a face, edge or vertex is found by moving
+        // the odd coordinates by one
+        let mut vertices = HashSet::new();
+        let mut edges = HashSet::new();
+        let mut faces = HashSet::new();
+
+        for piece in self.complex.cells() {
+            let centre = (2 * piece.0 + 1, 2 * piece.1 + 1, 2 * piece.2 + 1);
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let cell = (centre.0 + dx, centre.1 + dy, centre.2 + dz);
+                        // the number of coordinates we moved is the codimension of the cell
+                        match (dx != 0) as u8 + (dy != 0) as u8 + (dz != 0) as u8 {
+                            1 => { faces.insert(cell); }
+                            2 => { edges.insert(cell); }
+                            3 => { vertices.insert(cell); }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        vertices.len() as i64 - edges.len() as i64 + faces.len() as i64 - self.complex.len() as i64
+    }
+
+    // O(n)
+    /// Connected components of the union of the cubes, where cubes sharing a vertex or an edge
+    /// count as connected
+    pub fn union_components(&self) -> usize {
+        let mut visited = self.complex.empty_like();
+        let mut components = 0;
+
+        for start in self.complex.cells() {
+            if !visited.insert(start) {
+                continue;
+            }
+            components += 1;
+
+            let mut stack = vec![start];
+            while let Some(center) = stack.pop() {
+                for dx in -1..=1 {
+                    for dy in -1..=1 {
+                        for dz in -1..=1 {
+                            let neighbor = (center.0 + dx, center.1 + dy, center.2 + dz);
+                            if self.complex.contains(&neighbor) && visited.insert(neighbor) {
+                                stack.push(neighbor);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        components
+    }
+
+    // O(volume of the bounding box)
+    /// Number of enclosed components of the complement
+    pub fn cavity_count(&self) -> usize {
+        let (min, max) = match self.tight_bounds() {
+            Some(bounds) => bounds,
+            None => return 0,
+        };
+        let min = (min.0 - 1, min.1 - 1, min.2 - 1);
+        let max = (max.0 + 1, max.1 + 1, max.2 + 1);
+
+        let mut visited = HashSet::new();
+        let mut components = 0;
+
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    let start = (x, y, z);
+                    if self.complex.contains(&start) || visited.contains(&start) {
+                        continue;
+                    }
+                    components += 1;
+                    visited.extend(self.flood_complement(start, min, max));
+                }
+            }
+        }
+
+        // one of the components is the outside, which the padding keeps in one piece
+        components - 1
+    }
+
+    // O(volume of the bounding box)
+    /// The vacant cells the outside can't reach through faces, in any of the cavities. `cavities`
+    /// splits them into each cavity
+    pub fn enclosed_cells(&self) -> HashSet<(i32, i32, i32)> {
+        let (min, max) = match self.tight_bounds() {
+            Some(bounds) => bounds,
+            None => return HashSet::new(),
+        };
+        let min = (min.0 - 1, min.1 - 1, min.2 - 1);
+        let max = (max.0 + 1, max.1 + 1, max.2 + 1);
+
+        // the padding is all outside, so a corner of it reaches every vacant cell that isn't enclosed
+        let outside = self.flood_complement(min, min, max);
+        let mut enclosed = HashSet::new();
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    let cell = (x, y, z);
+                    if !self.complex.contains(&cell) && !outside.contains(&cell) {
+                        enclosed.insert(cell);
+                    }
+                }
+            }
+        }
+        enclosed
+    }
+
+    /// Betti numbers B0, B1 and B2 of the union of the cubes
+    pub fn betti_numbers(&self) -> [usize; 3] {
+        let b0 = self.union_components();
+        let b2 = self.cavity_count();
+        let b1 = b0 as i64 + b2 as i64 - self.euler_characteristic();
+
+        [b0, b1 as usize, b2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::{block, hollow_cube, polycube};
+
+    #[test]
+    fn betti_numbers_of_known_shapes() {
+        let solid = polycube(block(3, 3, 3));
+        assert_eq!(solid.betti_numbers(), [1, 0, 0]);
+        assert_eq!(solid.euler_characteristic(), 1);
+
+        let hollow = hollow_cube();
+        assert_eq!(hollow.betti_numbers(), [1, 0, 1]);
+        assert_eq!(hollow.euler_characteristic(), 2);
+
+        let two_cavities = polycube(block(5, 3, 3).filter(|cell| *cell != (1, 1, 1) && *cell != (3, 1, 1)));
+        assert_eq!(two_cavities.betti_numbers(), [1, 0, 2]);
+
+        let ring = polycube(block(3, 3, 1).filter(|cell| *cell != (1, 1, 0)));
+        assert_eq!(ring.betti_numbers(), [1, 1, 0]);
+        assert_eq!(ring.euler_characteristic(), 0);
+    }
+
+    #[test]
+    fn cubes_sharing_an_edge_or_vertex_are_connected() {
+        assert_eq!(polycube([(0, 0, 0), (1, 1, 0)]).betti_numbers(), [1, 0, 0]);
+        assert_eq!(polycube([(0, 0, 0), (1, 1, 1)]).betti_numbers(), [1, 0, 0]);
+        assert_eq!(polycube([(0, 0, 0), (2, 0, 0)]).betti_numbers(), [2, 0, 0]);
+
+        // four cubes around a square, each sharing an edge with the next, make a loop but no
+        // cavity, since the square is open above and below
+        let diamond = polycube([(0, 0, 0), (1, 1, 0), (0, 2, 0), (-1, 1, 0)]);
+        assert_eq!(diamond.betti_numbers(), [1, 1, 0]);
+    }
+}
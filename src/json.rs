@@ -0,0 +1,211 @@
+//! JSON exports that carry how a polycube was produced along with its cells, so that notebooks
+//! can read samples without recovering the parameters from file paths.
+//!
+//! `export_json` writes one indented object. `export_ndjson` writes the same object on a single
+//! line, so that a stream of samples, such as `polyforms unpack --format ndjson`, has one sample
+//! per line. `import_json` reads the cells, pinned cells and distribution back from either.
+
+use serde::{Deserialize, Serialize};
+
+use crate::load::Provenance;
+use crate::observables::Observables;
+use crate::storage::CellStorage;
+use crate::{rng, Dist, Polycube, ShuffleStats};
+
+/// The distribution a polycube was sampled from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DistRecord {
+    Uniform,
+    Bernoulli { p: f64 },
+}
+
+impl From<&Dist> for DistRecord {
+    fn from(dist: &Dist) -> DistRecord {
+        match dist {
+            Dist::Uniform => DistRecord::Uniform,
+            Dist::Bernoulli(p) => DistRecord::Bernoulli { p: *p },
+        }
+    }
+}
+
+impl From<DistRecord> for Dist {
+    fn from(dist: DistRecord) -> Dist {
+        match dist {
+            DistRecord::Uniform => Dist::Uniform,
+            DistRecord::Bernoulli { p } => Dist::Bernoulli(p),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Acceptance {
+    #[serde(flatten)]
+    pub stats: ShuffleStats,
+    pub rate: f64,
+}
+
+/// The shape statistics of `observables` along with the Betti numbers
+#[derive(Clone, Debug, Serialize)]
+pub struct ObservablesRecord {
+    #[serde(flatten)]
+    pub shape: Observables,
+    pub betti: [usize; 3],
+    pub euler_characteristic: i64,
+}
+
+/// Everything the JSON exports write about a polycube
+#[derive(Clone, Debug, Serialize)]
+pub struct Record {
+    pub lattice: &'static str,
+    /// How cells are connected, through faces on the site lattice
+    pub connectivity: &'static str,
+    pub n: usize,
+    pub cells: Vec<[i32; 3]>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pinned: Vec<[i32; 3]>,
+    pub dist: DistRecord,
+    /// The seed of the generator, if it was seeded
+    pub seed: Option<u64>,
+    pub shuffles: u64,
+    pub acceptance: Acceptance,
+    pub site_perimeter: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observables: Option<ObservablesRecord>,
+}
+
+impl Record {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("records serialize")
+    }
+
+    pub fn to_ndjson(&self) -> String {
+        serde_json::to_string(self).expect("records serialize")
+    }
+}
+
+/// The parts of a record that make up the polycube again
+#[derive(Deserialize)]
+struct Imported {
+    cells: Vec<[i32; 3]>,
+    #[serde(default)]
+    pinned: Vec<[i32; 3]>,
+    dist: Option<DistRecord>,
+    seed: Option<u64>,
+    shuffles: Option<u64>,
+}
+
+impl<S: CellStorage> Polycube<S> {
+    /// Imports every record of the JSON or NDJSON the exports write, with its pinned cells and
+    /// distribution. Fails if the cells of a record aren't connected
+    pub fn import_json(json: &str) -> Result<Vec<Polycube<S>>, String> {
+        Ok(Self::parse_json(json)?.into_iter().map(|(pfm, _)| pfm).collect())
+    }
+
+    /// Imports every record like `import_json`, along with the distribution, seed and shuffles it
+    /// records
+    pub fn parse_json(json: &str) -> Result<Vec<(Polycube<S>, Provenance)>, String> {
+        serde_json::Deserializer::from_str(json)
+            .into_iter::<Imported>()
+            .map(|record| {
+                let record = record.map_err(|e| e.to_string())?;
+                let provenance = Provenance { dist: record.dist.map(Dist::from), seed: record.seed, steps: record.shuffles };
+                let dist = provenance.dist.clone().unwrap_or(Dist::Uniform);
+                let mut pfm = Polycube::try_from_cells(record.cells.iter().map(|cell| (cell[0], cell[1], cell[2])), dist)?;
+                for cell in record.pinned {
+                    if !pfm.pin((cell[0], cell[1], cell[2])) {
+                        return Err(format!("the pinned cell {:?} isn't one of the cells", cell));
+                    }
+                }
+                Ok((pfm, provenance))
+            })
+            .collect()
+    }
+
+    /// The cells and metadata of the polycube, with the seed of the current thread's generator and
+    /// the shuffles counted since it was built. Observables are computed only if asked for
+    pub fn record(&self, observables: bool) -> Record {
+        let mut cells: Vec<[i32; 3]> = self.complex.cells().map(|cell| [cell.0, cell.1, cell.2]).collect();
+        cells.sort_unstable();
+        let mut pinned: Vec<[i32; 3]> = self.pinned.iter().map(|cell| [cell.0, cell.1, cell.2]).collect();
+        pinned.sort_unstable();
+
+        Record {
+            lattice: "site",
+            connectivity: "face",
+            n: cells.len(),
+            cells,
+            pinned,
+            dist: DistRecord::from(&self.dist),
+            seed: rng::current_seed(),
+            shuffles: self.stats.proposed,
+            acceptance: Acceptance { stats: self.stats, rate: self.stats.acceptance_rate() },
+            site_perimeter: self.insertable_locations.len(),
+            observables: observables.then(|| ObservablesRecord {
+                shape: self.observables(),
+                betti: self.betti_numbers(),
+                euler_characteristic: self.euler_characteristic(),
+            }),
+        }
+    }
+
+    /// Exports the cells and metadata as an indented JSON object
+    pub fn export_json(&self, observables: bool) -> String {
+        self.record(observables).to_json()
+    }
+
+    /// Exports the cells and metadata as a JSON object on one line
+    pub fn export_ndjson(&self, observables: bool) -> String {
+        self.record(observables).to_ndjson()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::CellSet;
+
+    #[test]
+    fn records_how_the_polycube_was_sampled() {
+        rng::seed(46);
+        let mut pfm = Polycube::new(20, Dist::Bernoulli(0.3));
+        pfm.shuffle(500);
+        let lowest = *pfm.complex.iter().min().unwrap();
+        assert!(pfm.pin(lowest));
+
+        let value: serde_json::Value = serde_json::from_str(&pfm.export_json(true)).unwrap();
+        assert_eq!(value["n"], 20);
+        assert_eq!(value["seed"], 46);
+        assert_eq!(value["shuffles"], 500);
+        assert_eq!(value["dist"], serde_json::json!({ "kind": "bernoulli", "p": 0.3 }));
+        assert_eq!(value["pinned"], serde_json::json!([[lowest.0, lowest.1, lowest.2]]));
+        assert_eq!(value["acceptance"]["proposed"], 500);
+        assert_eq!(value["observables"]["betti"][0], 1);
+        assert_eq!(value["observables"]["site_perimeter"], pfm.insertable_locations.len());
+
+        let line = pfm.export_ndjson(false);
+        assert!(!line.contains('\n') && !line.contains("observables"));
+    }
+
+    #[test]
+    fn imports_what_it_exports() {
+        rng::seed(46);
+        let mut pfm = Polycube::new(20, Dist::Bernoulli(0.3));
+        pfm.shuffle(500);
+        let lowest = *pfm.complex.iter().min().unwrap();
+        assert!(pfm.pin(lowest));
+
+        let imported: Vec<Polycube> = Polycube::import_json(&pfm.export_json(true)).unwrap();
+        let mut cells: Vec<_> = imported[0].complex.iter().copied().collect();
+        let mut expected: Vec<_> = pfm.complex.iter().copied().collect();
+        cells.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(cells, expected);
+        assert_eq!(imported[0].pinned(), pfm.pinned());
+        assert!(matches!(imported[0].dist, Dist::Bernoulli(p) if p == 0.3));
+
+        assert!(Polycube::<CellSet>::import_json(r#"{"cells": [[0, 0, 0], [2, 0, 0]]}"#).is_err());
+        assert!(Polycube::<CellSet>::import_json(r#"{"cells": [[0, 0, 0]], "pinned": [[1, 0, 0]]}"#).is_err());
+        assert!(Polycube::<CellSet>::import_json(r#"{"cells": [[0, 0, 0]]} {"n": 1}"#).is_err());
+    }
+}
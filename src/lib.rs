@@ -1,12 +1,37 @@
 extern crate kiss3d;
 extern crate rand;
-extern crate nom;
+
+pub mod bond;
+pub mod cavity;
+pub mod cli;
+pub mod aggregate;
+pub mod anneal;
+pub mod connectivity;
+pub mod container;
+pub mod energy;
+pub mod enumerate;
+pub mod experiment;
+#[cfg(test)]
+mod fixtures;
+pub mod headless;
+pub mod homology;
+pub mod image;
+pub mod json;
+pub mod load;
+pub mod mesh;
+pub mod nfold;
+pub mod npy;
+pub mod observables;
+pub mod perseus;
+pub mod region;
+pub mod rng;
+pub mod slices;
+pub mod storage;
+pub mod voxel;
 
 use std::collections::HashSet;
 use std::mem;
 
-use nom::bytes::complete::take_while;
-use nom::character::is_digit;
 use rand::{Rng, random};
 use rand::distributions::{Bernoulli, Distribution};
 
@@ -19,24 +44,61 @@ use kiss3d::camera::ArcBall;
 use kiss3d::nalgebra::Point3;
 use kiss3d::scene::SceneNode;
 use kiss3d::window::State;
-
-// import/export
-use nom::{
-  IResult,
-  bytes::complete::tag,
-  sequence::tuple
-};
-
+use kiss3d::event::{Action, Key, WindowEvent};
 
 // wasm
 use wasm_bindgen::prelude::*;
 
-#[derive(Clone, Debug)]
+use connectivity::DynamicConnectivity;
+use storage::{CellSet, CellStorage};
+use region::Region;
+use energy::{EnergyModel, Move};
+use observables::Moments;
+
+/// The (min, max) corners of a box of cells
+pub type Bounds = ((i32, i32, i32), (i32, i32, i32));
+
+/// A cell moved by `shuffle`, as (inserted, removed)
+pub type Shuffled = ((i32, i32, i32), (i32, i32, i32));
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Dist {
     Bernoulli(f64),
     Uniform
 }
 
+/// What became of the moves `shuffle` has proposed since the polycube was built
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize)]
+pub struct ShuffleStats {
+    pub proposed: u64,
+    pub accepted: u64,
+    /// Rejected by the Metropolis coin flip on the weights of the two states
+    pub rejected_by_weight: u64,
+    /// Rejected for disconnecting the polycube, leaving the wall or breaking a constraint
+    pub rejected_as_invalid: u64,
+}
+
+impl ShuffleStats {
+    /// The fraction of proposed moves that were accepted
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.proposed == 0 {
+            0.0
+        } else {
+            self.accepted as f64 / self.proposed as f64
+        }
+    }
+}
+
+/// Restricted classes of polycubes. The shuffle rejects any move that leaves the class, on top of
+/// rejecting moves that disconnect the polycube
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constraint {
+    /// Lattice trees: the adjacency graph of the cells has no cycles
+    Tree,
+    /// No enclosed cavities: the complement of the polycube is connected
+    NoCavities,
+}
+
 pub trait Polyform<E> {
     /// Returns a list of all neighbors of the provided element
     fn get_neighbors(&self, element: E) -> Vec<E>;
@@ -66,11 +128,11 @@ pub trait Polyform<E> {
 
 }
 
-/// Represents a 3D Polyform
-pub struct Polycube {
+/// Represents a 3D Polyform, with its cells kept in any `CellStorage`
+pub struct Polycube<S: CellStorage = CellSet> {
     
     // The actual polyform
-    pub complex: HashSet<(i32, i32, i32)>,
+    pub complex: S,
 
     // Bookkeeping information to speed up operations on the polyform
 
@@ -87,14 +149,40 @@ pub struct Polycube {
     // keeps track of all empty locations strongly connected to a piece
     // beacuse this includes holes, we can't use this as a "tighter bounding box". There may be
     // other ways to use this information to speed up check validitiy, but for now
-    pub insertable_locations: HashSet<(i32, i32, i32)>,
+    pub insertable_locations: CellSet,
 
     // distribution
     pub dist: Dist,
+
+    // optional dynamic connectivity backend, kept in sync by insert and remove. When present,
+    // shuffle asks it whether the polyform is still connected instead of running a DFS
+    pub connectivity: Option<DynamicConnectivity>,
+
+    // number of pairs of cells that share a face, i.e. the number of edges of the adjacency graph
+    contacts: usize,
+
+    // classes of polycubes the shuffle is restricted to
+    pub constraints: Vec<Constraint>,
+
+    // region the polycube is confined to, masks insertable_locations
+    pub region: Option<Region>,
+
+    // cells that never move during a shuffle, such as a seed at the origin or a scaffold. Always
+    // a subset of complex
+    pinned: HashSet<(i32, i32, i32)>,
+
+    // Boltzmann weighting of moves on top of dist
+    pub energy: Option<Box<dyn EnergyModel>>,
+
+    // counts of accepted and rejected moves, for exports
+    pub stats: ShuffleStats,
+
+    // sums of the coordinates and their products, for the gyration tensor
+    moments: Moments,
 }
 
 // O(1)
-fn get_neighbors(set: &HashSet<(i32, i32, i32)>, block: &(i32, i32, i32)) -> Vec<(i32, i32, i32)> {
+fn get_neighbors<S: CellStorage>(set: &S, block: &(i32, i32, i32)) -> Vec<(i32, i32, i32)> {
     let mut neighbors = Vec::<(i32, i32, i32)>::new();
 
     if set.contains(&(block.0, block.1, block.2 + 1)) {
@@ -120,7 +208,7 @@ fn get_neighbors(set: &HashSet<(i32, i32, i32)>, block: &(i32, i32, i32)) -> Vec
 }
 
 // O(1)
-fn get_vacant_neighbors(set: &HashSet<(i32, i32, i32)>, block: &(i32, i32, i32)) -> Vec<(i32, i32, i32)> {
+fn get_vacant_neighbors<S: CellStorage>(set: &S, block: &(i32, i32, i32)) -> Vec<(i32, i32, i32)> {
     let mut neighbors = Vec::<(i32, i32, i32)>::new();
 
     if !set.contains(&(block.0, block.1, block.2 + 1)) {
@@ -146,7 +234,7 @@ fn get_vacant_neighbors(set: &HashSet<(i32, i32, i32)>, block: &(i32, i32, i32))
 }
 
 // O(1)
-fn has_neighbor(set: &HashSet<(i32, i32, i32)>, piece: &(i32, i32, i32)) -> bool {
+fn has_neighbor<S: CellStorage>(set: &S, piece: &(i32, i32, i32)) -> bool {
         set.contains(&(piece.0, piece.1, piece.2+1))
                 || set.contains(&(piece.0, piece.1, piece.2-1)) 
                 || set.contains(&(piece.0, piece.1+1, piece.2)) 
@@ -155,14 +243,14 @@ fn has_neighbor(set: &HashSet<(i32, i32, i32)>, piece: &(i32, i32, i32)) -> bool
                 || set.contains(&(piece.0-1, piece.1, piece.2))
 }
 
-fn get_random(set: &HashSet<(i32, i32, i32)>) -> (i32, i32, i32) {
-    let mut rng = rand::thread_rng();
+fn get_random<S: CellStorage>(set: &S) -> (i32, i32, i32) {
+    let mut rng = rng::thread_rng();
     let index = rng.gen_range(0..set.len());
 
     let mut cur = 0;
-    for i in set {
+    for i in set.cells() {
         if cur == index {
-            return i.clone();
+            return i;
         }
         cur = cur + 1;
     }
@@ -171,6 +259,25 @@ fn get_random(set: &HashSet<(i32, i32, i32)>) -> (i32, i32, i32) {
     panic!()
 }
 
+// O(1)
+/// Metropolis acceptance probability (1-p)^Δperimeter of a move that takes the perimeter from
+/// x_perimeter to y_perimeter, capped at 1
+pub(crate) fn perimeter_probability(x_perimeter: usize, y_perimeter: usize, p: f64) -> f64 {
+    let probability = perimeter_ratio(x_perimeter, y_perimeter, p);
+    if probability > 1.0 {
+        return 1.0;
+    }
+    return probability;
+}
+
+// O(1)
+/// Ratio (1-p)^Δperimeter of the Bernoulli weights after and before a move, uncapped so that it
+/// can be combined with other weights
+pub(crate) fn perimeter_ratio(x_perimeter: usize, y_perimeter: usize, p: f64) -> f64 {
+    let perimeter = (y_perimeter as i32) - (x_perimeter as i32);
+    ((1.0 as f64)-p).powf(perimeter as f64)
+}
+
 /*
 // O(1)
 /// Used in DCUT algorithm, since we're looking for elements of ~P that preclude a strong
@@ -185,7 +292,7 @@ fn get_vacant_neighbors_dcut(set: &HashSet<(i32, i32, i32)>, block: &(i32, i32,
 }
 */
 
-impl Polycube {
+impl<S: CellStorage> Polycube<S> {
 
     // O(n^2) 
     // worst case:
@@ -196,7 +303,7 @@ impl Polycube {
     /// Naive known-correct approach (trivial to prove correctness for yourself) for checking validity. Basically a BFS
     fn _naive(&self) -> bool {
         let mut strongly_connected = HashSet::<(i32, i32, i32)>::new();
-        let mut working_poly = self.complex.clone();
+        let mut working_poly: HashSet<(i32, i32, i32)> = self.complex.cells().collect();
 
 
         while working_poly.len() > 0 {
@@ -258,9 +365,10 @@ impl Polycube {
     /// Stack + DFS
     fn dfs(&self) -> bool {
         let mut needs_neighbors_added = Vec::<(i32, i32, i32)>::new();
-        let mut visited = HashSet::<(i32, i32, i32)>::new();
+        // same storage as the complex, so this is a bitset when the complex is a BitGrid
+        let mut visited = self.complex.empty_like();
 
-        let first = match self.complex.iter().next() {
+        let first = match self.complex.cells().next() {
             Some(first) => first,
             None => {
                 // an empty polyform is a strongly connected polyform
@@ -268,8 +376,8 @@ impl Polycube {
             }
         };
 
-        needs_neighbors_added.push(first.clone());
-        visited.insert(first.clone());
+        needs_neighbors_added.push(first);
+        visited.insert(first);
 
         while let Some(center) = needs_neighbors_added.pop() {
             // add all neighbors to the set and, if they haven't already been visited, to the stack
@@ -354,12 +462,24 @@ impl Polycube {
         self.insertable_locations.remove(&block);
 
         // update the insertable_locations by inserting all empty locations strongly connected to
-        // this inserted piece
+        // this inserted piece, as long as they're inside the region we're confined to
         for neighbor in get_vacant_neighbors(&self.complex, &block) {
-            self.insertable_locations.insert(neighbor);
+            if self.in_region(&neighbor) {
+                self.insertable_locations.insert(neighbor);
+            }
         }
 
-        self.complex.insert(block)
+        if let Some(connectivity) = &mut self.connectivity {
+            connectivity.insert(block);
+        }
+
+        let inserted = self.complex.insert(block);
+        if inserted {
+            self.contacts += get_neighbors(&self.complex, &block).len();
+            self.moments.add(&block, 1);
+        }
+
+        inserted
     }
 
 
@@ -384,6 +504,14 @@ impl Polycube {
         }
 
         let removal = self.complex.remove(&piece);
+        if removal {
+            self.contacts -= get_neighbors(&self.complex, piece).len();
+            self.moments.add(piece, -1);
+        }
+
+        if let Some(connectivity) = &mut self.connectivity {
+            connectivity.remove(piece);
+        }
         
         // remove all strongly connected pieces of insertable_locations to the piece about to be
         // removed if those pieces. max|get_neighbors| = 6 so this loop runs in constant time 
@@ -420,27 +548,53 @@ impl Polycube {
     }
 
     fn remove_random(&mut self) -> (i32, i32, i32) {
-        let r = self.get_random();
+        let r = if self.pinned.is_empty() {
+            self.get_random()
+        } else {
+            // O(n), like get_random
+            let index = rng::thread_rng().gen_range(0..self.complex.len() - self.pinned.len());
+            self.complex.cells().filter(|piece| !self.pinned.contains(piece)).nth(index).expect("index is in range")
+        };
         self.remove(&r);
         return r.clone();
     }
 
+    /// Pins a cell of the polyform so that shuffling never moves it. Returns false if the cell
+    /// isn't part of the polyform
+    pub fn pin(&mut self, piece: (i32, i32, i32)) -> bool {
+        if !self.complex.contains(&piece) {
+            return false;
+        }
+        self.pinned.insert(piece);
+        true
+    }
+
+    /// Lets a pinned cell move again. Returns false if it wasn't pinned
+    pub fn unpin(&mut self, piece: &(i32, i32, i32)) -> bool {
+        self.pinned.remove(piece)
+    }
+
+    /// The cells shuffling never moves
+    pub fn pinned(&self) -> &HashSet<(i32, i32, i32)> {
+        &self.pinned
+    }
+
+    pub fn is_pinned(&self, piece: &(i32, i32, i32)) -> bool {
+        self.pinned.contains(piece)
+    }
+
     // O(1)
     pub fn compute_probability(&mut self, x_perimeter: usize, y_perimeter: usize, p: f64) -> f64 {
-        let perimeter = (y_perimeter as i32) - (x_perimeter as i32);
-        let probability = ((1.0 as f64)-p).powf(perimeter as f64); 
-        if probability > 1.0 {
-            return 1.0;
-        }
-        return probability;
+        perimeter_probability(x_perimeter, y_perimeter, p)
     }
 
 
     // O(n)
-    pub fn new(len: usize, dist: Dist) -> Polycube {
+    /// Like `Polycube::new`, but with the cells kept in the given storage backend
+    pub fn with_storage(len: usize, dist: Dist) -> Polycube<S> {
         let mut polyform = Polycube {
-            complex: HashSet::new(),
-            insertable_locations: HashSet::new(), // we could initialize this to be to origin but it doesn't matter
+            complex: S::default(),
+            insertable_locations: CellSet::default(), // we could initialize this to be to origin but it doesn't matter
             min_x: 0,
             max_x: 0,
             min_y: 0,
@@ -448,6 +602,14 @@ impl Polycube {
             min_z: 0,
             max_z: 0,
             dist,
+            connectivity: None,
+            contacts: 0,
+            constraints: Vec::new(),
+            region: None,
+            pinned: HashSet::new(),
+            energy: None,
+            stats: ShuffleStats::default(),
+            moments: Moments::default(),
         };
 
         for i in 0..len {
@@ -457,6 +619,181 @@ impl Polycube {
         polyform
     }
 
+    /// Switches connectivity checks over to the dynamic connectivity backend. Every insert and
+    /// remove then costs amortized O(log^2 n) more, but checking the polyform after a move is O(1)
+    /// instead of a full DFS
+    // O(n log^2 n)
+    pub fn enable_dynamic_connectivity(&mut self) {
+        self.connectivity = Some(DynamicConnectivity::from_cells(self.complex.cells()));
+    }
+
+    /// Checks the polyform for connectedness with whichever backend is enabled
+    pub fn is_connected(&self) -> bool {
+        match &self.connectivity {
+            Some(connectivity) => connectivity.is_connected(),
+            None => self.dfs(),
+        }
+    }
+
+    // O(n)
+    /// The face-connected components of the cells, each as a list of cells
+    pub fn components(&self) -> Vec<Vec<(i32, i32, i32)>> {
+        let mut visited = self.complex.empty_like();
+        let mut components = Vec::new();
+        for start in self.complex.cells() {
+            if !visited.insert(start) {
+                continue;
+            }
+            let mut component = vec![start];
+            let mut stack = vec![start];
+            while let Some(cell) = stack.pop() {
+                for neighbor in get_neighbors(&self.complex, &cell) {
+                    if visited.insert(neighbor) {
+                        component.push(neighbor);
+                        stack.push(neighbor);
+                    }
+                }
+            }
+            components.push(component);
+        }
+        components
+    }
+
+    /// Like `Polycube::with_storage`, but confined to a region. The initial polyform is a path
+    /// winding through the region from its lowest corner instead of a rod, so that it fits.
+    /// Returns None if the region has room for fewer than len cells
+    // O(n)
+    pub fn confined(len: usize, dist: Dist, region: Region) -> Option<Polycube<S>> {
+        let cells = region.initial_cells(len)?;
+
+        let mut polyform = Polycube::with_storage(0, dist);
+        polyform.region = Some(region);
+        for cell in cells {
+            polyform.insert(cell);
+        }
+
+        Some(polyform)
+    }
+
+    // O(n)
+    /// A polyform of the given cells, connected or not, with a tight bounding box
+    pub fn with_cells(cells: impl IntoIterator<Item = (i32, i32, i32)>, dist: Dist) -> Polycube<S> {
+        let mut polyform = Polycube::with_storage(0, dist);
+        polyform.set_cells(cells);
+        polyform.recompute_bounding_box();
+        polyform
+    }
+
+    // O(n)
+    /// Like `Polycube::with_cells`, for cells read from a file. Fails if they aren't face-connected
+    pub fn try_from_cells(cells: impl IntoIterator<Item = (i32, i32, i32)>, dist: Dist) -> Result<Polycube<S>, String> {
+        let polyform = Polycube::<S>::with_cells(cells, dist);
+        if !polyform.is_connected() {
+            return Err(format!("the {} cells aren't connected, there are {} components", polyform.complex.len(), polyform.components().len()));
+        }
+        Ok(polyform)
+    }
+
+    // O(n)
+    /// Replaces the cells of the polyform, rebuilding the site perimeter and every other piece of
+    /// bookkeeping. Pinned cells that aren't among the new cells are unpinned
+    pub fn set_cells(&mut self, cells: impl IntoIterator<Item = (i32, i32, i32)>) {
+        self.complex = S::default();
+        self.insertable_locations.clear();
+        self.contacts = 0;
+        self.moments = Moments::default();
+        (self.min_x, self.max_x, self.min_y, self.max_y, self.min_z, self.max_z) = (0, 0, 0, 0, 0, 0);
+        if self.connectivity.is_some() {
+            self.connectivity = Some(DynamicConnectivity::new());
+        }
+
+        for cell in cells {
+            self.insert(cell);
+        }
+
+        let complex = &self.complex;
+        self.pinned.retain(|piece| complex.contains(piece));
+    }
+
+    // O(1)
+    fn in_region(&self, piece: &(i32, i32, i32)) -> bool {
+        match &self.region {
+            Some(region) => region.contains(piece),
+            None => true,
+        }
+    }
+
+    /// Checks the polyform against every constraint it's restricted to. Assumes it's connected
+    pub fn satisfies_constraints(&self) -> bool {
+        self.constraints.iter().all(|constraint| match constraint {
+            Constraint::Tree => self.is_tree(),
+            Constraint::NoCavities => !self.has_cavities(),
+        })
+    }
+
+    // O(1)
+    /// A connected polyform is a lattice tree exactly when its adjacency graph has n-1 edges
+    pub fn is_tree(&self) -> bool {
+        self.contacts + 1 == self.complex.len()
+    }
+
+    // O(volume of the bounding box)
+    /// Whether the complement of the polyform has an enclosed component, found by flood filling
+    /// the complement from outside the tight bounding box
+    pub fn has_cavities(&self) -> bool {
+        let (min, max) = match self.tight_bounds() {
+            Some(bounds) => bounds,
+            None => return false,
+        };
+
+        // pad by one so that the outside is connected around the polyform
+        let min = (min.0 - 1, min.1 - 1, min.2 - 1);
+        let max = (max.0 + 1, max.1 + 1, max.2 + 1);
+        let volume = (max.0 - min.0 + 1) as usize * (max.1 - min.1 + 1) as usize * (max.2 - min.2 + 1) as usize;
+
+        self.flood_complement(min, min, max).len() != volume - self.complex.len()
+    }
+
+    /// Flood fills the vacant cells reachable from start without leaving the box between min and
+    /// max
+    fn flood_complement(&self, start: (i32, i32, i32), min: (i32, i32, i32), max: (i32, i32, i32)) -> HashSet<(i32, i32, i32)> {
+        let in_box = |c: &(i32, i32, i32)| {
+            c.0 >= min.0 && c.0 <= max.0 && c.1 >= min.1 && c.1 <= max.1 && c.2 >= min.2 && c.2 <= max.2
+        };
+
+        let mut visited = HashSet::new();
+        let mut stack = vec![start];
+        visited.insert(start);
+
+        while let Some(center) = stack.pop() {
+            for neighbor in get_vacant_neighbors(&self.complex, &center) {
+                if in_box(&neighbor) && visited.insert(neighbor) {
+                    stack.push(neighbor);
+                }
+            }
+        }
+
+        visited
+    }
+
+    // O(n)
+    /// The tight bounding box as (min, max) corners, without touching the tracked (loose) one
+    pub fn tight_bounds(&self) -> Option<Bounds> {
+        let mut cells = self.complex.cells();
+        let first = cells.next()?;
+
+        Some(cells.fold((first, first), |(min, max), c| {
+            ((min.0.min(c.0), min.1.min(c.1), min.2.min(c.2)), (max.0.max(c.0), max.1.max(c.1), max.2.max(c.2)))
+        }))
+    }
+
+    /// Recounts the pairs of cells that share a face from scratch
+    // O(n)
+    pub fn compute_contacts(&mut self) {
+        let twice: usize = self.complex.cells().map(|piece| get_neighbors(&self.complex, &piece).len()).sum();
+        self.contacts = twice / 2;
+    }
+
     // computes a tight bounding box in O(n)
     fn recompute_bounding_box(&mut self) {
         self.min_x = i32::MAX;
@@ -465,7 +802,7 @@ impl Polycube {
         self.max_x = i32::MIN;
         self.max_y = i32::MIN;
         self.max_z = i32::MIN;
-        for piece in &self.complex {
+        for piece in self.complex.cells() {
             if piece.0 < self.min_x {
                 self.min_x = piece.0;
             }
@@ -488,12 +825,20 @@ impl Polycube {
     }
 
     // this function is strongly based on the eaxmple in kiss3d's readme. Deprecated, use
-    pub fn render(self) {
+    pub fn render(self) where S: 'static {
         self.render_shuffle(0, Some(0))
     }
 
     // this function is strongly based on the eaxmple in kiss3d's readme
-    pub fn render_shuffle(self, shuffles_per_render: usize, stop_after: Option<usize>)  {
+    pub fn render_shuffle(self, shuffles_per_render: usize, stop_after: Option<usize>) where S: 'static {
+        self.render_shuffle_as(shuffles_per_render, stop_after, Some(cli::Export::Tuples), &npy::Grid::default())
+    }
+
+    /// Renders while shuffling, and prints the polyform in the given format once stop_after
+    /// shuffles are done. In the window, space pauses and resumes, n steps one move, and s, t, a
+    /// and m save the polyform as SCAD, tuples, analysis or STL to a timestamped file. NumPy
+    /// exports are laid out on the grid
+    pub fn render_shuffle_as(self, shuffles_per_render: usize, stop_after: Option<usize>, export: Option<cli::Export>, grid: &npy::Grid) where S: 'static {
         let mut window = Window::new("Polyform");
         window.set_background_color(0.0, 0.0, 0.0);
 
@@ -514,86 +859,139 @@ impl Polycube {
         //let ids = Ids::new(window.conrod_ui_mut().widget_id_generator());
 
 
+        #[cfg(not(target_arch = "wasm32"))]
+        eprintln!("Space pauses and resumes, n steps one move, s/t/a/m saves as SCAD/tuples/analysis/STL");
+
         let rs = RenderState {
             shuffles_per_render,
             stop_after,
+            export,
+            grid: grid.clone(),
             pfm: self,
             group: None,
             camera: arcball,
             total_shuffles: 0,
-            exported: false
+            exported: false,
+            paused: false,
         };
 
         window.render_loop(rs)
     }
     
 
-    pub fn shuffle(&mut self, times: usize) -> Option<((i32, i32, i32), (i32, i32, i32))> {
-
+    pub fn shuffle(&mut self, times: usize) -> Option<Shuffled> {
         let mut last_shuffled = None;
         for _i in 0..times {
-            let len: usize = self.complex.len();
-            let len_old = self.insertable_locations.len(); 
-
-            let removed = self.remove_random();
-            //println!("removed {:?}", removed);
-            let inserted = self.insert_random();
-            //println!("inserted {:?}", inserted);
-            if self.complex.len() != len {
-                println!("detected decrease in polyform size");
+            if let Some(moved) = self.step(None) {
+                last_shuffled = Some(moved);
             }
+        }
+        last_shuffled
+    }
 
-            /*if i%5000 == 0 {
-                println!("times: {} sa: {}", i, self.insertable_locations.len());
-            }*/
+    /// Proposes one move of `shuffle` and returns it, as (inserted, removed), if it was accepted.
+    /// `bias` multiplies the ratio of the weights of the new and old states, so that a caller can
+    /// weight the chain by more than the polyform's own weights with a single Metropolis test. It's
+    /// given the polyform after the move
+    pub(crate) fn step(&mut self, mut bias: Option<&mut dyn FnMut(&Self) -> f64>) -> Option<Shuffled> {
+        // with every cell pinned there's nothing to remove
+        if self.pinned.len() >= self.complex.len() {
+            return None;
+        }
 
-            let did_shuffle = match self.dist{
-                Dist::Bernoulli(probability) => {
-                    
-                    // bernoulli coin flip
-                    //println!("current probability (should be fixed): {}", probability);
+        self.stats.proposed += 1;
+        let len: usize = self.complex.len();
+        let len_old = self.insertable_locations.len(); 
+        let contacts_old = self.contacts;
+
+        let removed = self.remove_random();
+        //println!("removed {:?}", removed);
+        let inserted = self.insert_random();
+        //println!("inserted {:?}", inserted);
+        if self.complex.len() != len {
+            println!("detected decrease in polyform size");
+        }
 
-                    //compute probability based on site perimeter
-                    let computed_probability = self.compute_probability(len_old, self.insertable_locations.len(), probability);
+        /*if i%5000 == 0 {
+            println!("times: {} sa: {}", i, self.insertable_locations.len());
+        }*/
 
-                    // sample from distribution
-                    let dist = Bernoulli::new(computed_probability).unwrap();
+        // ratio of the weights of the new and old states, as in Metropolis-Hastings
+        let mut ratio = match self.dist {
+            //compute probability based on site perimeter
+            Dist::Bernoulli(probability) => perimeter_ratio(len_old, self.insertable_locations.len(), probability),
+            Dist::Uniform => 1.0
+        };
 
-                    let sample = dist.sample(&mut rand::thread_rng());
+        // surface contacts with the walls of the region we're confined to
+        if let Some(region) = &self.region {
+            ratio *= region.weight_ratio(&removed, &inserted);
+        }
 
-                    // get a number on some interval
-                    // check which part of the interval it's in
+        if let Some(energy) = &self.energy {
+            let complex = &self.complex;
+            let delta = energy.delta(&Move {
+                removed,
+                inserted,
+                perimeter: (len_old, self.insertable_locations.len()),
+                contacts: (contacts_old, self.contacts),
+                occupied: &|piece| complex.contains(piece),
+            });
+            ratio *= (-delta).exp();
+        }
 
-                    if !sample {
-                        // Reverse operation if reject mode
-                        // println!("Reversing operation");
-                        self.remove(&inserted);
-                        self.insert(removed);
+        if let Some(bias) = bias.as_mut() {
+            ratio *= bias(self);
+        }
 
-                        false
-                    } else {
-                        // println!("Maintainig operation");
-                        // Do nothing, let the 
-                        true
-                    }
-                }
-                Dist::Uniform => true 
+        let did_shuffle = if ratio < 1.0 {
+            // bernoulli coin flip
+
+            // sample from distribution
+            let dist = Bernoulli::new(ratio).unwrap();
+
+            let sample = dist.sample(&mut rng::thread_rng());
+
+            // get a number on some interval
+            // check which part of the interval it's in
+
+            if !sample {
+                // Reverse operation if reject mode
+                // println!("Reversing operation");
+                self.remove(&inserted);
+                self.insert(removed);
+                self.stats.rejected_by_weight += 1;
+
+                false
+            } else {
+                // println!("Maintainig operation");
+                // Do nothing, let the 
+                true
+            }
+        } else {
+            true
+        };
+
+        // the polyform was shuffled, so we should check that it's still connected
+        if did_shuffle {
+            // adsorbed polyforms must also stay on the wall
+            let attached = match &self.region {
+                Some(region) => region.is_attached(self.complex.cells()),
+                None => true,
             };
 
-            // the polyform was shuffled, so we should check that it's still connected
-            if did_shuffle {
-                if !self.dfs() { //can use self.naive or self.semi_naive here instead
-                    // not strongly connected, so reverse operation
-                    //println!("Reversing operation");
-                    self.remove(&inserted);
-                    self.insert(removed);
-                } else {
-                    // update the length
-                    last_shuffled = Some((inserted, removed));
-                }
+            if !attached || !self.is_connected() || !self.satisfies_constraints() { //can use self.naive or self.semi_naive here instead
+                // not strongly connected, so reverse operation
+                //println!("Reversing operation");
+                self.remove(&inserted);
+                self.insert(removed);
+                self.stats.rejected_as_invalid += 1;
+            } else {
+                self.stats.accepted += 1;
+                return Some((inserted, removed));
             }
         }
-        last_shuffled
+        None
     }
 
     pub fn export_scad(&mut self) -> String {
@@ -601,20 +999,26 @@ impl Polycube {
         self.recompute_bounding_box();
 
 
-        for piece in &self.complex {
-            let centered = self.center(piece);
+        for piece in self.complex.cells() {
+            let centered = self.center(&piece);
             scad.push_str(&format!("translate([{}, {}, {}]) cube([1.01, 1.01, 1.01]);\n", centered.0, centered.1, centered.2));
         }
 
         scad
     }
 
+    /// Exports the cells as a list of (x, y, z) tuples. If any cells are pinned, every tuple gets a
+    /// fourth element that is 1 for pinned cells and 0 otherwise
     pub fn export(&self) -> String {
         let mut export = String::from("[");
 
         // don't center in order to prevent floating point problems
-        for piece in &self.complex {
-            export.push_str(&format!("({}, {}, {}), ", piece.0, piece.1, piece.2));
+        for piece in self.complex.cells() {
+            if self.pinned.is_empty() {
+                export.push_str(&format!("({}, {}, {}), ", piece.0, piece.1, piece.2));
+            } else {
+                export.push_str(&format!("({}, {}, {}, {}), ", piece.0, piece.1, piece.2, self.pinned.contains(&piece) as u8));
+            }
         }
 
         export.push_str("]");
@@ -622,108 +1026,36 @@ impl Polycube {
         export
     }
 
+    /// Exports the cells in Perseus' sparse cubical format. If any cells are pinned, they're born
+    /// at time 1 and the rest at time 2, so Perseus reports the homology of the pinned cells on
+    /// their own as well as of the whole polyform
     pub fn export_analysis(&self) -> String {
         let mut export = String::from("3");
 
         // don't center in order to prevent floating point problems
-        for piece in &self.complex {
-            export.push_str(&format!("\n{} {} {} 1", piece.0, piece.1, piece.2));
+        for piece in self.complex.cells() {
+            let birth = if self.pinned.is_empty() || self.pinned.contains(&piece) { 1 } else { 2 };
+            export.push_str(&format!("\n{} {} {} {}", piece.0, piece.1, piece.2, birth));
         }
 
         export
     }
 
-    fn import_i32(line: &str) -> IResult<&str, i32> {
-        // read the token
-        let (input, iso_int) = take_while(|c| is_digit(c as u8) || c == '-')(line)?;
-
-        // eat the delimeter
-        let (input, _) = take_while(|c| c == ' ')(input)?;
-
-
-        let i = i32::from_str_radix(iso_int, 10).expect("Parsed integer");
-
-
-        Ok((input, i))
-    }
-
-    fn import_analysis_line(input: &str) -> IResult<&str, (i32, i32, i32)> {
-        let (input, line) = take_while(|c| c != '\n')(input)?;
-
-        let (_, tp) = tuple((Self::import_i32, Self::import_i32, Self::import_i32))(line)?;
-
-        // eat the delimeter
-        let (input, _) = take_while(|c| c == '\n')(input)?;
-
-        Ok((input, tp))
-    }
-
     /// DO NOT use this function unless you need to compute the insertable_locations site perimeter
     /// from scratch. You should aim to make incremental O(1) changes where possible
     pub fn compute_insertable(&mut self) {
         // loop through self and add neighbors to the site perimeter set
-        let mut site_perimeter = HashSet::<(i32, i32, i32)>::new();
+        let mut site_perimeter = CellSet::default();
 
-        for piece in &self.complex {
+        for piece in self.complex.cells() {
             for neighbor in get_vacant_neighbors(&self.complex, &piece) {
-                site_perimeter.insert(neighbor.clone());
-            }
-        }
-
-        self.insertable_locations = site_perimeter;
-    }
-
-    pub fn import_analysis(analysis: &str) -> IResult<&str, Polycube> {
-        let mut pfm = Polycube { complex: HashSet::<(i32, i32, i32)>::new(), min_x: i32::MAX, max_x: i32::MIN, min_y: i32::MAX, max_y: i32::MIN, min_z: i32::MAX, max_z: i32::MIN, insertable_locations: HashSet::<(i32, i32, i32)>::new(), dist: Dist::Uniform };
-
-        // read in the dimension
-        let (mut input, _) = tag("3\n")(analysis)?;
-
-        // read each line in the analysis and construct the complex
-        loop {
-            if input == "" {
-                break;
-            }
-
-            input = match Self::import_analysis_line(input) {
-                Ok((input, block)) => {
-                    if block.0 < pfm.min_x {
-                        pfm.min_x = block.0;
-                    }
-
-                    if block.1 < pfm.min_y {
-                        pfm.min_y = block.1;
-                    }
-
-                    if block.2 < pfm.min_z {
-                        pfm.min_z = block.2;
-                    }
-
-                    if block.0 > pfm.max_x {
-                        pfm.max_x = block.0;
-                    }
-
-                    if block.1 > pfm.max_y {
-                        pfm.max_y = block.1;
-                    }
-                    
-                    if block.2 > pfm.max_z {
-                        pfm.max_y = block.2;
-                    }
-
-                    pfm.complex.insert(block);
-                    input
-                },
-                IResult::Err(e) => {
-                    eprintln!("Breaking with error {}", e);
-                    break;
+                if self.in_region(&neighbor) {
+                    site_perimeter.insert(neighbor.clone());
                 }
             }
         }
 
-        pfm.compute_insertable();
-
-        Ok((input, pfm))
+        self.insertable_locations = site_perimeter;
     }
 
     pub fn center(&self, piece: &(i32, i32, i32)) -> (f32, f32, f32) {
@@ -732,17 +1064,49 @@ impl Polycube {
     }
 }
 
-struct RenderState {
+impl Polycube {
+    // O(n)
+    pub fn new(len: usize, dist: Dist) -> Polycube {
+        Polycube::with_storage(len, dist)
+    }
+}
+
+struct RenderState<S: CellStorage> {
     shuffles_per_render: usize,
     stop_after: Option<usize>,
-    pfm: Polycube,
+    /// printed once stop_after is reached
+    export: Option<cli::Export>,
+    grid: npy::Grid,
+    pfm: Polycube<S>,
     group: Option<SceneNode>,
     camera: ArcBall,
     total_shuffles: usize,
-    exported: bool
+    exported: bool,
+    paused: bool,
 }
 
-impl State for RenderState {
+// how many shuffles stepping tries before giving up on finding a move that is accepted
+const STEP_ATTEMPTS: usize = 10_000;
+
+impl<S: CellStorage> RenderState<S> {
+    /// Writes the polyform to polycube-<unix time in ms>.<extension> in the working directory
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save(&mut self, export: cli::Export) {
+        let millis = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let path = format!("polycube-{}.{}", millis, export.extension());
+        match cli::export(&mut self.pfm, &export, &self.grid).and_then(|bytes| std::fs::write(&path, bytes).map_err(|e| e.to_string())) {
+            Ok(()) => eprintln!("Saved {}", path),
+            Err(e) => eprintln!("Couldn't save {}: {}", path, e),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn save(&mut self, _export: cli::Export) {}
+}
+
+impl<S: CellStorage + 'static> State for RenderState<S> {
     fn cameras_and_effect_and_renderer(
             &mut self,
         ) -> (
@@ -756,11 +1120,30 @@ impl State for RenderState {
     }
 
     fn step(&mut self, window: &mut Window) {
+
+        let mut step_once = false;
+        for event in window.events().iter() {
+            if let WindowEvent::Key(key, Action::Press, _) = event.value {
+                match key {
+                    Key::Space => self.paused = !self.paused,
+                    Key::N => step_once = true,
+                    Key::S => self.save(cli::Export::Scad),
+                    Key::T => self.save(cli::Export::Tuples),
+                    Key::A => self.save(cli::Export::Analysis),
+                    Key::M => self.save(cli::Export::Stl),
+                    _ => ()
+                }
+            }
+        }
         
         match self.stop_after {
             Some(stop_after) if stop_after <= self.total_shuffles => {
                 if !self.exported {
-                    println!("{}", self.pfm.export());
+                    if let Some(export) = &self.export {
+                        if let Err(e) = cli::print(&mut self.pfm, export, &self.grid) {
+                            eprintln!("{}", e);
+                        }
+                    }
                     self.exported = true;
                 }
                 //window.close();
@@ -775,9 +1158,23 @@ impl State for RenderState {
             self.camera.set_pitch(3f32*((window.height() as f32)-(mouse_y as f32))/(window.height() as f32));
         }
 
-        let last_shuffled = self.pfm.shuffle(self.shuffles_per_render);
+        let last_shuffled = if step_once {
+            // keep shuffling until a move is accepted, so that stepping always shows one
+            let mut attempts = 0;
+            let mut last_shuffled = None;
+            while last_shuffled.is_none() && attempts < STEP_ATTEMPTS {
+                last_shuffled = self.pfm.shuffle(1);
+                attempts += 1;
+            }
+            self.total_shuffles += attempts;
+            last_shuffled
+        } else if self.paused {
+            return;
+        } else {
+            self.total_shuffles = self.total_shuffles + self.shuffles_per_render;
+            self.pfm.shuffle(self.shuffles_per_render)
+        };
 
-        self.total_shuffles = self.total_shuffles + self.shuffles_per_render;
         eprintln!("Completed {} shuffles live", self.total_shuffles);
 
         let mut oldgroup = None;
@@ -790,17 +1187,21 @@ impl State for RenderState {
         self.pfm.recompute_bounding_box();
 
         // in the future we can combine neighboring pieces for faster rendering
-        for piece in &self.pfm.complex {
+        for piece in self.pfm.complex.cells() {
             let mut c = group.add_cube(1.0, 1.0, 1.0);
             c.set_color(0.2 +random::<f32>()*0.4, 0.3+ random::<f32>()*0.4, 0.9);
 
+            if self.pfm.is_pinned(&piece) {
+                c.set_color(1.0, 0.6, 0.0);
+            }
+
             if let Some(last_shuffled) = last_shuffled {
                 if last_shuffled.0.0 == piece.0 && last_shuffled.0.1 ==piece.1 && last_shuffled.0.2 == piece.2 {
                     c.set_color(0.0, 1.0, 0.0);
                 }
             }
 
-            let centered = self.pfm.center(piece);
+            let centered = self.pfm.center(&piece);
 
             // because we don't maintain strict bounds, this isn't a perfect translation. We could
             // recompute strict bounds
@@ -831,3 +1232,64 @@ pub fn our_main() -> Result<(), JsValue> {
     pfm.render_shuffle(10, None);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hollow_cube;
+
+    fn contacts(pfm: &Polycube) -> usize {
+        pfm.complex.iter().map(|cell| get_neighbors(&pfm.complex, cell).len()).sum::<usize>() / 2
+    }
+
+    #[test]
+    fn constrained_shuffles_stay_in_their_class() {
+        rng::seed(29);
+        let mut tree = Polycube::new(40, Dist::Bernoulli(0.9));
+        tree.constraints = vec![Constraint::Tree];
+        let mut solid = Polycube::new(40, Dist::Bernoulli(0.9));
+        solid.constraints = vec![Constraint::NoCavities];
+        for _ in 0..50 {
+            tree.shuffle(100);
+            solid.shuffle(100);
+            assert!(tree.is_connected() && tree.is_tree());
+            assert_eq!(contacts(&tree) + 1, 40);
+            assert!(solid.is_connected() && !solid.has_cavities());
+        }
+    }
+
+    #[test]
+    fn a_hollow_cube_has_a_cavity_and_cycles() {
+        let mut cube = hollow_cube();
+        assert!(cube.has_cavities() && !cube.is_tree());
+        assert!(cube.satisfies_constraints());
+        cube.constraints = vec![Constraint::NoCavities];
+        assert!(!cube.satisfies_constraints());
+
+        let line = Polycube::new(5, Dist::Uniform);
+        assert!(!line.has_cavities() && line.is_tree());
+    }
+
+    #[test]
+    fn pinned_cells_never_move() {
+        rng::seed(31);
+        let mut pfm = Polycube::new(20, Dist::Bernoulli(0.5));
+        // the ends of the initial line along z
+        let ends = [(0, 0, 0), (0, 0, 19)];
+        for end in ends {
+            assert!(pfm.pin(end));
+        }
+        assert!(!pfm.pin((100, 100, 100)));
+
+        for _ in 0..50 {
+            let moved = pfm.shuffle(40);
+            assert!(ends.iter().all(|end| pfm.complex.contains(end) && pfm.is_pinned(end)));
+            assert!(moved.is_none_or(|(_, removed)| !ends.contains(&removed)));
+        }
+
+        assert!(pfm.unpin(&ends[0]) && !pfm.unpin(&ends[0]));
+        let mut single = Polycube::new(1, Dist::Uniform);
+        single.pin((0, 0, 0));
+        assert_eq!(single.step(None), None);
+    }
+}
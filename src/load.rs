@@ -0,0 +1,241 @@
+//! Reading polycubes back from the formats they're exported in, picking the format from the file.
+//!
+//! Binary formats are recognized by their magic bytes, `.vox` by `VOX `, `.binvox` by `#binvox`
+//! and sample containers by `PFMS`. Text starting with `[` is the tuple format of
+//! `Polycube::export`, text starting with `{` is JSON or NDJSON, and any other text is read as
+//! Perseus' cubical format, which `export_analysis` writes. Containers and NDJSON can hold many
+//! polycubes, which `load_all` reads. JSON and containers also record how each polycube was
+//! sampled, which `load_provenance` keeps.
+
+use std::fs;
+use std::path::Path;
+
+use crate::container;
+use crate::storage::CellStorage;
+use crate::{Dist, Polycube};
+
+type Cell = (i32, i32, i32);
+
+/// How a polycube was sampled, as far as the file it was read from records it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Provenance {
+    pub dist: Option<Dist>,
+    pub seed: Option<u64>,
+    /// Shuffles, or moves of the n-fold way
+    pub steps: Option<u64>,
+}
+
+impl From<&container::Metadata> for Provenance {
+    fn from(metadata: &container::Metadata) -> Provenance {
+        Provenance { dist: Some(metadata.dist.clone()), seed: metadata.seed, steps: Some(metadata.steps) }
+    }
+}
+
+/// Formats `Polycube::load` can read
+#[derive(Clone, Debug, PartialEq)]
+pub enum Format {
+    Tuples,
+    Analysis,
+    Vox,
+    Binvox,
+    Json,
+    Container,
+}
+
+impl Format {
+    /// Guesses the format of a file from its contents
+    pub fn detect(bytes: &[u8]) -> Format {
+        if bytes.starts_with(b"VOX ") {
+            return Format::Vox;
+        }
+        if bytes.starts_with(b"#binvox") {
+            return Format::Binvox;
+        }
+        if bytes.starts_with(container::MAGIC) {
+            return Format::Container;
+        }
+        match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'[') => Format::Tuples,
+            Some(b'{') => Format::Json,
+            _ => Format::Analysis,
+        }
+    }
+}
+
+/// Parses the list of 3 or 4 element tuples `Polycube::export` writes, where a fourth element of
+/// 1 marks a pinned cell. Errors give the character they're at, counting from 1
+fn parse_tuples(tuples: &str) -> Result<Vec<(Cell, bool)>, String> {
+    let at = |rest: &str| tuples.len() - rest.len() + 1;
+
+    let mut rest = tuples.trim_start().strip_prefix('[').ok_or("expected [ at the start")?;
+    let mut cells = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(']') {
+            if !after.trim().is_empty() {
+                return Err(format!("unexpected {:?} after ] at character {}", after.trim(), at(after.trim_start())));
+            }
+            return Ok(cells);
+        }
+
+        let inner = rest.strip_prefix('(').ok_or_else(|| format!("expected ( or ] at character {}", at(rest)))?;
+        let close = inner.find(')').ok_or_else(|| format!("unclosed ( at character {}", at(rest)))?;
+        let elements = inner[..close]
+            .split(',')
+            .map(|element| element.trim().parse::<i32>().map_err(|_| format!("expected an integer, found {:?} in the tuple at character {}", element.trim(), at(rest))))
+            .collect::<Result<Vec<i32>, String>>()?;
+        match elements.as_slice() {
+            [x, y, z] => cells.push(((*x, *y, *z), false)),
+            [x, y, z, pinned] => cells.push(((*x, *y, *z), *pinned != 0)),
+            _ => return Err(format!("expected 3 or 4 elements, found {} in the tuple at character {}", elements.len(), at(rest))),
+        }
+
+        // tuples are separated by commas, and export leaves one after the last
+        rest = inner[close + 1..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+    }
+}
+
+impl<S: CellStorage> Polycube<S> {
+    /// Imports the tuple format of `Polycube::export`, pinning the cells it marks as pinned.
+    /// Fails if the cells aren't connected
+    pub fn import_tuples(tuples: &str) -> Result<Polycube<S>, String> {
+        let cells = parse_tuples(tuples)?;
+        let mut pfm = Polycube::try_from_cells(cells.iter().map(|(cell, _)| *cell), Dist::Uniform)?;
+        for (cell, pinned) in cells {
+            if pinned {
+                pfm.pin(cell);
+            }
+        }
+        Ok(pfm)
+    }
+
+    /// Reads the polycube in a file in any format it can be imported from, detecting the format
+    /// from the contents. Fails if the file holds more than one
+    pub fn load(path: &Path) -> Result<Polycube<S>, String> {
+        let mut polycubes = Polycube::load_all(path)?;
+        match polycubes.len() {
+            1 => Ok(polycubes.remove(0)),
+            count => Err(format!("{} holds {} polycubes instead of one", path.display(), count)),
+        }
+    }
+
+    /// Reads every polycube in a file in any format they can be imported from, in order
+    pub fn load_all(path: &Path) -> Result<Vec<Polycube<S>>, String> {
+        Ok(Polycube::load_provenance(path)?.into_iter().map(|(pfm, _)| pfm).collect())
+    }
+
+    /// Reads every polycube in a file like `load_all`, along with how it was sampled
+    pub fn load_provenance(path: &Path) -> Result<Vec<(Polycube<S>, Provenance)>, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let text = || std::str::from_utf8(&bytes).map_err(|e| format!("{} isn't text: {}", path.display(), e));
+
+        // the other formats don't record how the polycube was sampled
+        let unknown = |pfm| vec![(pfm, Provenance::default())];
+        let polycubes = match Format::detect(&bytes) {
+            Format::Vox => Polycube::import_vox(&bytes).map(unknown),
+            Format::Binvox => Polycube::import_binvox(&bytes).map(unknown),
+            Format::Tuples => Polycube::import_tuples(text()?).map(unknown),
+            Format::Analysis => Polycube::import_analysis(text()?).map(unknown).map_err(|e| e.to_string()),
+            Format::Json => Polycube::parse_json(text()?),
+            Format::Container => container::Reader::new(bytes.as_slice())
+                .map_err(|e| e.to_string())?
+                .map(|sample| {
+                    let sample = sample.map_err(|e| e.to_string())?;
+                    Ok((sample.polycube()?, Provenance::from(&sample.metadata)))
+                })
+                .collect(),
+        };
+        polycubes.map_err(|e| format!("Couldn't import {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::{Sample, Writer};
+    use crate::fixtures::{shuffled, sorted};
+    use crate::storage::CellSet;
+
+    fn temp_file(name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("polyforms-load-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn detects_every_format() {
+        assert_eq!(Format::detect(b"VOX \x96\0\0\0"), Format::Vox);
+        assert_eq!(Format::detect(b"#binvox 1\n"), Format::Binvox);
+        assert_eq!(Format::detect(b"PFMS\x01\0"), Format::Container);
+        assert_eq!(Format::detect(b"  [(0, 0, 0), ]"), Format::Tuples);
+        assert_eq!(Format::detect(b"\n{\"cells\": []}"), Format::Json);
+        assert_eq!(Format::detect(b"3\n0 0 0 1\n"), Format::Analysis);
+    }
+
+    #[test]
+    fn tuples_round_trip() {
+        let mut pfm = shuffled(44);
+        let pinned = pfm.complex.iter().next().copied().unwrap();
+        pfm.pin(pinned);
+        let imported: Polycube = Polycube::import_tuples(&pfm.export()).unwrap();
+        assert_eq!(sorted(&imported), sorted(&pfm));
+        assert_eq!(imported.pinned(), pfm.pinned());
+
+        assert!(Polycube::<CellSet>::import_tuples("[(0, 0, 0), (2, 0, 0), ]").is_err());
+        assert!(Polycube::<CellSet>::import_tuples("[(0, 0), ]").is_err());
+    }
+
+    #[test]
+    fn loads_json_and_every_line_of_ndjson() {
+        let (a, b) = (shuffled(1), shuffled(2));
+        let json = temp_file("a.json", a.export_json(true).as_bytes());
+        let ndjson = temp_file("ab.ndjson", format!("{}\n{}\n", a.export_ndjson(false), b.export_ndjson(false)).as_bytes());
+
+        let loaded: Polycube = Polycube::load(&json).unwrap();
+        assert_eq!(sorted(&loaded), sorted(&a));
+        assert!(matches!(loaded.dist, Dist::Bernoulli(p) if p == 0.5));
+
+        let all: Vec<Polycube> = Polycube::load_all(&ndjson).unwrap();
+        assert_eq!(all.iter().map(sorted).collect::<Vec<_>>(), vec![sorted(&a), sorted(&b)]);
+        assert!(Polycube::<CellSet>::load(&ndjson).is_err());
+
+        fs::remove_file(json).unwrap();
+        fs::remove_file(ndjson).unwrap();
+    }
+
+    #[test]
+    fn loads_every_sample_of_a_container() {
+        let (a, b) = (shuffled(3), shuffled(4));
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer.write(&Sample::of_polycube(&a, Some(3), 2000)).unwrap();
+        writer.write(&Sample::of_polycube(&b, Some(4), 2000)).unwrap();
+        let path = temp_file("ab.pfms", &writer.finish().unwrap());
+
+        let all: Vec<Polycube> = Polycube::load_all(&path).unwrap();
+        assert_eq!(all.iter().map(sorted).collect::<Vec<_>>(), vec![sorted(&a), sorted(&b)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keeps_how_samples_were_produced() {
+        let pfm = shuffled(5);
+        let json = temp_file("provenance.json", pfm.export_json(false).as_bytes());
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer.write(&Sample::of_polycube(&pfm, Some(5), 2000)).unwrap();
+        let container = temp_file("provenance.pfms", &writer.finish().unwrap());
+        let tuples = temp_file("provenance.txt", pfm.export().as_bytes());
+
+        let expected = Provenance { dist: Some(Dist::Bernoulli(0.5)), seed: Some(5), steps: Some(2000) };
+        for path in [&json, &container] {
+            let loaded: Vec<(Polycube, Provenance)> = Polycube::load_provenance(path).unwrap();
+            assert_eq!(loaded[0].1, expected);
+        }
+        let loaded: Vec<(Polycube, Provenance)> = Polycube::load_provenance(&tuples).unwrap();
+        assert_eq!(loaded[0].1, Provenance::default());
+
+        for path in [json, container, tuples] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! Triangle meshes of the surface of a polycube, for slicers, Blender and other modelling tools.
+//!
+//! Faces shared by two cells are inside the polycube, so only the faces between a cell and an
+//! empty neighbor are part of the surface. Coplanar faces are then merged into rectangles by
+//! greedy meshing: in every plane, each rectangle grows as far as it can along one axis and then
+//! along the other. A corner of one rectangle can end up in the middle of an edge of its
+//! neighbor, so every rectangle also passes through the corners of other rectangles on its
+//! boundary, which keeps the mesh watertight. Everything is wound counter-clockwise when seen from
+//! outside.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::storage::CellStorage;
+use crate::Polycube;
+
+type Point = (i32, i32, i32);
+pub type Vertex = (f32, f32, f32);
+
+/// The face of a cell on one of its six sides
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Face {
+    pub cell: (i32, i32, i32),
+    /// 0, 1 or 2 for x, y or z
+    pub axis: usize,
+    /// Whether the face is on the positive side of the cell along the axis
+    pub positive: bool,
+}
+
+/// A rectangle of merged faces in the plane where the axis coordinate is level. min and max are
+/// its corners along the other two axes, in cyclic order after the axis
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Rectangle {
+    pub axis: usize,
+    pub positive: bool,
+    pub level: i32,
+    pub min: (i32, i32),
+    pub max: (i32, i32),
+}
+
+fn coordinate(cell: &Point, axis: usize) -> i32 {
+    match axis {
+        0 => cell.0,
+        1 => cell.1,
+        _ => cell.2,
+    }
+}
+
+fn with_coordinate(cell: Point, axis: usize, value: i32) -> Point {
+    match axis {
+        0 => (value, cell.1, cell.2),
+        1 => (cell.0, value, cell.2),
+        _ => (cell.0, cell.1, value),
+    }
+}
+
+// the other two axes, in cyclic order so that u × v points along the axis
+fn plane_axes(axis: usize) -> (usize, usize) {
+    ((axis + 1) % 3, (axis + 2) % 3)
+}
+
+fn normal(axis: usize, positive: bool) -> Point {
+    with_coordinate((0, 0, 0), axis, if positive { 1 } else { -1 })
+}
+
+impl Face {
+    /// The outward normal
+    pub fn normal(&self) -> (i32, i32, i32) {
+        normal(self.axis, self.positive)
+    }
+
+    /// The corners of the face, counter-clockwise when seen from outside
+    pub fn corners(&self) -> [(i32, i32, i32); 4] {
+        self.rectangle().corners()
+    }
+
+    fn rectangle(&self) -> Rectangle {
+        let (u, v) = plane_axes(self.axis);
+        let position = (coordinate(&self.cell, u), coordinate(&self.cell, v));
+        Rectangle {
+            axis: self.axis,
+            positive: self.positive,
+            level: coordinate(&self.cell, self.axis) + self.positive as i32,
+            min: position,
+            max: (position.0 + 1, position.1 + 1),
+        }
+    }
+}
+
+impl Rectangle {
+    /// The outward normal
+    pub fn normal(&self) -> (i32, i32, i32) {
+        normal(self.axis, self.positive)
+    }
+
+    fn point(&self, a: i32, b: i32) -> Point {
+        let (u, v) = plane_axes(self.axis);
+        with_coordinate(with_coordinate(with_coordinate((0, 0, 0), self.axis, self.level), u, a), v, b)
+    }
+
+    /// The corners of the rectangle, counter-clockwise when seen from outside
+    pub fn corners(&self) -> [(i32, i32, i32); 4] {
+        let corners = [
+            self.point(self.min.0, self.min.1),
+            self.point(self.max.0, self.min.1),
+            self.point(self.max.0, self.max.1),
+            self.point(self.min.0, self.max.1),
+        ];
+        if self.positive {
+            corners
+        } else {
+            [corners[0], corners[3], corners[2], corners[1]]
+        }
+    }
+
+    /// The lattice points around the boundary that are in the given set, along with the corners,
+    /// counter-clockwise when seen from outside
+    fn boundary(&self, vertices: &HashSet<Point>) -> Vec<Point> {
+        let mut boundary = Vec::new();
+        let (min, max) = (self.min, self.max);
+        // walk each side from one corner up to but not including the next
+        let sides = [
+            ((min.0, min.1), (1, 0), max.0 - min.0),
+            ((max.0, min.1), (0, 1), max.1 - min.1),
+            ((max.0, max.1), (-1, 0), max.0 - min.0),
+            ((min.0, max.1), (0, -1), max.1 - min.1),
+        ];
+        for (start, step, length) in sides {
+            for i in 0..length {
+                let point = self.point(start.0 + i * step.0, start.1 + i * step.1);
+                if i == 0 || vertices.contains(&point) {
+                    boundary.push(point);
+                }
+            }
+        }
+        if !self.positive {
+            boundary[1..].reverse();
+        }
+        boundary
+    }
+}
+
+/// A surface of rectangles, as polygons over a shared list of vertices
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    pub vertices: Vec<Vertex>,
+    /// Indices into vertices, counter-clockwise when seen from outside, with the outward normal
+    pub polygons: Vec<(Vec<usize>, (i32, i32, i32))>,
+}
+
+impl Mesh {
+    /// Splits every polygon into triangles. Rectangles with extra points on their boundary are
+    /// split into a fan around their centre, since a fan from a corner would have triangles with
+    /// no area
+    pub fn triangles(&self) -> Vec<([Vertex; 3], (i32, i32, i32))> {
+        let mut triangles = Vec::new();
+        for (polygon, normal) in &self.polygons {
+            let points: Vec<Vertex> = polygon.iter().map(|&index| self.vertices[index]).collect();
+            if points.len() == 4 {
+                triangles.push(([points[0], points[1], points[2]], *normal));
+                triangles.push(([points[0], points[2], points[3]], *normal));
+                continue;
+            }
+
+            let n = points.len() as f32;
+            let centre = points.iter().fold((0.0, 0.0, 0.0), |sum, p| (sum.0 + p.0 / n, sum.1 + p.1 / n, sum.2 + p.2 / n));
+            for i in 0..points.len() {
+                triangles.push(([centre, points[i], points[(i + 1) % points.len()]], *normal));
+            }
+        }
+        triangles
+    }
+}
+
+impl<S: CellStorage> Polycube<S> {
+    // O(n)
+    /// Every face between a cell and an empty neighbor
+    pub fn surface_faces(&self) -> Vec<Face> {
+        let mut faces = Vec::new();
+        for cell in self.complex.cells() {
+            for axis in 0..3 {
+                for positive in [false, true] {
+                    let step = if positive { 1 } else { -1 };
+                    let neighbor = with_coordinate(cell, axis, coordinate(&cell, axis) + step);
+                    if !self.complex.contains(&neighbor) {
+                        faces.push(Face { cell, axis, positive });
+                    }
+                }
+            }
+        }
+        faces
+    }
+
+    // O(n log n)
+    /// The surface faces merged into as few rectangles as greedy meshing finds, in a fixed order
+    pub fn surface_rectangles(&self) -> Vec<Rectangle> {
+        // the faces of each plane, by their position in the plane
+        let mut planes: BTreeMap<(usize, bool, i32), BTreeSet<(i32, i32)>> = BTreeMap::new();
+        for face in self.surface_faces() {
+            let rectangle = face.rectangle();
+            planes.entry((face.axis, face.positive, rectangle.level)).or_default().insert(rectangle.min);
+        }
+
+        let mut rectangles = Vec::new();
+        for ((axis, positive, level), mut faces) in planes {
+            while let Some(&min) = faces.iter().next() {
+                // grow along the second axis first, since the set is ordered by the first
+                let mut max = (min.0 + 1, min.1 + 1);
+                while faces.contains(&(min.0, max.1)) {
+                    max.1 += 1;
+                }
+                while (min.1..max.1).all(|b| faces.contains(&(max.0, b))) {
+                    max.0 += 1;
+                }
+
+                for a in min.0..max.0 {
+                    for b in min.1..max.1 {
+                        faces.remove(&(a, b));
+                    }
+                }
+                rectangles.push(Rectangle { axis, positive, level, min, max });
+            }
+        }
+        rectangles
+    }
+
+    /// The merged surface as a mesh without T-junctions, in cell coordinates, one unit per cell
+    pub fn surface_mesh(&self) -> Mesh {
+        let rectangles = self.surface_rectangles();
+        let corners: HashSet<Point> = rectangles.iter().flat_map(|rectangle| rectangle.corners()).collect();
+
+        let mut mesh = Mesh::default();
+        let mut indices: HashMap<Point, usize> = HashMap::new();
+        for rectangle in rectangles {
+            let polygon = rectangle.boundary(&corners).into_iter().map(|point| {
+                *indices.entry(point).or_insert_with(|| {
+                    mesh.vertices.push((point.0 as f32, point.1 as f32, point.2 as f32));
+                    mesh.vertices.len() - 1
+                })
+            }).collect();
+            mesh.polygons.push((polygon, rectangle.normal()));
+        }
+        mesh
+    }
+
+    /// Exports the surface as an ASCII STL mesh
+    pub fn export_stl(&self) -> String {
+        let mut stl = String::from("solid polycube\n");
+
+        for (triangle, normal) in self.surface_mesh().triangles() {
+            stl.push_str(&format!("  facet normal {} {} {}\n    outer loop\n", normal.0, normal.1, normal.2));
+            for vertex in triangle {
+                stl.push_str(&format!("      vertex {} {} {}\n", vertex.0, vertex.1, vertex.2));
+            }
+            stl.push_str("    endloop\n  endfacet\n");
+        }
+
+        stl.push_str("endsolid polycube\n");
+        stl
+    }
+
+    /// Exports the surface as a binary STL mesh
+    pub fn export_stl_binary(&self) -> Vec<u8> {
+        let triangles = self.surface_mesh().triangles();
+
+        let mut stl = vec![0; 80];
+        stl[..8].copy_from_slice(b"polycube");
+        stl.extend_from_slice(&(triangles.len() as u32).to_le_bytes());
+        for (triangle, normal) in triangles {
+            for value in [normal.0 as f32, normal.1 as f32, normal.2 as f32] {
+                stl.extend_from_slice(&value.to_le_bytes());
+            }
+            for vertex in triangle {
+                for value in [vertex.0, vertex.1, vertex.2] {
+                    stl.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            // attribute byte count
+            stl.extend_from_slice(&[0, 0]);
+        }
+        stl
+    }
+
+    /// Exports the surface as a Wavefront OBJ mesh, with one polygon per merged rectangle
+    pub fn export_obj(&self) -> String {
+        let mesh = self.surface_mesh();
+        let mut obj = String::from("o polycube\n");
+
+        for vertex in &mesh.vertices {
+            obj.push_str(&format!("v {} {} {}\n", vertex.0, vertex.1, vertex.2));
+        }
+
+        // OBJ indices count from 1
+        let normals = [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)];
+        for normal in normals {
+            obj.push_str(&format!("vn {} {} {}\n", normal.0, normal.1, normal.2));
+        }
+        for (polygon, normal) in &mesh.polygons {
+            let n = normals.iter().position(|candidate| candidate == normal).expect("axis-aligned normal") + 1;
+            let indices: Vec<String> = polygon.iter().map(|index| format!("{}//{}", index + 1, n)).collect();
+            obj.push_str(&format!("f {}\n", indices.join(" ")));
+        }
+        obj
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::{block, hollow_cube, polycube, shuffled};
+
+    /// How many polygons have each directed edge
+    fn directed_edges<T: Clone + Ord>(polygons: &[Vec<T>]) -> std::collections::BTreeMap<(T, T), usize> {
+        let mut edges = std::collections::BTreeMap::new();
+        for polygon in polygons {
+            for i in 0..polygon.len() {
+                *edges.entry((polygon[i].clone(), polygon[(i + 1) % polygon.len()].clone())).or_insert(0) += 1;
+            }
+        }
+        edges
+    }
+
+    /// Every edge is crossed as often in one direction as in the other, so the surface is closed
+    /// and consistently wound, without T-junctions. Edge-manifold surfaces cross each edge exactly
+    /// once each way
+    fn assert_closed<T: Clone + Ord + std::fmt::Debug>(polygons: &[Vec<T>], manifold: bool) {
+        let edges = directed_edges(polygons);
+        for ((a, b), count) in &edges {
+            assert_eq!(edges.get(&(b.clone(), a.clone())), Some(count), "edge {:?} to {:?}", a, b);
+            if manifold {
+                assert_eq!(*count, 1, "edge {:?} to {:?}", a, b);
+            }
+        }
+    }
+
+    fn stl_triangles(stl: &str) -> Vec<Vec<String>> {
+        let vertices: Vec<String> = stl.lines().filter_map(|line| line.trim().strip_prefix("vertex ")).map(str::to_string).collect();
+        vertices.chunks(3).map(<[String]>::to_vec).collect()
+    }
+
+    fn obj_polygons(obj: &str) -> Vec<Vec<usize>> {
+        obj.lines()
+            .filter_map(|line| line.strip_prefix("f "))
+            .map(|face| face.split(' ').map(|corner| corner.split("//").next().unwrap().parse().unwrap()).collect())
+            .collect()
+    }
+
+    fn area(triangle: &[(f32, f32, f32); 3]) -> f32 {
+        let [a, b, c] = triangle;
+        let (u, v) = ((b.0 - a.0, b.1 - a.1, b.2 - a.2), (c.0 - a.0, c.1 - a.1, c.2 - a.2));
+        let cross = (u.1 * v.2 - u.2 * v.1, u.2 * v.0 - u.0 * v.2, u.0 * v.1 - u.1 * v.0);
+        (cross.0 * cross.0 + cross.1 * cross.1 + cross.2 * cross.2).sqrt() / 2.0
+    }
+
+    #[test]
+    fn merged_surfaces_are_watertight() {
+        for pfm in [hollow_cube(), shuffled(41), shuffled(42)] {
+            assert_closed(&stl_triangles(&pfm.export_stl()), false);
+            assert_closed(&obj_polygons(&pfm.export_obj()), false);
+
+            let mesh = pfm.surface_mesh();
+            let area: f32 = mesh.triangles().iter().map(|(triangle, _)| area(triangle)).sum();
+            assert!((area - pfm.surface_area() as f32).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn surfaces_without_cells_meeting_at_an_edge_are_manifold() {
+        // a plus sign around a column, a hollow cube and a staircase of slabs have no edge shared
+        // by more than two faces
+        let plus = polycube([(0, 0, 0), (1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, 2)]);
+        let stairs = polycube((0..4).flat_map(|x| (0..3).flat_map(move |y| (0..=x).map(move |z| (x, y, z)))));
+        for pfm in [plus, hollow_cube(), stairs] {
+            assert_closed(&stl_triangles(&pfm.export_stl()), true);
+            assert_closed(&obj_polygons(&pfm.export_obj()), true);
+        }
+
+        // a cube is six squares
+        let cube = polycube(block(2, 2, 2));
+        assert_eq!(cube.surface_rectangles().len(), 6);
+        assert_eq!(cube.surface_mesh().vertices.len(), 8);
+    }
+
+    #[test]
+    fn binary_stl_has_every_triangle() {
+        let pfm = shuffled(43);
+        let stl = pfm.export_stl_binary();
+        let count = u32::from_le_bytes(stl[80..84].try_into().unwrap()) as usize;
+        assert_eq!(count, pfm.surface_mesh().triangles().len());
+        assert_eq!(stl.len(), 84 + 50 * count);
+        assert_eq!(count, stl_triangles(&pfm.export_stl()).len());
+    }
+}
@@ -0,0 +1,587 @@
+//! Rejection-free (n-fold way) sampling for the shuffle chain.
+//!
+//! `shuffle` proposes a move, then rejects and reverses most of them at high `--bernoulli` p.
+//! Here we instead keep track of the total rate of every valid move, grouped by how much the move
+//! changes the site perimeter, draw the next accepted move directly, and advance time by the
+//! expected number of shuffle attempts the chain would have spent in the current state. Averages
+//! weighted by that time match averages over the rejection chain.
+//!
+//! A move takes a removable cell c out and puts it at a site d of the site perimeter of the rest.
+//! With t the site perimeter, the rejection chain makes it with probability
+//! a(Δt) / (n (t + r(c))), where r(c) is the change in site perimeter from removing c and
+//! a(Δt) = min(1, (1-p)^Δt) is the Bernoulli acceptance. When c is far from d (L1 distance 3 or
+//! more) the two don't interact, so Δt = r(c) + i(d), with i(d) the change from inserting d. Those
+//! moves are counted with histograms of r over cells and i over sites. Moves with d near c are
+//! cached per cell and recomputed only around cells that moved, so an event costs O(1) apart
+//! from the connectivity check.
+//!
+//! Rates don't know whether a move disconnects the polycube, since that isn't local. A drawn move
+//! that does is reversed instead, and the time it took still counts, as the rejections of the
+//! shuffle chain would. Such moves take out an articulation point, which compact polycubes at high
+//! p have few of. Connectivity is checked by the dynamic connectivity structure, which the sampler
+//! turns on, rather than by a DFS per event.
+
+use std::collections::{BTreeMap, HashMap};
+
+use rand::Rng;
+
+use crate::region::Shape;
+use crate::rng;
+use crate::storage::{CellSet, CellStorage};
+use crate::{Dist, Polycube, ShuffleStats};
+
+type Cell = (i32, i32, i32);
+
+const NEIGHBORS: [Cell; 6] = [(0, 0, 1), (0, 0, -1), (0, 1, 0), (0, -1, 0), (1, 0, 0), (-1, 0, 0)];
+
+fn add(a: &Cell, b: &Cell) -> Cell {
+    (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+}
+
+fn distance(a: &Cell, b: &Cell) -> i32 {
+    (a.0 - b.0).abs() + (a.1 - b.1).abs() + (a.2 - b.2).abs()
+}
+
+// offsets within L1 distance radius of the origin
+fn ball(radius: i32) -> Vec<Cell> {
+    let mut offsets = Vec::new();
+    for x in -radius..=radius {
+        for y in -radius..=radius {
+            for z in -radius..=radius {
+                if x.abs() + y.abs() + z.abs() <= radius {
+                    offsets.push((x, y, z));
+                }
+            }
+        }
+    }
+    offsets
+}
+
+/// A set with O(1) insertion, removal and uniform random picks
+#[derive(Default)]
+struct Bag {
+    items: Vec<Cell>,
+    index: HashMap<Cell, usize>,
+}
+
+impl Bag {
+    fn insert(&mut self, item: Cell) {
+        if !self.index.contains_key(&item) {
+            self.index.insert(item, self.items.len());
+            self.items.push(item);
+        }
+    }
+
+    fn remove(&mut self, item: &Cell) {
+        if let Some(i) = self.index.remove(item) {
+            self.items.swap_remove(i);
+            if i < self.items.len() {
+                self.index.insert(self.items[i], i);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn random(&self) -> Cell {
+        self.items[rng::thread_rng().gen_range(0..self.items.len())]
+    }
+}
+
+/// What a cell contributes to the rates, which depends only on the polycube within L1 distance 4
+struct CellRates {
+    // change in site perimeter from removing the cell
+    r: i32,
+    // sites near the cell that are in the site perimeter once it's removed, with the change in
+    // site perimeter of moving the cell there
+    near: Vec<(Cell, i32)>,
+    // sites of the current site perimeter near the cell with their insertion class i, which the
+    // histograms would otherwise count as far moves
+    excluded: Vec<(Cell, i32)>,
+}
+
+enum Choice {
+    Far(i32, i32),
+    Near(i32, i32),
+}
+
+/// The n-fold way sampler, which owns the polycube while it runs
+pub struct NFoldWay<S: CellStorage> {
+    pub pfm: Polycube<S>,
+
+    // time elapsed, in shuffle attempts of the rejection chain
+    pub time: f64,
+
+    // what the polycube's stats were when the sampler took it over
+    base: ShuffleStats,
+    // moves drawn, made or not
+    drawn: u64,
+
+    // per-cell contributions of every cell that can move
+    cells: HashMap<Cell, CellRates>,
+
+    // movable cells by r
+    groups: BTreeMap<i32, Bag>,
+    // sites of the site perimeter by i
+    sites: BTreeMap<i32, Bag>,
+    site_class: HashMap<Cell, i32>,
+
+    // counts of the (r, i) pairs in `excluded` and (r, Δt) pairs in `near` of movable cells
+    excluded: BTreeMap<(i32, i32), i64>,
+    near: BTreeMap<(i32, i32), i64>,
+
+    // offsets within L1 distance 2 and 4
+    near_offsets: Vec<Cell>,
+    affected_offsets: Vec<Cell>,
+}
+
+impl<S: CellStorage> NFoldWay<S> {
+    /// Takes over a polycube, turning on dynamic connectivity. Only the Bernoulli and uniform
+    /// weights, region masks and pinned cells are supported, anything else would make the rates
+    /// non-local
+    pub fn new(mut pfm: Polycube<S>) -> Result<NFoldWay<S>, String> {
+        if pfm.energy.is_some() {
+            return Err("energy models aren't supported by the rejection-free sampler".to_string());
+        }
+        if !pfm.constraints.is_empty() {
+            return Err("constraints aren't supported by the rejection-free sampler".to_string());
+        }
+        if let Some(region) = &pfm.region {
+            if region.contact_energy != 0.0 || region.shape == Shape::Wall {
+                return Err("only regions without contact energy or attachment are supported by the rejection-free sampler".to_string());
+            }
+        }
+
+        if pfm.connectivity.is_none() {
+            pfm.enable_dynamic_connectivity();
+        }
+
+        let mut sampler = NFoldWay {
+            base: pfm.stats,
+            pfm,
+            time: 0.0,
+            drawn: 0,
+            cells: HashMap::new(),
+            groups: BTreeMap::new(),
+            sites: BTreeMap::new(),
+            site_class: HashMap::new(),
+            excluded: BTreeMap::new(),
+            near: BTreeMap::new(),
+            near_offsets: ball(2),
+            affected_offsets: ball(4),
+        };
+
+        let sites: Vec<Cell> = sampler.pfm.insertable_locations.iter().copied().collect();
+        for site in sites {
+            sampler.update_site(site);
+        }
+
+        let cells: Vec<Cell> = sampler.pfm.complex.cells().collect();
+        for cell in cells {
+            sampler.update_cell(cell, [cell, cell]);
+        }
+
+        Ok(sampler)
+    }
+
+    fn occupied(&self, cell: &Cell) -> bool {
+        self.pfm.complex.contains(cell)
+    }
+
+    fn acceptance(&self, delta: i32) -> f64 {
+        match self.pfm.dist {
+            Dist::Bernoulli(p) => (1.0 - p).powi(delta).min(1.0),
+            Dist::Uniform => 1.0,
+        }
+    }
+
+    // O(1)
+    /// Change in the size of the site perimeter from taking out a cell: it joins it if it touches
+    /// the rest, and its empty neighbors that touched only it leave
+    fn removal_change(&self, cell: Cell) -> i32 {
+        let touches = |site: &Cell| {
+            NEIGHBORS.iter().any(|offset| {
+                let neighbor = add(site, offset);
+                neighbor != cell && self.occupied(&neighbor)
+            })
+        };
+        let leaving = NEIGHBORS
+            .iter()
+            .map(|offset| add(&cell, offset))
+            .filter(|neighbor| self.pfm.insertable_locations.contains(neighbor) && !touches(neighbor))
+            .count();
+        touches(&cell) as i32 - leaving as i32
+    }
+
+    // O(1)
+    /// Change in the size of the site perimeter from putting a cell at a site of the site
+    /// perimeter, once removed is taken out: the site leaves it, and its empty neighbors that
+    /// touched nothing join it
+    fn insertion_change(&self, site: Cell, removed: Option<Cell>) -> i32 {
+        let occupied = |cell: &Cell| self.occupied(cell) && Some(*cell) != removed;
+        let joining = NEIGHBORS
+            .iter()
+            .map(|offset| add(&site, offset))
+            .filter(|neighbor| {
+                !occupied(neighbor)
+                    && self.pfm.in_region(neighbor)
+                    && !NEIGHBORS.iter().any(|offset| occupied(&add(neighbor, offset)))
+            })
+            .count();
+        joining as i32 - 1
+    }
+
+    fn update_site(&mut self, site: Cell) {
+        if let Some(class) = self.site_class.remove(&site) {
+            self.sites.get_mut(&class).expect("class of a site").remove(&site);
+        }
+
+        if self.pfm.insertable_locations.contains(&site) {
+            let class = self.insertion_change(site, None);
+            self.site_class.insert(site, class);
+            self.sites.entry(class).or_default().insert(site);
+        }
+    }
+
+    fn contribute(&mut self, cell: Cell, sign: i64) {
+        let rates = &self.cells[&cell];
+        let r = rates.r;
+        for (_, class) in &rates.excluded {
+            *self.excluded.entry((r, *class)).or_default() += sign;
+        }
+        for (_, delta) in &rates.near {
+            *self.near.entry((r, *delta)).or_default() += sign;
+        }
+
+        if sign > 0 {
+            self.groups.entry(r).or_default().insert(cell);
+        } else {
+            self.groups.get_mut(&r).expect("group of a movable cell").remove(&cell);
+        }
+    }
+
+    /// The entries of the site in a cell's `excluded` and `near`
+    fn site_rates(&self, cell: Cell, r: i32, site: Cell) -> (Option<i32>, Option<i32>) {
+        if site == cell || self.occupied(&site) || !self.pfm.in_region(&site) {
+            return (None, None);
+        }
+
+        // in the site perimeter once the cell is gone
+        let touches = NEIGHBORS.iter().any(|offset| {
+            let neighbor = add(&site, offset);
+            neighbor != cell && self.occupied(&neighbor)
+        });
+
+        (
+            self.site_class.get(&site).copied(),
+            touches.then(|| r + self.insertion_change(site, Some(cell))),
+        )
+    }
+
+    /// Recomputes a cell's contribution, and whether it contributes at all, after the given cells
+    /// were removed and inserted. A cell further than 2 from both only needs the sites near them
+    fn update_cell(&mut self, cell: Cell, moved: [Cell; 2]) {
+        let stale = |site: &Cell| moved.iter().any(|m| distance(m, site) <= 2);
+
+        if !stale(&cell) {
+            if let Some(mut rates) = self.cells.remove(&cell) {
+                // r doesn't change, so only the stale entries need to come out of the counts and
+                // go back in
+                let r = rates.r;
+                let (old_excluded, excluded): (Vec<_>, Vec<_>) = rates.excluded.into_iter().partition(|(site, _)| stale(site));
+                let (old_near, near): (Vec<_>, Vec<_>) = rates.near.into_iter().partition(|(site, _)| stale(site));
+                rates.excluded = excluded;
+                rates.near = near;
+
+                let (excluded_len, near_len) = (rates.excluded.len(), rates.near.len());
+                for offset in &self.near_offsets {
+                    let site = add(&cell, offset);
+                    if stale(&site) {
+                        self.push_site(&mut rates, cell, site);
+                    }
+                }
+
+                for (_, class) in &old_excluded {
+                    *self.excluded.entry((r, *class)).or_default() -= 1;
+                }
+                for (_, delta) in &old_near {
+                    *self.near.entry((r, *delta)).or_default() -= 1;
+                }
+                for (_, class) in &rates.excluded[excluded_len..] {
+                    *self.excluded.entry((r, *class)).or_default() += 1;
+                }
+                for (_, delta) in &rates.near[near_len..] {
+                    *self.near.entry((r, *delta)).or_default() += 1;
+                }
+
+                self.cells.insert(cell, rates);
+                return;
+            }
+        }
+
+        if self.cells.contains_key(&cell) {
+            self.contribute(cell, -1);
+            self.cells.remove(&cell);
+        }
+
+        if !self.occupied(&cell) || self.pfm.is_pinned(&cell) {
+            return;
+        }
+
+        let mut rates = CellRates {
+            r: self.removal_change(cell),
+            near: Vec::new(),
+            excluded: Vec::new(),
+        };
+        for offset in &self.near_offsets {
+            self.push_site(&mut rates, cell, add(&cell, offset));
+        }
+
+        self.cells.insert(cell, rates);
+        self.contribute(cell, 1);
+    }
+
+    fn push_site(&self, rates: &mut CellRates, cell: Cell, site: Cell) {
+        let (excluded, near) = self.site_rates(cell, rates.r, site);
+        if let Some(class) = excluded {
+            rates.excluded.push((site, class));
+        }
+        if let Some(delta) = near {
+            rates.near.push((site, delta));
+        }
+    }
+
+    fn movable(&self) -> usize {
+        self.cells.len()
+    }
+
+    // the rates of every group of moves, in shuffle attempts
+    fn choices(&self) -> Vec<(f64, Choice)> {
+        let n = self.movable() as f64;
+        let t = self.pfm.insertable_locations.len() as i32;
+        let proposal = |r: i32| 1.0 / (n * (t + r) as f64);
+        let mut choices = Vec::new();
+
+        for (&r, group) in &self.groups {
+            if group.len() == 0 {
+                continue;
+            }
+            for (&i, sites) in &self.sites {
+                let far = group.len() as i64 * sites.len() as i64 - self.excluded.get(&(r, i)).copied().unwrap_or(0);
+                if far > 0 {
+                    choices.push((far as f64 * self.acceptance(r + i) * proposal(r), Choice::Far(r, i)));
+                }
+            }
+        }
+
+        for (&(r, delta), &count) in &self.near {
+            if count > 0 {
+                choices.push((count as f64 * self.acceptance(delta) * proposal(r), Choice::Near(r, delta)));
+            }
+        }
+
+        choices
+    }
+
+    /// Probability that a single shuffle attempt of the rejection chain draws a move its weights
+    /// accept, whether or not the move keeps the polycube connected
+    pub fn total_rate(&self) -> f64 {
+        self.choices().iter().map(|(rate, _)| rate).sum()
+    }
+
+    /// Picks the next move as (removed, inserted), along with the time the polycube spends in its
+    /// current state before making it. None if no cell can move
+    fn next(&self) -> Option<(f64, Cell, Cell)> {
+        let choices = self.choices();
+        let total: f64 = choices.iter().map(|(rate, _)| rate).sum();
+        if total <= 0.0 {
+            return None;
+        }
+
+        let mut rng = rng::thread_rng();
+        let mut u = rng.gen::<f64>() * total;
+        let mut chosen = &choices[choices.len() - 1].1;
+        for (rate, choice) in &choices {
+            if u < *rate {
+                chosen = choice;
+                break;
+            }
+            u -= rate;
+        }
+
+        let (removed, inserted) = match *chosen {
+            Choice::Far(r, i) => loop {
+                let cell = self.groups[&r].random();
+                let site = self.sites[&i].random();
+                if distance(&cell, &site) > 2 {
+                    break (cell, site);
+                }
+            },
+            // there are at most 24 sites within distance 2 of a cell
+            Choice::Near(r, delta) => loop {
+                let cell = self.groups[&r].random();
+                let slot = rng.gen_range(0..24);
+                if let Some(&(site, d)) = self.cells[&cell].near.get(slot) {
+                    if d == delta {
+                        break (cell, site);
+                    }
+                }
+            },
+        };
+
+        Some((1.0 / total, removed, inserted))
+    }
+
+    /// Draws the next move and makes it if it keeps the polycube connected. Returns the time the
+    /// polycube spent in its state before the move, along with the move as (inserted, removed)
+    /// like `shuffle` does, or None in its place if it was reversed. None if no cell can move
+    pub fn step(&mut self) -> Option<(f64, Option<(Cell, Cell)>)> {
+        let (dt, removed, inserted) = self.next()?;
+        self.time += dt;
+        let moved = self.apply(removed, inserted).then_some((inserted, removed));
+        self.record_time();
+        Some((dt, moved))
+    }
+
+    /// Makes a move and updates the rates around it, or reverses it if it disconnects the
+    /// polycube. Returns whether it was made
+    fn apply(&mut self, removed: Cell, inserted: Cell) -> bool {
+        self.drawn += 1;
+        self.pfm.remove(&removed);
+        self.pfm.insert(inserted);
+        if !self.pfm.is_connected() {
+            self.pfm.remove(&inserted);
+            self.pfm.insert(removed);
+            self.pfm.stats.rejected_as_invalid += 1;
+            return false;
+        }
+        self.pfm.stats.accepted += 1;
+
+        for i in 0..self.near_offsets.len() {
+            let offset = self.near_offsets[i];
+            self.update_site(add(&removed, &offset));
+            self.update_site(add(&inserted, &offset));
+        }
+
+        let mut affected = CellSet::default();
+        for offset in &self.affected_offsets {
+            for center in [removed, inserted] {
+                let cell = add(&center, offset);
+                if self.occupied(&cell) || self.cells.contains_key(&cell) {
+                    affected.insert(cell);
+                }
+            }
+        }
+        for cell in affected {
+            self.update_cell(cell, [removed, inserted]);
+        }
+        true
+    }
+
+    /// Brings the polycube's stats up to the time elapsed, counting every shuffle attempt that
+    /// time stands for as proposed, and those that drew no move as rejected by weight
+    fn record_time(&mut self) {
+        let stats = &mut self.pfm.stats;
+        stats.proposed = self.base.proposed + (self.time.round() as u64).max(self.drawn);
+        stats.rejected_by_weight = stats.proposed - stats.accepted - stats.rejected_as_invalid;
+    }
+
+    /// Moves until the given time has passed, calling observe with the polycube and the time it
+    /// spent in each state. Returns the number of moves made
+    pub fn run(&mut self, until: f64, mut observe: impl FnMut(&Polycube<S>, f64)) -> usize {
+        let mut moves = 0;
+        while self.time < until {
+            let (dt, removed, inserted) = match self.next() {
+                Some(next) => next,
+                None => break,
+            };
+
+            // the last state only counts until the deadline
+            if self.time + dt > until {
+                observe(&self.pfm, until - self.time);
+                self.time = until;
+                break;
+            }
+
+            observe(&self.pfm, dt);
+            self.time += dt;
+            if self.apply(removed, inserted) {
+                moves += 1;
+            }
+        }
+        self.record_time();
+        moves
+    }
+}
+
+/// Accumulates an average weighted by the time spent in each state
+#[derive(Clone, Debug, Default)]
+pub struct TimeAverage {
+    pub sum: f64,
+    pub time: f64,
+}
+
+impl TimeAverage {
+    pub fn add(&mut self, value: f64, dt: f64) {
+        self.sum += value * dt;
+        self.time += dt;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells<S: CellStorage>(pfm: &Polycube<S>) -> Vec<Cell> {
+        let mut cells: Vec<Cell> = pfm.complex.cells().collect();
+        cells.sort_unstable();
+        cells
+    }
+
+    #[test]
+    fn incremental_rates_match_a_fresh_sampler() {
+        rng::seed(34);
+        let mut sampler = NFoldWay::new(Polycube::new(10, Dist::Bernoulli(0.6))).unwrap();
+        for _ in 0..500 {
+            sampler.step();
+
+            let mut fresh = Polycube::new(0, Dist::Bernoulli(0.6));
+            fresh.set_cells(cells(&sampler.pfm));
+            let fresh = NFoldWay::new(fresh).unwrap();
+            assert!((sampler.total_rate() - fresh.total_rate()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn time_averages_match_the_shuffle_chain() {
+        let (p, attempts) = (0.6, 10_000);
+
+        rng::seed(34);
+        let mut pfm = Polycube::new(6, Dist::Bernoulli(p));
+        let mut shuffled = TimeAverage::default();
+        for _ in 0..attempts {
+            pfm.shuffle(1);
+            shuffled.add(pfm.insertable_locations.len() as f64, 1.0);
+        }
+
+        rng::seed(34);
+        let mut sampler = NFoldWay::new(Polycube::new(6, Dist::Bernoulli(p))).unwrap();
+        let mut weighted = TimeAverage::default();
+        let moves = sampler.run(attempts as f64, |pfm, dt| weighted.add(pfm.insertable_locations.len() as f64, dt));
+
+        assert!((shuffled.mean() - weighted.mean()).abs() < 0.02 * shuffled.mean(), "{} {}", shuffled.mean(), weighted.mean());
+        assert!(sampler.pfm.is_connected());
+        assert!(sampler.pfm.dfs());
+
+        let stats = sampler.pfm.stats;
+        assert_eq!(stats.proposed, attempts);
+        assert_eq!(stats.accepted, moves as u64);
+        assert_eq!(stats.proposed, stats.accepted + stats.rejected_by_weight + stats.rejected_as_invalid);
+    }
+}
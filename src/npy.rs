@@ -0,0 +1,249 @@
+//! Dense occupancy arrays in NumPy's `.npy` format, and `.npz` archives of them for batches, for
+//! 3D CNNs and `scipy.ndimage`.
+//!
+//! Arrays are uint8 in C order, indexed `[x, y, z]`, with 1 for cells and 0 elsewhere. With the
+//! site perimeter as a second channel they are indexed `[channel, x, y, z]`, channel 1 marking
+//! `insertable_locations`. By default the grid is cropped to the bounding box; a fixed size pads
+//! it instead, with the polycube in the middle. `.npz` files are zip archives of `.npy` files,
+//! written uncompressed like the PNG encoder in `image`.
+
+use crate::image::crc32;
+use crate::storage::CellStorage;
+use crate::Polycube;
+
+type Cell = (i32, i32, i32);
+
+/// How the occupancy grid is laid out
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Grid {
+    /// Pad to this many cells along each axis, instead of cropping to the bounding box
+    pub size: Option<[usize; 3]>,
+    /// Put the centroid of the cells in the middle of the grid, instead of the middle of the
+    /// bounding box
+    pub center: bool,
+    /// Add the site perimeter as a second channel
+    pub perimeter: bool,
+}
+
+/// A uint8 array and its shape
+#[derive(Clone, Debug, PartialEq)]
+pub struct Array {
+    pub shape: Vec<usize>,
+    pub data: Vec<u8>,
+}
+
+impl Array {
+    /// The array in the `.npy` format, version 1.0
+    pub fn encode_npy(&self) -> Vec<u8> {
+        let shape = match self.shape.as_slice() {
+            [length] => format!("({},)", length),
+            shape => format!("({})", shape.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ")),
+        };
+        let mut header = format!("{{'descr': '|u1', 'fortran_order': False, 'shape': {}, }}", shape);
+        // the magic, version, header length and header are padded to a multiple of 64 bytes
+        let padding = 63 - (10 + header.len()) % 64;
+        header.push_str(&" ".repeat(padding));
+        header.push('\n');
+
+        let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+        npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        npy.extend_from_slice(header.as_bytes());
+        npy.extend_from_slice(&self.data);
+        npy
+    }
+
+    /// Stacks arrays of the same shape along a new first axis
+    pub fn stack(arrays: &[Array]) -> Result<Array, String> {
+        let shape = arrays.first().map_or(Vec::new(), |array| array.shape.clone());
+        if let Some(other) = arrays.iter().find(|array| array.shape != shape) {
+            return Err(format!("can't stack arrays of shapes {:?} and {:?}", shape, other.shape));
+        }
+        let mut stacked = Array { shape: [arrays.len()].into_iter().chain(shape).collect(), data: Vec::new() };
+        for array in arrays {
+            stacked.data.extend_from_slice(&array.data);
+        }
+        Ok(stacked)
+    }
+}
+
+/// A zip archive of the given files, stored uncompressed, which `numpy.load` reads as `.npz` when
+/// the files are `.npy` arrays
+pub fn npz(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let mut directory = Vec::new();
+    for (name, data) in files {
+        let offset = zip.len() as u32;
+        // version needed, flags, stored, time, date, crc, sizes, name length, extra length
+        let mut header = Vec::new();
+        header.extend_from_slice(&20u16.to_le_bytes());
+        header.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x21, 0]);
+        header.extend_from_slice(&crc32(data).to_le_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&[0, 0]);
+
+        zip.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        zip.extend_from_slice(&header);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(data);
+
+        // version made by, then the local header, comment length, disk, attributes and offset
+        directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        directory.extend_from_slice(&20u16.to_le_bytes());
+        directory.extend_from_slice(&header);
+        directory.extend_from_slice(&[0; 10]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let start = zip.len() as u32;
+    zip.extend_from_slice(&directory);
+    zip.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    zip.extend_from_slice(&[0, 0, 0, 0]);
+    zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    zip.extend_from_slice(&start.to_le_bytes());
+    zip.extend_from_slice(&[0, 0]);
+    zip
+}
+
+impl<S: CellStorage> Polycube<S> {
+    /// The occupancy grid of the cells. Fails if they don't fit in a grid of the given size
+    pub fn occupancy(&self, grid: &Grid) -> Result<Array, String> {
+        let cells: Vec<Cell> = self.complex.cells().collect();
+        let perimeter: Vec<Cell> = if grid.perimeter { self.insertable_locations.iter().copied().collect() } else { Vec::new() };
+        // the site perimeter sticks out of the bounding box by a cell, except where a region cuts it off
+        let (min, max) = match self.tight_bounds() {
+            Some(bounds) => perimeter.iter().fold(bounds, |(min, max), cell| {
+                ((min.0.min(cell.0), min.1.min(cell.1), min.2.min(cell.2)), (max.0.max(cell.0), max.1.max(cell.1), max.2.max(cell.2)))
+            }),
+            None => ((0, 0, 0), (-1, -1, -1)),
+        };
+
+        // the rounded centroid, or the middle of the bounding box
+        let middle = if grid.center && !cells.is_empty() {
+            let n = cells.len() as f64;
+            let sum = cells.iter().fold((0.0, 0.0, 0.0), |sum, cell| (sum.0 + cell.0 as f64, sum.1 + cell.1 as f64, sum.2 + cell.2 as f64));
+            [(sum.0 / n).round() as i32, (sum.1 / n).round() as i32, (sum.2 / n).round() as i32]
+        } else {
+            [(min.0 + max.0).div_euclid(2), (min.1 + max.1).div_euclid(2), (min.2 + max.2).div_euclid(2)]
+        };
+        let (min, max) = ([min.0, min.1, min.2], [max.0, max.1, max.2]);
+
+        let mut origin = [0; 3];
+        let mut size = [0; 3];
+        for axis in 0..3 {
+            (origin[axis], size[axis]) = match (grid.size, grid.center) {
+                (Some(fixed), _) => (middle[axis] - (fixed[axis] as i32 - 1) / 2, fixed[axis]),
+                // as small as it can be with the centroid in the middle
+                (None, true) => {
+                    let half = (middle[axis] - min[axis]).max(max[axis] - middle[axis]);
+                    (middle[axis] - half, 2 * half as usize + 1)
+                }
+                (None, false) => (min[axis], (max[axis] - min[axis] + 1) as usize),
+            };
+        }
+
+        let channels = if grid.perimeter { 2 } else { 1 };
+        let volume = size[0] * size[1] * size[2];
+        let mut data = vec![0; channels * volume];
+        for (channel, cells) in [&cells, &perimeter].into_iter().enumerate().take(channels) {
+            for cell in cells {
+                let offset = [cell.0 - origin[0], cell.1 - origin[1], cell.2 - origin[2]];
+                if (0..3).any(|axis| offset[axis] < 0 || offset[axis] >= size[axis] as i32) {
+                    return Err(format!("{:?} is outside the {}x{}x{} grid", cell, size[0], size[1], size[2]));
+                }
+                let index = (offset[0] as usize * size[1] + offset[1] as usize) * size[2] + offset[2] as usize;
+                data[channel * volume + index] = 1;
+            }
+        }
+
+        let shape = if grid.perimeter { vec![2, size[0], size[1], size[2]] } else { size.to_vec() };
+        Ok(Array { shape, data })
+    }
+
+    /// Exports the occupancy grid as a `.npy` file
+    pub fn export_npy(&self, grid: &Grid) -> Result<Vec<u8>, String> {
+        Ok(self.occupancy(grid)?.encode_npy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::polycube;
+
+    fn at(array: &Array, index: [usize; 3]) -> u8 {
+        let [_, y, z] = array.shape[..] else { panic!("not 3D") };
+        array.data[(index[0] * y + index[1]) * z + index[2]]
+    }
+
+    #[test]
+    fn npy_headers_are_aligned() {
+        for shape in [vec![3], vec![2, 3, 4], vec![2, 10, 10, 10]] {
+            let array = Array { data: vec![1; shape.iter().product()], shape };
+            let npy = array.encode_npy();
+            let header = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+            assert_eq!((10 + header) % 64, 0);
+            assert_eq!(npy[10 + header - 1], b'\n');
+            assert_eq!(npy.len(), 10 + header + array.data.len());
+        }
+        assert!(String::from_utf8_lossy(&Array { shape: vec![3], data: vec![0; 3] }.encode_npy()).contains("'shape': (3,)"));
+    }
+
+    #[test]
+    fn crops_pads_and_centers() {
+        // an L tromino
+        let pfm = polycube([(0, 0, 0), (1, 0, 0), (1, 1, 0)]);
+        let cropped = pfm.occupancy(&Grid::default()).unwrap();
+        assert_eq!(cropped.shape, [2, 2, 1]);
+        assert_eq!(cropped.data, [1, 0, 1, 1]);
+
+        let padded = pfm.occupancy(&Grid { size: Some([5, 5, 5]), ..Grid::default() }).unwrap();
+        assert_eq!(padded.shape, [5, 5, 5]);
+        assert_eq!(padded.data.iter().map(|&v| v as usize).sum::<usize>(), 3);
+        // the middle of the bounding box, rounded down, is at the middle of the grid
+        assert_eq!((at(&padded, [2, 2, 2]), at(&padded, [3, 2, 2]), at(&padded, [3, 3, 2])), (1, 1, 1));
+        assert!(pfm.occupancy(&Grid { size: Some([1, 5, 5]), ..Grid::default() }).is_err());
+
+        let line = polycube([(0, 0, 0), (0, 0, 1), (0, 0, 2), (0, 0, 3), (0, 0, 4), (0, 0, 5), (1, 0, 5)]);
+        let centered = line.occupancy(&Grid { center: true, ..Grid::default() }).unwrap();
+        // the centroid rounds to (0, 0, 3), so the grid reaches as far below it as above
+        assert_eq!(centered.shape, [3, 1, 7]);
+        assert_eq!(at(&centered, [1, 0, 3]), 1);
+    }
+
+    #[test]
+    fn perimeter_is_the_second_channel() {
+        let pfm = polycube([(0, 0, 0), (1, 0, 0)]);
+        let array = pfm.occupancy(&Grid { perimeter: true, ..Grid::default() }).unwrap();
+        assert_eq!(array.shape, [2, 4, 3, 3]);
+        let volume = 4 * 3 * 3;
+        assert_eq!(array.data[..volume].iter().filter(|&&v| v == 1).count(), 2);
+        assert_eq!(array.data[volume..].iter().filter(|&&v| v == 1).count(), pfm.insertable_locations.len());
+    }
+
+    #[test]
+    fn stacks_and_archives_arrays() {
+        let (a, b) = (Array { shape: vec![2], data: vec![0, 1] }, Array { shape: vec![2], data: vec![1, 1] });
+        let stacked = Array::stack(&[a.clone(), b.clone()]).unwrap();
+        assert_eq!((stacked.shape, stacked.data), (vec![2, 2], vec![0, 1, 1, 1]));
+        assert!(Array::stack(&[a.clone(), Array { shape: vec![3], data: vec![0; 3] }]).is_err());
+
+        let files = vec![("arr_0.npy".to_string(), a.encode_npy()), ("arr_1.npy".to_string(), b.encode_npy())];
+        let zip = npz(&files);
+        assert!(zip.starts_with(&0x04034b50u32.to_le_bytes()));
+        // the end of the central directory counts the files and points at the directory
+        let end = &zip[zip.len() - 22..];
+        assert_eq!(end[..4], 0x06054b50u32.to_le_bytes());
+        assert_eq!(u16::from_le_bytes([end[10], end[11]]), 2);
+        let start = u32::from_le_bytes(end[16..20].try_into().unwrap()) as usize;
+        assert_eq!(zip[start..start + 4], 0x02014b50u32.to_le_bytes());
+        // the first file is stored whole after its header, with its crc
+        let data = &zip[30 + 9..30 + 9 + files[0].1.len()];
+        assert_eq!(data, files[0].1);
+        assert_eq!(zip[14..18], crc32(data).to_le_bytes());
+    }
+}
@@ -0,0 +1,266 @@
+//! Shape statistics of a polycube, for every sample of a run.
+//!
+//! Most of them cost O(1) per shuffle. `insert` and `remove` keep the first and second moments of
+//! the cell coordinates up to date, from which the gyration tensor follows, and the number of
+//! contacts, from which the surface area and the cycle rank follow: every contact hides two of the
+//! 6n faces, and a connected graph with n vertices and c edges has c - n + 1 independent cycles.
+//! Only the tight bounding box takes O(n), since the tracked one never shrinks.
+//!
+//! JSON exports and the metadata of sample containers carry them. The other formats have nowhere
+//! to put them, so `polyforms observables` computes them from files in any format.
+//!
+//! Eigenvalues of the gyration tensor are sorted from largest to smallest. Asphericity and
+//! prolateness are the normalized forms of Rudnick and Gaspari: asphericity is 0 for shapes as
+//! extended along every axis and 1 for rods, prolateness is between -1/4 for flat disks and 2 for
+//! rods.
+
+use std::f64::consts::PI;
+
+use serde::Serialize;
+
+use crate::storage::CellStorage;
+use crate::Polycube;
+
+type Cell = (i32, i32, i32);
+
+/// Sums of the coordinates of the cells and of their pairwise products, exact in integers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Moments {
+    pub count: i64,
+    /// Σx, Σy, Σz
+    pub sums: [i64; 3],
+    /// Σxx, Σyy, Σzz, Σxy, Σxz, Σyz
+    pub products: [i64; 6],
+}
+
+impl Moments {
+    pub fn of(cells: impl IntoIterator<Item = Cell>) -> Moments {
+        let mut moments = Moments::default();
+        for cell in cells {
+            moments.add(&cell, 1);
+        }
+        moments
+    }
+
+    // O(1)
+    /// Adds a cell with a sign of 1, or removes it with -1
+    pub fn add(&mut self, cell: &Cell, sign: i64) {
+        let (x, y, z) = (cell.0 as i64, cell.1 as i64, cell.2 as i64);
+        self.count += sign;
+        for (sum, value) in self.sums.iter_mut().zip([x, y, z]) {
+            *sum += sign * value;
+        }
+        for (sum, value) in self.products.iter_mut().zip([x * x, y * y, z * z, x * y, x * z, y * z]) {
+            *sum += sign * value;
+        }
+    }
+
+    /// The gyration tensor, the covariance of the cell coordinates
+    pub fn gyration_tensor(&self) -> [[f64; 3]; 3] {
+        if self.count == 0 {
+            return [[0.0; 3]; 3];
+        }
+        let n = self.count as f64;
+        let mean = self.sums.map(|sum| sum as f64 / n);
+        let p = self.products.map(|sum| sum as f64 / n);
+        let xy = p[3] - mean[0] * mean[1];
+        let xz = p[4] - mean[0] * mean[2];
+        let yz = p[5] - mean[1] * mean[2];
+        [
+            [p[0] - mean[0] * mean[0], xy, xz],
+            [xy, p[1] - mean[1] * mean[1], yz],
+            [xz, yz, p[2] - mean[2] * mean[2]],
+        ]
+    }
+}
+
+/// Eigenvalues of a symmetric 3x3 matrix, from largest to smallest, in closed form
+fn symmetric_eigenvalues(m: [[f64; 3]; 3]) -> [f64; 3] {
+    let q = (m[0][0] + m[1][1] + m[2][2]) / 3.0;
+    let off = m[0][1].powi(2) + m[0][2].powi(2) + m[1][2].powi(2);
+    let p = (((m[0][0] - q).powi(2) + (m[1][1] - q).powi(2) + (m[2][2] - q).powi(2) + 2.0 * off) / 6.0).sqrt();
+    if p < 1e-12 {
+        return [q; 3];
+    }
+
+    // the eigenvalues of B = (M - qI) / p are 2cos(φ + 2πk/3), where det(B) = 2cos(3φ)
+    let b = |i: usize, j: usize| (m[i][j] - if i == j { q } else { 0.0 }) / p;
+    let det = b(0, 0) * (b(1, 1) * b(2, 2) - b(1, 2) * b(2, 1)) - b(0, 1) * (b(1, 0) * b(2, 2) - b(1, 2) * b(2, 0))
+        + b(0, 2) * (b(1, 0) * b(2, 1) - b(1, 1) * b(2, 0));
+    let phi = (det / 2.0).clamp(-1.0, 1.0).acos() / 3.0;
+
+    let largest = q + 2.0 * p * phi.cos();
+    let smallest = q + 2.0 * p * (phi + 2.0 * PI / 3.0).cos();
+    [largest, 3.0 * q - largest - smallest, smallest]
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Observables {
+    pub cells: usize,
+    pub site_perimeter: usize,
+    pub radius_of_gyration: f64,
+    /// From largest to smallest
+    pub gyration_eigenvalues: [f64; 3],
+    pub asphericity: f64,
+    pub prolateness: f64,
+    /// Cells along x, y and z
+    pub bounding_box: [usize; 3],
+    /// Faces between a cell and an empty neighbor
+    pub surface_area: usize,
+    /// Pairs of cells that share a face
+    pub contacts: usize,
+    /// Independent cycles of the adjacency graph of the cells
+    pub cycle_rank: usize,
+}
+
+impl Observables {
+    /// Names of the fields, in the order of `values`, with the eigenvalues and bounding box
+    /// split into one column per axis
+    pub const COLUMNS: [&'static str; 14] = [
+        "cells", "site_perimeter", "radius_of_gyration", "lambda_1", "lambda_2", "lambda_3", "asphericity",
+        "prolateness", "box_x", "box_y", "box_z", "surface_area", "contacts", "cycle_rank",
+    ];
+
+    /// The observables as one row of a table, in the order of `COLUMNS`
+    pub fn values(&self) -> Vec<String> {
+        let [l1, l2, l3] = self.gyration_eigenvalues;
+        let [bx, by, bz] = self.bounding_box;
+        let mut row = vec![self.cells.to_string(), self.site_perimeter.to_string()];
+        row.extend([self.radius_of_gyration, l1, l2, l3, self.asphericity, self.prolateness].map(|value| format!("{:.6}", value)));
+        row.extend([bx, by, bz, self.surface_area, self.contacts, self.cycle_rank].map(|value| value.to_string()));
+        row
+    }
+}
+
+impl<S: CellStorage> Polycube<S> {
+    // O(1)
+    /// The gyration tensor of the cells, kept up to date through every move
+    pub fn gyration_tensor(&self) -> [[f64; 3]; 3] {
+        self.moments.gyration_tensor()
+    }
+
+    // O(1)
+    pub fn radius_of_gyration(&self) -> f64 {
+        let tensor = self.gyration_tensor();
+        (tensor[0][0] + tensor[1][1] + tensor[2][2]).max(0.0).sqrt()
+    }
+
+    // O(1)
+    /// Faces between a cell and an empty neighbor
+    pub fn surface_area(&self) -> usize {
+        6 * self.complex.len() - 2 * self.contacts
+    }
+
+    // O(1)
+    /// Pairs of cells that share a face
+    pub fn contact_count(&self) -> usize {
+        self.contacts
+    }
+
+    // O(1)
+    /// Independent cycles of the adjacency graph, assuming the cells are connected
+    pub fn cycle_rank(&self) -> usize {
+        (self.contacts + 1).saturating_sub(self.complex.len())
+    }
+
+    // O(n)
+    pub fn observables(&self) -> Observables {
+        let tensor = self.gyration_tensor();
+        let eigenvalues = symmetric_eigenvalues(tensor);
+        let trace = tensor[0][0] + tensor[1][1] + tensor[2][2];
+        let (asphericity, prolateness) = if trace > 1e-12 {
+            let [l1, l2, l3] = eigenvalues;
+            let spread = (l1 - l2).powi(2) + (l2 - l3).powi(2) + (l1 - l3).powi(2);
+            let mean = trace / 3.0;
+            (spread / (2.0 * trace * trace), 27.0 * (l1 - mean) * (l2 - mean) * (l3 - mean) / trace.powi(3))
+        } else {
+            (0.0, 0.0)
+        };
+
+        let bounding_box = match self.tight_bounds() {
+            Some((min, max)) => [(max.0 - min.0 + 1) as usize, (max.1 - min.1 + 1) as usize, (max.2 - min.2 + 1) as usize],
+            None => [0; 3],
+        };
+
+        Observables {
+            cells: self.complex.len(),
+            site_perimeter: self.insertable_locations.len(),
+            radius_of_gyration: trace.max(0.0).sqrt(),
+            gyration_eigenvalues: eigenvalues.map(|eigenvalue| eigenvalue.max(0.0)),
+            asphericity,
+            prolateness,
+            bounding_box,
+            surface_area: self.surface_area(),
+            contacts: self.contacts,
+            cycle_rank: self.cycle_rank(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{block, polycube};
+    use crate::storage::BitGrid;
+    use crate::{get_neighbors, rng, Dist};
+    use rand::Rng;
+
+    fn contacts<S: CellStorage>(pfm: &Polycube<S>) -> usize {
+        pfm.complex.cells().map(|cell| get_neighbors(&pfm.complex, &cell).len()).sum::<usize>() / 2
+    }
+
+    // repeated eigenvalues cost the closed form about half the digits of a double
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn incremental_counts_match_a_recompute() {
+        rng::seed(49);
+        let mut pfm = Polycube::new(40, Dist::Bernoulli(0.6));
+        let mut grid: Polycube<BitGrid> = Polycube::with_storage(25, Dist::Uniform);
+        for _ in 0..100 {
+            pfm.shuffle(rng::thread_rng().gen_range(1..50));
+            grid.shuffle(rng::thread_rng().gen_range(1..50));
+            assert_eq!(pfm.moments, Moments::of(pfm.complex.cells()));
+            assert_eq!(pfm.contact_count(), contacts(&pfm));
+            assert_eq!(grid.moments, Moments::of(grid.complex.cells()));
+            assert_eq!(grid.contact_count(), contacts(&grid));
+        }
+
+        // replacing every cell starts the counts over
+        pfm.set_cells([(0, 0, 0), (1, 0, 0), (1, 1, 0), (0, 1, 0)]);
+        assert_eq!(pfm.moments, Moments::of(pfm.complex.cells()));
+        assert_eq!((pfm.contact_count(), pfm.cycle_rank(), pfm.surface_area()), (4, 1, 16));
+    }
+
+    #[test]
+    fn rods_and_cubes() {
+        let rod = Polycube::new(5, Dist::Uniform);
+        let observables = rod.observables();
+        // the variance of 0..5 is 2
+        assert!(close(observables.radius_of_gyration, 2f64.sqrt()));
+        assert!(close(observables.gyration_eigenvalues[0], 2.0) && close(observables.gyration_eigenvalues[1], 0.0));
+        assert!(close(observables.asphericity, 1.0) && close(observables.prolateness, 2.0));
+        assert_eq!(observables.bounding_box, [1, 1, 5]);
+        assert_eq!((observables.surface_area, observables.cycle_rank), (22, 0));
+
+        let cube = polycube(block(2, 2, 2));
+        let observables = cube.observables();
+        assert!(observables.gyration_eigenvalues.iter().all(|eigenvalue| close(*eigenvalue, 0.25)));
+        assert!(close(observables.asphericity, 0.0) && close(observables.prolateness, 0.0));
+        assert_eq!((observables.contacts, observables.cycle_rank, observables.surface_area), (12, 5, 24));
+        assert_eq!(observables.values().len(), Observables::COLUMNS.len());
+    }
+
+    #[test]
+    fn eigenvalues_of_symmetric_matrices() {
+        let eigenvalues = symmetric_eigenvalues([[2.0, 1.0, 0.0], [1.0, 2.0, 0.0], [0.0, 0.0, 3.0]]);
+        assert!(close(eigenvalues[0], 3.0) && close(eigenvalues[1], 3.0) && close(eigenvalues[2], 1.0));
+        let eigenvalues = symmetric_eigenvalues([[4.0, 1.0, 2.0], [1.0, 0.0, 1.0], [2.0, 1.0, -1.0]]);
+        // the trace and determinant are the sum and product of the eigenvalues
+        assert!(close(eigenvalues.iter().sum(), 3.0));
+        assert!(close(eigenvalues.iter().product(), 1.0));
+        assert!(eigenvalues[0] >= eigenvalues[1] && eigenvalues[1] >= eigenvalues[2]);
+    }
+}
@@ -0,0 +1,291 @@
+//! Reading Perseus' cubical complex formats, which `export_analysis` writes.
+//!
+//! Both formats start with the dimension, which has to be 3. The sparse format follows it with one
+//! top cell per line as `x y z birth`; the birth is optional here, since older exports left it
+//! out. The dense format follows it with the size of the grid along each axis and then a birth
+//! for every cell of the grid with x varying fastest, where negative births are empty cells. Blank
+//! lines and `\r\n` line endings are fine in both.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::storage::CellStorage;
+use crate::{Dist, Polycube};
+
+type Cell = (i32, i32, i32);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// The file has no dimension line
+    Empty,
+    /// The dimension isn't 3
+    Dimension(String),
+    /// A token isn't an integer
+    InvalidNumber(String),
+    /// A sparse line doesn't have 3 coordinates and an optional birth
+    Columns(usize),
+    /// A dense file has more or fewer births than cells in its grid
+    Births { expected: usize, found: usize },
+    /// A dense file ends before the size of its grid along every axis
+    Size,
+    /// A dense grid has more cells than can be counted
+    TooLarge([usize; 3]),
+    /// A cell appears twice, when duplicates are rejected
+    Duplicate(Cell),
+    /// The cells aren't face-connected, when disconnected polycubes are rejected
+    Disconnected { components: usize },
+}
+
+/// An error in an analysis file, at a line and column counting from 1, or at 0, 0 for errors
+/// about the whole file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Empty => write!(f, "expected the dimension, found the end of the file"),
+            ErrorKind::Dimension(dimension) => write!(f, "only 3 dimensional complexes are supported, not {:?}", dimension),
+            ErrorKind::InvalidNumber(token) => write!(f, "expected an integer, found {:?}", token),
+            ErrorKind::Columns(columns) => write!(f, "expected x y z and an optional birth, found {} columns", columns),
+            ErrorKind::Births { expected, found } => write!(f, "expected {} births for the grid, found {}", expected, found),
+            ErrorKind::Size => write!(f, "expected the size of the grid along 3 axes"),
+            ErrorKind::TooLarge(size) => write!(f, "a grid of {}x{}x{} cells is too large", size[0], size[1], size[2]),
+            ErrorKind::Duplicate(cell) => write!(f, "{:?} appears more than once", cell),
+            ErrorKind::Disconnected { components } => write!(f, "the cells aren't connected, there are {} components", components),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.kind)
+        } else {
+            write!(f, "line {}, column {}: {}", self.line, self.column, self.kind)
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// What `Polycube::parse_analysis` rejects besides malformed files, and whether it restores pins
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub reject_duplicates: bool,
+    pub reject_disconnected: bool,
+    /// Pin the cells born at time 1 when the rest are born at time 2, as `export_analysis`
+    /// writes pinned cells. Other tools write filtrations like that too, so it's up to the caller
+    pub pinned_births: bool,
+}
+
+/// A whitespace separated token, with its line and column
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl Token<'_> {
+    fn error(&self, kind: ErrorKind) -> ParseError {
+        ParseError { line: self.line, column: self.column, kind }
+    }
+
+    fn parse<T: std::str::FromStr>(&self) -> Result<T, ParseError> {
+        self.text.parse::<T>().map_err(|_| self.error(ErrorKind::InvalidNumber(self.text.to_string())))
+    }
+}
+
+/// A cell read from a file, with where it was read
+struct Entry {
+    cell: Cell,
+    birth: i64,
+    line: usize,
+    column: usize,
+}
+
+/// The tokens of every non-blank line
+fn lines(analysis: &str) -> Vec<Vec<Token<'_>>> {
+    let mut lines = Vec::new();
+    for (index, line) in analysis.lines().enumerate() {
+        let mut tokens = Vec::new();
+        let mut rest = line;
+        while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+            let end = rest[start..].find(char::is_whitespace).map_or(rest.len(), |end| start + end);
+            let column = line.len() - rest.len() + start + 1;
+            tokens.push(Token { text: &rest[start..end], line: index + 1, column });
+            rest = &rest[end..];
+        }
+        if !tokens.is_empty() {
+            lines.push(tokens);
+        }
+    }
+    lines
+}
+
+/// The cells of a sparse file
+fn sparse(lines: &[Vec<Token>]) -> Result<Vec<Entry>, ParseError> {
+    let mut cells = Vec::new();
+    for tokens in lines {
+        if tokens.len() != 3 && tokens.len() != 4 {
+            return Err(tokens[0].error(ErrorKind::Columns(tokens.len())));
+        }
+        let cell = (tokens[0].parse()?, tokens[1].parse()?, tokens[2].parse()?);
+        let birth = match tokens.get(3) {
+            Some(birth) => birth.parse()?,
+            None => 1,
+        };
+        cells.push(Entry { cell, birth, line: tokens[0].line, column: tokens[0].column });
+    }
+    Ok(cells)
+}
+
+/// The cells of a dense file, skipping empty cells
+fn dense(lines: &[Vec<Token>]) -> Result<Vec<Entry>, ParseError> {
+    // Perseus reads dense files token by token, so lines don't matter after the dimension
+    let tokens: Vec<&Token> = lines.iter().flatten().collect();
+    if tokens.len() < 3 {
+        let (line, column) = tokens.last().map_or((0, 0), |token| (token.line, token.column));
+        return Err(ParseError { line, column, kind: ErrorKind::Size });
+    }
+    let mut size = [0usize; 3];
+    for (axis, token) in tokens.iter().take(3).enumerate() {
+        size[axis] = token.parse()?;
+    }
+
+    let births = &tokens[3..];
+    let expected = size[0]
+        .checked_mul(size[1])
+        .and_then(|area| area.checked_mul(size[2]))
+        .ok_or_else(|| tokens[2].error(ErrorKind::TooLarge(size)))?;
+    if births.len() != expected {
+        let (line, column) = tokens.last().map_or((0, 0), |token| (token.line, token.column));
+        return Err(ParseError { line, column, kind: ErrorKind::Births { expected, found: births.len() } });
+    }
+
+    let mut cells = Vec::new();
+    for (index, token) in births.iter().enumerate() {
+        let birth: i64 = token.parse()?;
+        if birth >= 0 {
+            let cell = (index % size[0], (index / size[0]) % size[1], index / (size[0] * size[1]));
+            cells.push(Entry { cell: (cell.0 as i32, cell.1 as i32, cell.2 as i32), birth, line: token.line, column: token.column });
+        }
+    }
+    Ok(cells)
+}
+
+impl<S: CellStorage> Polycube<S> {
+    /// Imports a polycube in either of Perseus' cubical formats, keeping duplicate cells once and
+    /// allowing disconnected ones
+    pub fn import_analysis(analysis: &str) -> Result<Polycube<S>, ParseError> {
+        Ok(Self::parse_analysis(analysis, &Options::default())?.0)
+    }
+
+    /// Imports a polycube in either of Perseus' cubical formats, along with the birth of every
+    /// cell
+    pub fn parse_analysis(analysis: &str, options: &Options) -> Result<(Polycube<S>, HashMap<Cell, i64>), ParseError> {
+        let lines = lines(analysis);
+        let header = lines.first().ok_or(ParseError { line: 0, column: 0, kind: ErrorKind::Empty })?;
+        if header.len() != 1 || header[0].text != "3" {
+            return Err(header[0].error(ErrorKind::Dimension(header.iter().map(|token| token.text).collect::<Vec<_>>().join(" "))));
+        }
+
+        // dense files have one number on each line after the dimension, sparse ones at least 3
+        let cells = match lines.get(1) {
+            Some(tokens) if tokens.len() == 1 => dense(&lines[1..])?,
+            _ => sparse(&lines[1..])?,
+        };
+
+        let mut births = HashMap::new();
+        let mut order = Vec::new();
+        for Entry { cell, birth, line, column } in cells {
+            if births.insert(cell, birth).is_none() {
+                order.push(cell);
+            } else if options.reject_duplicates {
+                return Err(ParseError { line, column, kind: ErrorKind::Duplicate(cell) });
+            }
+        }
+
+        let mut pfm = Polycube::with_cells(order, Dist::Uniform);
+
+        let mut times: Vec<i64> = births.values().copied().collect();
+        times.sort_unstable();
+        times.dedup();
+        if options.pinned_births && times == [1, 2] {
+            pfm.pinned = births.iter().filter(|(_, birth)| **birth == 1).map(|(cell, _)| *cell).collect();
+        }
+
+        if options.reject_disconnected && !pfm.is_connected() {
+            return Err(ParseError { line: 0, column: 0, kind: ErrorKind::Disconnected { components: pfm.components().len() } });
+        }
+
+        Ok((pfm, births))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{shuffled, sorted};
+    use crate::storage::CellSet;
+
+    #[test]
+    fn pinned_cells_survive_a_round_trip_when_asked() {
+        let mut pfm = Polycube::new(6, Dist::Uniform);
+        pfm.pin((0, 0, 0));
+        pfm.pin((0, 0, 3));
+
+        let options = Options { pinned_births: true, ..Options::default() };
+        let (imported, _): (Polycube, _) = Polycube::parse_analysis(&pfm.export_analysis(), &options).unwrap();
+        assert_eq!(imported.pinned(), pfm.pinned());
+
+        let (unpinned, _): (Polycube, _) = Polycube::parse_analysis(&Polycube::new(6, Dist::Uniform).export_analysis(), &options).unwrap();
+        assert!(unpinned.pinned().is_empty());
+
+        // a two step filtration from elsewhere is just a polycube
+        let filtration: Polycube = Polycube::import_analysis(&pfm.export_analysis()).unwrap();
+        assert!(filtration.pinned().is_empty());
+    }
+
+    #[test]
+    fn sparse_round_trip() {
+        let pfm = shuffled(43);
+        let imported: Polycube = Polycube::import_analysis(&pfm.export_analysis()).unwrap();
+        assert_eq!(sorted(&imported), sorted(&pfm));
+        assert_eq!(imported.insertable_locations.len(), pfm.insertable_locations.len());
+        assert_eq!(imported.contact_count(), pfm.contact_count());
+    }
+
+    #[test]
+    fn reads_dense_grids() {
+        // an L in a 2x2x1 grid, x varying fastest, with the cell at (0, 1, 0) empty
+        let pfm: Polycube = Polycube::import_analysis("3\n2\n2\n1\n1\n1\n-1\n1\n").unwrap();
+        assert_eq!(sorted(&pfm), vec![(0, 0, 0), (1, 0, 0), (1, 1, 0)]);
+        assert_eq!(pfm.insertable_locations.len(), 13);
+    }
+
+    #[test]
+    fn rejects_malformed_dense_headers() {
+        let kind = |analysis: &str| Polycube::<CellSet>::import_analysis(analysis).map(|_| ()).unwrap_err().kind;
+        assert_eq!(kind("3\n4294967296\n4294967296\n2\n"), ErrorKind::TooLarge([4294967296, 4294967296, 2]));
+        assert_eq!(kind("3\n2\n2\n"), ErrorKind::Size);
+        assert_eq!(kind("3\n1\n1\n2\n1\n"), ErrorKind::Births { expected: 2, found: 1 });
+    }
+
+    #[test]
+    fn reports_where_errors_are() {
+        let error = Polycube::<CellSet>::import_analysis("3\n0 0 0 1\n1 x 0 1\n").map(|_| ()).unwrap_err();
+        assert_eq!((error.line, error.column), (3, 3));
+        assert_eq!(error.kind, ErrorKind::InvalidNumber("x".to_string()));
+
+        let options = Options { reject_duplicates: true, reject_disconnected: true, ..Options::default() };
+        let duplicate = Polycube::<CellSet>::parse_analysis("3\n0 0 0\n0 0 0\n", &options).map(|_| ()).unwrap_err();
+        assert_eq!(duplicate.kind, ErrorKind::Duplicate((0, 0, 0)));
+        let disconnected = Polycube::<CellSet>::parse_analysis("3\n0 0 0\n2 0 0\n", &options).map(|_| ()).unwrap_err();
+        assert_eq!(disconnected.kind, ErrorKind::Disconnected { components: 2 });
+    }
+}
@@ -0,0 +1,198 @@
+//! Regions that confine a polycube: boxes, slabs and a wall to adsorb onto.
+//!
+//! A region masks `insertable_locations`, so moves never leave it, and weights every face a cell
+//! shares with the outside of the region by a contact energy.
+
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    /// Box between two corners, inclusive
+    Box { min: (i32, i32, i32), max: (i32, i32, i32) },
+    /// Slab between two planes perpendicular to an axis (0 for x, 1 for y, 2 for z), inclusive
+    Slab { axis: usize, min: i32, max: i32 },
+    /// Half-space z >= 0 with at least one cell on the wall z = 0, which models adsorption
+    Wall,
+}
+
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub shape: Shape,
+
+    // energy of each face shared with the outside of the region, in units of kT. Negative values
+    // attract the polycube to the surface
+    pub contact_energy: f64,
+}
+
+fn axis(c: &(i32, i32, i32), axis: usize) -> i32 {
+    match axis {
+        0 => c.0,
+        1 => c.1,
+        _ => c.2,
+    }
+}
+
+impl Region {
+    pub fn new(shape: Shape) -> Region {
+        Region { shape, contact_energy: 0.0 }
+    }
+
+    // O(1)
+    pub fn contains(&self, c: &(i32, i32, i32)) -> bool {
+        match &self.shape {
+            Shape::Box { min, max } => {
+                c.0 >= min.0 && c.0 <= max.0 && c.1 >= min.1 && c.1 <= max.1 && c.2 >= min.2 && c.2 <= max.2
+            }
+            Shape::Slab { axis: a, min, max } => axis(c, *a) >= *min && axis(c, *a) <= *max,
+            Shape::Wall => c.2 >= 0,
+        }
+    }
+
+    // O(1)
+    /// Number of faces of the cell that touch the outside of the region
+    pub fn surface_contacts(&self, c: &(i32, i32, i32)) -> usize {
+        [
+            (c.0, c.1, c.2 + 1),
+            (c.0, c.1, c.2 - 1),
+            (c.0, c.1 + 1, c.2),
+            (c.0, c.1 - 1, c.2),
+            (c.0 + 1, c.1, c.2),
+            (c.0 - 1, c.1, c.2),
+        ]
+        .iter()
+        .filter(|neighbor| !self.contains(neighbor))
+        .count()
+    }
+
+    /// Boltzmann weight ratio exp(-ε Δcontacts) of a move that removes one cell and inserts
+    /// another
+    pub fn weight_ratio(&self, removed: &(i32, i32, i32), inserted: &(i32, i32, i32)) -> f64 {
+        let delta = self.surface_contacts(inserted) as f64 - self.surface_contacts(removed) as f64;
+        (-self.contact_energy * delta).exp()
+    }
+
+    /// Whether the cells satisfy the attachment requirement of the region, if it has one
+    pub fn is_attached(&self, mut cells: impl Iterator<Item = (i32, i32, i32)>) -> bool {
+        match self.shape {
+            Shape::Wall => cells.any(|c| c.2 == 0),
+            _ => true,
+        }
+    }
+
+    /// Connected initial cells for a polycube of the given length inside the region: a
+    /// boustrophedon path through the region starting from its lowest corner. None if the region
+    /// is too small
+    pub fn initial_cells(&self, len: usize) -> Option<Vec<(i32, i32, i32)>> {
+        let unbounded = (0, len as i32 - 1);
+        let (x, y, z) = match &self.shape {
+            Shape::Box { min, max } => ((min.0, max.0), (min.1, max.1), (min.2, max.2)),
+            Shape::Slab { axis: 0, min, max } => ((*min, *max), unbounded, unbounded),
+            Shape::Slab { axis: 1, min, max } => (unbounded, (*min, *max), unbounded),
+            Shape::Slab { min, max, .. } => (unbounded, unbounded, (*min, *max)),
+            Shape::Wall => (unbounded, unbounded, unbounded),
+        };
+
+        let mut cells = Vec::with_capacity(len);
+        let mut row = 0;
+        for (layer, k) in (z.0..=z.1).enumerate() {
+            let js: Vec<i32> = if layer % 2 == 0 { (y.0..=y.1).collect() } else { (y.0..=y.1).rev().collect() };
+            for j in js {
+                let is: Vec<i32> = if row % 2 == 0 { (x.0..=x.1).collect() } else { (x.0..=x.1).rev().collect() };
+                row += 1;
+                for i in is {
+                    if cells.len() == len {
+                        return Some(cells);
+                    }
+                    cells.push((i, j, k));
+                }
+            }
+        }
+
+        if cells.len() == len {
+            Some(cells)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Shape {
+    type Err = String;
+
+    /// Parses "box:LxMxK" (a box with its lowest corner at the origin), "slab:AXIS:MIN:MAX"
+    /// (AXIS is x, y or z) or "wall"
+    fn from_str(s: &str) -> Result<Shape, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let int = |part: &str| part.trim().parse::<i32>().map_err(|e| format!("invalid number {:?}: {}", part, e));
+
+        match parts.as_slice() {
+            ["box", dims] => {
+                let dims = dims.split('x').map(int).collect::<Result<Vec<i32>, String>>()?;
+                match dims.as_slice() {
+                    [l, m, k] if *l > 0 && *m > 0 && *k > 0 => Ok(Shape::Box { min: (0, 0, 0), max: (l - 1, m - 1, k - 1) }),
+                    _ => Err(format!("expected box:LxMxK with positive sides, got {:?}", s)),
+                }
+            }
+            ["slab", a, min, max] => {
+                let axis = match *a {
+                    "x" => 0,
+                    "y" => 1,
+                    "z" => 2,
+                    _ => return Err(format!("expected slab axis x, y or z, got {:?}", a)),
+                };
+                let (min, max) = (int(min)?, int(max)?);
+                if min > max {
+                    return Err(format!("slab min {} is above max {}", min, max));
+                }
+                Ok(Shape::Slab { axis, min, max })
+            }
+            ["wall"] => Ok(Shape::Wall),
+            _ => Err(format!("expected box:LxMxK, slab:AXIS:MIN:MAX or wall, got {:?}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng, Dist, Polycube};
+
+    #[test]
+    fn parses_every_shape() {
+        assert_eq!("box:2x3x4".parse(), Ok(Shape::Box { min: (0, 0, 0), max: (1, 2, 3) }));
+        assert_eq!("slab:y:-1:1".parse(), Ok(Shape::Slab { axis: 1, min: -1, max: 1 }));
+        assert_eq!("wall".parse(), Ok(Shape::Wall));
+        for invalid in ["box:0x1x1", "box:2x2", "slab:w:0:1", "slab:z:2:1", "sphere"] {
+            assert!(invalid.parse::<Shape>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn initial_cells_fill_the_region_in_a_path() {
+        let region = Region::new("box:3x2x2".parse().unwrap());
+        let cells = region.initial_cells(12).unwrap();
+        assert!(cells.iter().all(|cell| region.contains(cell)));
+        // consecutive cells of the path share a face
+        for pair in cells.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert_eq!((a.0 - b.0).abs() + (a.1 - b.1).abs() + (a.2 - b.2).abs(), 1);
+        }
+        assert!(region.initial_cells(13).is_none());
+        assert_eq!(region.surface_contacts(&(0, 0, 0)), 3);
+    }
+
+    #[test]
+    fn shuffles_stay_inside_and_on_the_wall() {
+        rng::seed(30);
+        for shape in ["box:4x4x3", "slab:z:0:1", "wall"] {
+            let region = Region { shape: shape.parse().unwrap(), contact_energy: -1.0 };
+            let mut pfm: Polycube = Polycube::confined(30, Dist::Bernoulli(0.5), region.clone()).unwrap();
+            for _ in 0..20 {
+                pfm.shuffle(100);
+                assert!(pfm.complex.iter().all(|cell| region.contains(cell)), "{}", shape);
+                assert!(region.is_attached(pfm.complex.iter().copied()), "{}", shape);
+                assert!(pfm.insertable_locations.iter().all(|cell| region.contains(cell)), "{}", shape);
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! The random number generator behind every random choice in the crate.
+//!
+//! Each thread has its own generator, seeded from entropy until `seed` is called, after which a
+//! run is reproducible: cells are stored in `storage::CellSet`, whose iteration order doesn't
+//! change between runs either.
+
+use std::cell::{Cell, RefCell};
+
+use rand::distributions::{Distribution, Standard};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+    static SEED: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Reseeds the generator of the current thread
+pub fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+    SEED.with(|current| current.set(Some(seed)));
+}
+
+/// The seed the generator of the current thread was last seeded with, or None if it's still
+/// seeded from entropy
+pub fn current_seed() -> Option<u64> {
+    SEED.with(|current| current.get())
+}
+
+/// A handle to the generator of the current thread, a drop-in for `rand::thread_rng()`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadRng;
+
+pub fn thread_rng() -> ThreadRng {
+    ThreadRng
+}
+
+/// A drop-in for `rand::random()`
+pub fn random<T>() -> T
+where
+    Standard: Distribution<T>,
+{
+    thread_rng().gen()
+}
+
+impl RngCore for ThreadRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|rng| rng.borrow_mut().next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RNG.with(|rng| rng.borrow_mut().fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        RNG.with(|rng| rng.borrow_mut().try_fill_bytes(dest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Dist, Polycube};
+
+    #[test]
+    fn seeding_reproduces_a_run() {
+        let run = || {
+            seed(36);
+            let mut pfm = Polycube::new(25, Dist::Bernoulli(0.5));
+            pfm.shuffle(2000);
+            let mut cells: Vec<_> = pfm.complex.iter().copied().collect();
+            cells.sort_unstable();
+            (cells, random::<u64>())
+        };
+        assert_eq!(run(), run());
+        assert_eq!(current_seed(), Some(36));
+    }
+
+    #[test]
+    fn threads_start_unseeded() {
+        seed(1);
+        assert_eq!(std::thread::spawn(current_seed).join().unwrap(), None);
+    }
+}
@@ -0,0 +1,243 @@
+//! Flat views of a polycube: stacks of z-slices, to see inside it, and orthographic projections
+//! as SVG.
+//!
+//! A slice is one layer of cells between the lowest and highest z, seen from above with x to the
+//! right and y up. Besides occupied cells it can mark the site perimeter and the vacant cells of
+//! enclosed cavities, in color in PNGs and as gray levels in PGMs. Projections look down an axis
+//! at the topmost cell of every column, shaded lighter the closer it is.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::image::Image;
+use crate::storage::CellStorage;
+use crate::Polycube;
+
+type Cell = (i32, i32, i32);
+
+/// What a slice shows at a cell
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mark {
+    Empty,
+    Occupied,
+    Perimeter,
+    Cavity,
+}
+
+impl Mark {
+    pub fn color(&self) -> [u8; 3] {
+        match self {
+            Mark::Empty => [0, 0, 0],
+            Mark::Occupied => [102, 128, 230],
+            Mark::Perimeter => [0, 160, 0],
+            Mark::Cavity => [230, 40, 40],
+        }
+    }
+
+    pub fn gray(&self) -> u8 {
+        match self {
+            Mark::Empty => 0,
+            Mark::Occupied => 255,
+            Mark::Perimeter => 85,
+            Mark::Cavity => 170,
+        }
+    }
+}
+
+/// What slices mark besides occupied cells, and how large they are drawn
+#[derive(Clone, Debug)]
+pub struct SliceStyle {
+    pub perimeter: bool,
+    pub cavities: bool,
+    /// Pixels per cell
+    pub scale: usize,
+}
+
+impl Default for SliceStyle {
+    fn default() -> SliceStyle {
+        SliceStyle { perimeter: true, cavities: true, scale: 16 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SliceFormat {
+    Png,
+    Pgm,
+}
+
+/// One layer of cells, row by row from the highest y
+#[derive(Clone, Debug)]
+pub struct Slice {
+    pub z: i32,
+    pub width: usize,
+    pub height: usize,
+    pub marks: Vec<Mark>,
+}
+
+impl Slice {
+    pub fn to_image(&self, scale: usize) -> Image {
+        let mut image = Image::new(self.width * scale, self.height * scale, Mark::Empty.color());
+        for y in 0..image.height {
+            for x in 0..image.width {
+                image.set(x, y, self.marks[(y / scale) * self.width + x / scale].color());
+            }
+        }
+        image
+    }
+
+    /// The slice as a binary PGM, with the gray level of each mark
+    pub fn encode_pgm(&self, scale: usize) -> Vec<u8> {
+        let (width, height) = (self.width * scale, self.height * scale);
+        let mut pgm = format!("P5\n{} {}\n255\n", width, height).into_bytes();
+        for y in 0..height {
+            for x in 0..width {
+                pgm.push(self.marks[(y / scale) * self.width + x / scale].gray());
+            }
+        }
+        pgm
+    }
+}
+
+impl<S: CellStorage> Polycube<S> {
+    // O(volume of the bounding box)
+    /// A slice for every z between the lowest and highest cell. Slices span the bounding box in x
+    /// and y, one cell wider on every side when the site perimeter is marked
+    pub fn slices(&self, style: &SliceStyle) -> Vec<Slice> {
+        let (min, max) = match self.tight_bounds() {
+            Some(bounds) => bounds,
+            None => return Vec::new(),
+        };
+        let pad = style.perimeter as i32;
+        let (min_x, max_x, min_y, max_y) = (min.0 - pad, max.0 + pad, min.1 - pad, max.1 + pad);
+        let enclosed = if style.cavities { self.enclosed_cells() } else { Default::default() };
+
+        let mark = |cell: &Cell| {
+            if self.complex.contains(cell) {
+                Mark::Occupied
+            } else if enclosed.contains(cell) {
+                Mark::Cavity
+            } else if style.perimeter && self.insertable_locations.contains(cell) {
+                Mark::Perimeter
+            } else {
+                Mark::Empty
+            }
+        };
+
+        (min.2..=max.2)
+            .map(|z| Slice {
+                z,
+                width: (max_x - min_x + 1) as usize,
+                height: (max_y - min_y + 1) as usize,
+                marks: (min_y..=max_y).rev().flat_map(|y| (min_x..=max_x).map(move |x| (x, y, z))).map(|cell| mark(&cell)).collect(),
+            })
+            .collect()
+    }
+
+    /// Writes the slices to slice-000.png (or .pgm) and so on in the directory, from the lowest
+    /// z up, and returns how many were written
+    pub fn write_slices(&self, dir: &Path, style: &SliceStyle, format: SliceFormat) -> io::Result<usize> {
+        fs::create_dir_all(dir)?;
+        let slices = self.slices(style);
+        for (index, slice) in slices.iter().enumerate() {
+            match format {
+                SliceFormat::Png => slice.to_image(style.scale).save_png(&dir.join(format!("slice-{:03}.png", index)))?,
+                SliceFormat::Pgm => fs::write(dir.join(format!("slice-{:03}.pgm", index)), slice.encode_pgm(style.scale))?,
+            }
+        }
+        Ok(slices.len())
+    }
+
+    // O(n)
+    /// An orthographic projection looking down the given axis (0, 1 or 2 for x, y or z) from its
+    /// positive side, as SVG. Each column shows its topmost cell, lighter the closer it is
+    pub fn export_svg(&self, axis: usize, scale: usize) -> String {
+        let coordinate = |cell: &Cell, axis: usize| [cell.0, cell.1, cell.2][axis];
+        // the other two axes in cyclic order, so the view is right handed like the mesh export
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+        let mut tops = BTreeMap::new();
+        for cell in self.complex.cells() {
+            let depth = tops.entry((coordinate(&cell, u), coordinate(&cell, v))).or_insert(i32::MIN);
+            *depth = (*depth).max(coordinate(&cell, axis));
+        }
+
+        let bounds = |values: &mut dyn Iterator<Item = i32>| values.fold((i32::MAX, i32::MIN), |(min, max), value| (min.min(value), max.max(value)));
+        let (min_u, max_u) = bounds(&mut tops.keys().map(|column| column.0));
+        let (min_v, max_v) = bounds(&mut tops.keys().map(|column| column.1));
+        let (far, near) = bounds(&mut tops.values().copied());
+        let (width, height) = if tops.is_empty() { (0, 0) } else { ((max_u - min_u + 1) as usize * scale, (max_v - min_v + 1) as usize * scale) };
+
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n", width, height);
+        for ((a, b), depth) in tops {
+            let closeness = if near == far { 1.0 } else { (depth - far) as f32 / (near - far) as f32 };
+            let shade = 0.35 + 0.65 * closeness;
+            let color = Mark::Occupied.color().map(|channel| (channel as f32 * shade) as u8);
+            // v increases upwards, unlike SVG's y
+            let (x, y) = ((a - min_u) as usize * scale, (max_v - b) as usize * scale);
+            svg.push_str(&format!(
+                "  <rect x=\"{x}\" y=\"{y}\" width=\"{scale}\" height=\"{scale}\" fill=\"rgb({},{},{})\" stroke=\"black\" stroke-width=\"0.5\"/>\n",
+                color[0], color[1], color[2]
+            ));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{hollow_cube, polycube};
+
+    #[test]
+    fn slices_mark_cavities_and_the_perimeter() {
+        let cube = hollow_cube();
+        let slices = cube.slices(&SliceStyle { perimeter: false, ..SliceStyle::default() });
+        assert_eq!(slices.iter().map(|slice| slice.z).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!((slices[1].width, slices[1].height), (3, 3));
+        assert_eq!(slices[1].marks.iter().filter(|mark| **mark == Mark::Cavity).count(), 1);
+        assert_eq!(slices[1].marks[4], Mark::Cavity);
+        assert!(slices[0].marks.iter().all(|mark| *mark == Mark::Occupied));
+
+        let padded = cube.slices(&SliceStyle::default());
+        assert_eq!((padded[0].width, padded[0].height), (5, 5));
+        // the corners of the padding are diagonal to the cube, so they aren't on the perimeter
+        assert_eq!(padded[0].marks[0], Mark::Empty);
+        assert_eq!(padded[0].marks[1], Mark::Perimeter);
+    }
+
+    #[test]
+    fn rows_run_from_the_highest_y() {
+        let pfm = polycube([(0, 0, 0), (0, 1, 0), (1, 1, 0)]);
+        let slice = &pfm.slices(&SliceStyle { perimeter: false, cavities: false, scale: 1 })[0];
+        assert_eq!(slice.marks, [Mark::Occupied, Mark::Occupied, Mark::Occupied, Mark::Empty]);
+
+        let pgm = slice.encode_pgm(2);
+        assert!(pgm.starts_with(b"P5\n4 4\n255\n"));
+        assert_eq!(pgm.len(), b"P5\n4 4\n255\n".len() + 16);
+        assert_eq!(slice.to_image(3).get(5, 5), Mark::Empty.color());
+    }
+
+    #[test]
+    fn projections_show_every_column() {
+        let cube = hollow_cube();
+        for axis in 0..3 {
+            let svg = cube.export_svg(axis, 10);
+            assert!(svg.starts_with("<svg") && svg.contains("width=\"30\" height=\"30\""));
+            assert_eq!(svg.matches("<rect").count(), 9);
+        }
+        let empty = polycube([]);
+        assert_eq!(empty.export_svg(2, 10).matches("<rect").count(), 0);
+        assert!(empty.slices(&SliceStyle::default()).is_empty());
+    }
+
+    #[test]
+    fn writes_a_file_per_slice() {
+        let dir = std::env::temp_dir().join(format!("polyforms-slices-{}", std::process::id()));
+        assert_eq!(hollow_cube().write_slices(&dir, &SliceStyle::default(), SliceFormat::Pgm).unwrap(), 3);
+        assert!(dir.join("slice-002.pgm").exists() && !dir.join("slice-003.pgm").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
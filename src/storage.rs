@@ -0,0 +1,264 @@
+//! Storage backends for the cells of a polyform.
+//!
+//! Every neighbor probe in the shuffle is a membership test, so how the complex is stored
+//! dominates the cost of a move. `HashSet` hashes a tuple per probe, while `BitGrid` turns each
+//! probe into a bit test in a dense array indexed from the bounding box.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, BuildHasherDefault};
+
+/// A `HashSet` of cells with a fixed hasher, so that it iterates in the same order in every run
+/// and seeded runs can be reproduced
+pub type CellSet = HashSet<(i32, i32, i32), BuildHasherDefault<DefaultHasher>>;
+
+/// A set of lattice cells
+pub trait CellStorage: Clone + Default {
+    fn contains(&self, cell: &(i32, i32, i32)) -> bool;
+
+    /// Returns true if the cell was not already present
+    fn insert(&mut self, cell: (i32, i32, i32)) -> bool;
+
+    /// Returns true if the cell was present
+    fn remove(&mut self, cell: &(i32, i32, i32)) -> bool;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over every cell in the set, in no particular order
+    fn cells(&self) -> Box<dyn Iterator<Item = (i32, i32, i32)> + '_>;
+
+    /// An empty set that is cheap to fill with cells of this one, such as the visited set of a
+    /// search over this set
+    fn empty_like(&self) -> Self;
+}
+
+impl<H: BuildHasher + Clone + Default> CellStorage for HashSet<(i32, i32, i32), H> {
+    fn contains(&self, cell: &(i32, i32, i32)) -> bool {
+        HashSet::contains(self, cell)
+    }
+
+    fn insert(&mut self, cell: (i32, i32, i32)) -> bool {
+        HashSet::insert(self, cell)
+    }
+
+    fn remove(&mut self, cell: &(i32, i32, i32)) -> bool {
+        HashSet::remove(self, cell)
+    }
+
+    fn len(&self) -> usize {
+        HashSet::len(self)
+    }
+
+    fn cells(&self) -> Box<dyn Iterator<Item = (i32, i32, i32)> + '_> {
+        Box::new(self.iter().copied())
+    }
+
+    fn empty_like(&self) -> Self {
+        HashSet::with_capacity_and_hasher(self.len(), H::default())
+    }
+}
+
+/// A growable dense 3D bit array.
+///
+/// The grid covers a box around the cells it holds. Inserting outside of the box reallocates it
+/// around the tight bounding box of the current cells plus some slack on every side, so memory
+/// stays proportional to the volume of the polyform's bounding box rather than to everywhere it
+/// has ever been.
+#[derive(Clone, Default)]
+pub struct BitGrid {
+    // corner of the box with the smallest coordinates
+    min: (i32, i32, i32),
+    // extent of the box along x, y, z
+    dims: (usize, usize, usize),
+    bits: Vec<u64>,
+    len: usize,
+}
+
+impl BitGrid {
+    pub fn new() -> BitGrid {
+        BitGrid::default()
+    }
+
+    // O(1)
+    fn index(&self, cell: &(i32, i32, i32)) -> Option<usize> {
+        let x = cell.0 as i64 - self.min.0 as i64;
+        let y = cell.1 as i64 - self.min.1 as i64;
+        let z = cell.2 as i64 - self.min.2 as i64;
+
+        if x < 0 || y < 0 || z < 0 || x >= self.dims.0 as i64 || y >= self.dims.1 as i64 || z >= self.dims.2 as i64 {
+            return None;
+        }
+
+        Some((z as usize * self.dims.1 + y as usize) * self.dims.0 + x as usize)
+    }
+
+    fn cell(&self, index: usize) -> (i32, i32, i32) {
+        let x = index % self.dims.0;
+        let y = (index / self.dims.0) % self.dims.1;
+        let z = index / (self.dims.0 * self.dims.1);
+
+        (self.min.0 + x as i32, self.min.1 + y as i32, self.min.2 + z as i32)
+    }
+
+    fn volume(&self) -> usize {
+        self.dims.0 * self.dims.1 * self.dims.2
+    }
+
+    // O(volume)
+    /// Reallocates the grid so that it covers the current cells and the given cell
+    fn grow(&mut self, cell: (i32, i32, i32)) {
+        let mut min = cell;
+        let mut max = cell;
+        for c in self.cells() {
+            min = (min.0.min(c.0), min.1.min(c.1), min.2.min(c.2));
+            max = (max.0.max(c.0), max.1.max(c.1), max.2.max(c.2));
+        }
+
+        // leave a quarter of the extent free on each side so that a polyform wandering in one
+        // direction only reallocates a logarithmic number of times
+        let pad = |lo: i32, hi: i32| ((hi - lo + 1) / 4).max(2);
+        let pad = (pad(min.0, max.0), pad(min.1, max.1), pad(min.2, max.2));
+
+        let mut grown = BitGrid {
+            min: (min.0 - pad.0, min.1 - pad.1, min.2 - pad.2),
+            dims: (
+                (max.0 - min.0 + 1 + 2 * pad.0) as usize,
+                (max.1 - min.1 + 1 + 2 * pad.1) as usize,
+                (max.2 - min.2 + 1 + 2 * pad.2) as usize,
+            ),
+            bits: Vec::new(),
+            len: 0,
+        };
+        grown.bits = vec![0; grown.volume().div_ceil(64)];
+
+        for c in self.cells() {
+            grown.insert(c);
+        }
+
+        *self = grown;
+    }
+}
+
+impl CellStorage for BitGrid {
+    fn contains(&self, cell: &(i32, i32, i32)) -> bool {
+        match self.index(cell) {
+            Some(i) => self.bits[i >> 6] >> (i & 63) & 1 == 1,
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, cell: (i32, i32, i32)) -> bool {
+        let i = match self.index(&cell) {
+            Some(i) => i,
+            None => {
+                self.grow(cell);
+                self.index(&cell).expect("grown grid covers the cell")
+            }
+        };
+
+        let mask = 1u64 << (i & 63);
+        if self.bits[i >> 6] & mask != 0 {
+            return false;
+        }
+
+        self.bits[i >> 6] |= mask;
+        self.len += 1;
+        true
+    }
+
+    fn remove(&mut self, cell: &(i32, i32, i32)) -> bool {
+        let i = match self.index(cell) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        let mask = 1u64 << (i & 63);
+        if self.bits[i >> 6] & mask == 0 {
+            return false;
+        }
+
+        self.bits[i >> 6] &= !mask;
+        self.len -= 1;
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    // O(volume / 64 + n)
+    fn cells(&self) -> Box<dyn Iterator<Item = (i32, i32, i32)> + '_> {
+        Box::new(self.bits.iter().enumerate().flat_map(move |(w, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(self.cell(w * 64 + bit))
+            })
+        }))
+    }
+
+    fn empty_like(&self) -> Self {
+        BitGrid {
+            min: self.min,
+            dims: self.dims,
+            bits: vec![0; self.bits.len()],
+            len: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng, Dist, Polycube};
+    use rand::Rng;
+
+    fn sorted(storage: &impl CellStorage) -> Vec<(i32, i32, i32)> {
+        let mut cells: Vec<_> = storage.cells().collect();
+        cells.sort_unstable();
+        cells
+    }
+
+    #[test]
+    fn bit_grid_agrees_with_a_hash_set() {
+        rng::seed(27);
+        let mut grid = BitGrid::new();
+        let mut set = CellSet::default();
+        let mut offset = 0;
+        for step in 0..5000 {
+            // drift away from the origin so the grid keeps growing
+            if step % 500 == 0 {
+                offset += 40;
+            }
+            let coordinate = || rng::thread_rng().gen_range(-4..4);
+            let cell = (offset + coordinate(), coordinate(), -offset + coordinate());
+            if rng::random() {
+                assert_eq!(grid.insert(cell), set.insert(cell));
+            } else {
+                assert_eq!(CellStorage::remove(&mut grid, &cell), set.remove(&cell));
+            }
+            assert_eq!(grid.contains(&cell), set.contains(&cell));
+            assert_eq!(grid.len(), set.len());
+        }
+        assert_eq!(sorted(&grid), sorted(&set));
+        assert!(grid.empty_like().is_empty());
+    }
+
+    #[test]
+    fn shuffles_keep_the_perimeter_of_a_bit_grid() {
+        rng::seed(27);
+        let mut pfm: Polycube<BitGrid> = Polycube::with_storage(40, Dist::Bernoulli(0.5));
+        pfm.shuffle(3000);
+        let fresh: Polycube<BitGrid> = Polycube::with_cells(pfm.complex.cells(), Dist::Uniform);
+        assert_eq!(sorted(&pfm.insertable_locations), sorted(&fresh.insertable_locations));
+        assert!(pfm.is_connected());
+    }
+}
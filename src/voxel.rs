@@ -0,0 +1,291 @@
+//! MagicaVoxel `.vox` and `.binvox` voxel files.
+//!
+//! Both are sized from the tight bounding box, with the cell at its lowest corner at the origin of
+//! the file. `.vox` stores a color for every voxel, which a `Palette` picks: all alike, pinned
+//! cells, the cell a move inserted, or connected components. `.binvox` only stores occupancy, as
+//! run-length encoded bytes in a dense cube, and keeps the original position in its translation.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::storage::CellStorage;
+use crate::{Dist, Polycube};
+
+type Cell = (i32, i32, i32);
+
+/// How `.vox` exports color the cells
+#[derive(Clone, Debug, PartialEq)]
+pub enum Palette {
+    Uniform,
+    /// Pinned cells in orange
+    Pinned,
+    /// The cell inserted by a move, as returned by `shuffle`, in green
+    Inserted(Cell),
+    /// Each face-connected component in its own color
+    Components,
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// Parses uniform, pinned, components or inserted:x,y,z
+    fn from_str(s: &str) -> Result<Palette, String> {
+        match s {
+            "uniform" => Ok(Palette::Uniform),
+            "pinned" => Ok(Palette::Pinned),
+            "components" => Ok(Palette::Components),
+            _ => match s.strip_prefix("inserted:") {
+                Some(cell) => Ok(Palette::Inserted(crate::cli::parse_cell(cell)?)),
+                None => Err(format!("expected uniform, pinned, components or inserted:x,y,z, got {:?}", s)),
+            },
+        }
+    }
+}
+
+// palette indices, which count from 1 in .vox files
+const CELL: u8 = 1;
+const PINNED: u8 = 2;
+const INSERTED: u8 = 3;
+const FIRST_COMPONENT: u8 = 4;
+
+/// The 255 colors of the palette, as RGBA
+fn palette_colors() -> Vec<[u8; 4]> {
+    let mut colors = vec![[102, 128, 230, 255], [255, 153, 0, 255], [0, 255, 0, 255]];
+    // components go around the hue circle by the golden angle, so neighbors in order differ
+    for k in 0..(255 - colors.len()) {
+        let hue = (k as f32 * 137.508) % 360.0 / 60.0;
+        let x = 1.0 - (hue % 2.0 - 1.0).abs();
+        let (r, g, b) = match hue as u32 {
+            0 => (1.0, x, 0.0),
+            1 => (x, 1.0, 0.0),
+            2 => (0.0, 1.0, x),
+            3 => (0.0, x, 1.0),
+            4 => (x, 0.0, 1.0),
+            _ => (1.0, 0.0, x),
+        };
+        colors.push([(r * 230.0) as u8 + 25, (g * 230.0) as u8 + 25, (b * 230.0) as u8 + 25, 255]);
+    }
+    colors
+}
+
+fn chunk(vox: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    vox.extend_from_slice(id);
+    vox.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    vox.extend_from_slice(&(children.len() as u32).to_le_bytes());
+    vox.extend_from_slice(content);
+    vox.extend_from_slice(children);
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, String> {
+    bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or_else(|| format!("truncated at byte {}", at))
+}
+
+impl<S: CellStorage> Polycube<S> {
+    /// Exports the cells as a MagicaVoxel `.vox` file. Fails if the bounding box is more than 256
+    /// cells along any axis, the most MagicaVoxel allows
+    pub fn export_vox(&self, palette: &Palette) -> Result<Vec<u8>, String> {
+        let (min, max) = self.tight_bounds().unwrap_or(((0, 0, 0), (0, 0, 0)));
+        let size = (max.0 - min.0 + 1, max.1 - min.1 + 1, max.2 - min.2 + 1);
+        if size.0 > 256 || size.1 > 256 || size.2 > 256 {
+            return Err(format!(".vox models are at most 256 cells wide, this one is {}x{}x{}", size.0, size.1, size.2));
+        }
+
+        let mut colors: HashMap<Cell, u8> = HashMap::new();
+        if *palette == Palette::Components {
+            for (label, component) in self.components().into_iter().enumerate() {
+                let color = FIRST_COMPONENT + (label % (256 - FIRST_COMPONENT as usize)) as u8;
+                colors.extend(component.into_iter().map(|cell| (cell, color)));
+            }
+        }
+        let color = |cell: &Cell| match palette {
+            Palette::Pinned if self.is_pinned(cell) => PINNED,
+            Palette::Inserted(inserted) if inserted == cell => INSERTED,
+            Palette::Components => colors[cell],
+            _ => CELL,
+        };
+
+        let mut size_chunk = Vec::new();
+        for extent in [size.0, size.1, size.2] {
+            size_chunk.extend_from_slice(&(extent as u32).to_le_bytes());
+        }
+
+        let mut voxels = (self.complex.len() as u32).to_le_bytes().to_vec();
+        for cell in self.complex.cells() {
+            let offset = (cell.0 - min.0, cell.1 - min.1, cell.2 - min.2);
+            voxels.extend_from_slice(&[offset.0 as u8, offset.1 as u8, offset.2 as u8, color(&cell)]);
+        }
+
+        let rgba: Vec<u8> = palette_colors().into_iter().chain(std::iter::once([0, 0, 0, 0])).flatten().collect();
+
+        let mut children = Vec::new();
+        chunk(&mut children, b"SIZE", &size_chunk, &[]);
+        chunk(&mut children, b"XYZI", &voxels, &[]);
+        chunk(&mut children, b"RGBA", &rgba, &[]);
+
+        let mut vox = b"VOX ".to_vec();
+        vox.extend_from_slice(&150u32.to_le_bytes());
+        chunk(&mut vox, b"MAIN", &[], &children);
+        Ok(vox)
+    }
+
+    /// Imports the first model of a MagicaVoxel `.vox` file, ignoring colors
+    pub fn import_vox(vox: &[u8]) -> Result<Polycube<S>, String> {
+        if vox.get(..4) != Some(b"VOX ") {
+            return Err("not a .vox file".to_string());
+        }
+
+        // skip the header and the MAIN chunk's own header, and read its children
+        let mut at = 8 + 12;
+        while at < vox.len() {
+            let id = vox.get(at..at + 4).ok_or_else(|| format!("truncated at byte {}", at))?;
+            let content = read_u32(vox, at + 4)? as usize;
+            let children = read_u32(vox, at + 8)? as usize;
+            let start = at + 12;
+            if id == b"XYZI" {
+                let count = read_u32(vox, start)? as usize;
+                let data = vox.get(start + 4..start + 4 + 4 * count).ok_or("truncated XYZI chunk")?;
+                let cells = data.chunks(4).map(|voxel| (voxel[0] as i32, voxel[1] as i32, voxel[2] as i32));
+                return Polycube::try_from_cells(cells, Dist::Uniform);
+            }
+            at = start + content + children;
+        }
+        Err("no XYZI chunk".to_string())
+    }
+
+    /// Exports the cells as a `.binvox` file, a cube of run-length encoded voxels. The translation
+    /// is the lowest corner of the bounding box and the scale is the side of the cube, so that
+    /// voxels are one unit wide in the original coordinates
+    pub fn export_binvox(&self) -> Vec<u8> {
+        let (min, max) = self.tight_bounds().unwrap_or(((0, 0, 0), (0, 0, 0)));
+        let dim = (max.0 - min.0).max(max.1 - min.1).max(max.2 - min.2) as usize + 1;
+
+        let mut binvox = format!("#binvox 1\ndim {} {} {}\ntranslate {} {} {}\nscale {}\ndata\n", dim, dim, dim, min.0, min.1, min.2, dim).into_bytes();
+
+        // y runs fastest, then z, then x
+        let mut runs: Vec<(u8, u8)> = Vec::new();
+        for x in 0..dim as i32 {
+            for z in 0..dim as i32 {
+                for y in 0..dim as i32 {
+                    let value = self.complex.contains(&(min.0 + x, min.1 + y, min.2 + z)) as u8;
+                    match runs.last_mut() {
+                        Some((last, count)) if *last == value && *count < u8::MAX => *count += 1,
+                        _ => runs.push((value, 1)),
+                    }
+                }
+            }
+        }
+        for (value, count) in runs {
+            binvox.extend_from_slice(&[value, count]);
+        }
+        binvox
+    }
+
+    /// Imports a `.binvox` file, placing the voxels by its translation and scale
+    pub fn import_binvox(binvox: &[u8]) -> Result<Polycube<S>, String> {
+        let data = binvox.windows(5).position(|window| window == b"data\n").ok_or("no data section")?;
+        let header = std::str::from_utf8(&binvox[..data]).map_err(|e| e.to_string())?;
+
+        let mut dim = None;
+        let mut translate = [0.0f64; 3];
+        let mut scale = None;
+        for line in header.lines() {
+            let mut words = line.split_whitespace();
+            let key = words.next();
+            let numbers = words.map(|word| word.parse::<f64>().map_err(|e| format!("invalid {:?}: {}", line, e))).collect::<Result<Vec<f64>, String>>();
+            match (key, numbers) {
+                (Some("#binvox") | None, _) => (),
+                (Some("dim"), Ok(d)) if d.len() == 3 => dim = Some([d[0] as usize, d[1] as usize, d[2] as usize]),
+                (Some("translate"), Ok(t)) if t.len() == 3 => translate = [t[0], t[1], t[2]],
+                (Some("scale"), Ok(s)) if s.len() == 1 => scale = Some(s[0]),
+                (Some("dim" | "translate" | "scale"), Err(e)) => return Err(e),
+                _ => return Err(format!("invalid header line {:?}", line)),
+            }
+        }
+
+        // binvox dims are depth (x), width (z) and height (y)
+        let [depth, width, height] = dim.ok_or("no dim line")?;
+        let voxel = scale.unwrap_or(depth as f64) / depth.max(1) as f64;
+        let total = depth.checked_mul(width).and_then(|area| area.checked_mul(height)).ok_or("the grid is too large")?;
+
+        let mut cells = Vec::new();
+        let mut index = 0;
+        for run in binvox[data + 5..].chunks(2) {
+            let (value, count) = match run {
+                [value, count] => (*value, *count as usize),
+                _ => return Err("odd number of bytes in the data section".to_string()),
+            };
+            if index + count > total {
+                return Err(format!("more than the {} voxels of a {}x{}x{} grid", total, depth, width, height));
+            }
+            if value != 0 {
+                for i in index..index + count {
+                    let (x, z, y) = (i / (width * height), (i / height) % width, i % height);
+                    let position = |offset: usize, axis: usize| (translate[axis] + offset as f64 * voxel).round() as i32;
+                    cells.push((position(x, 0), position(y, 1), position(z, 2)));
+                }
+            }
+            index += count;
+        }
+
+        Polycube::try_from_cells(cells, Dist::Uniform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{shuffled, sorted};
+    use crate::storage::CellSet;
+
+    /// The cells, moved so the lowest corner of the bounding box is at the origin, and sorted
+    fn at_origin(pfm: &Polycube) -> Vec<Cell> {
+        let (min, _) = pfm.tight_bounds().unwrap();
+        let mut cells: Vec<Cell> = pfm.complex.iter().map(|cell| (cell.0 - min.0, cell.1 - min.1, cell.2 - min.2)).collect();
+        cells.sort_unstable();
+        cells
+    }
+
+    #[test]
+    fn vox_round_trip() {
+        let pfm = shuffled(42);
+        for palette in [Palette::Uniform, Palette::Pinned, Palette::Components, Palette::Inserted((0, 0, 0))] {
+            let imported: Polycube = Polycube::import_vox(&pfm.export_vox(&palette).unwrap()).unwrap();
+            assert_eq!(sorted(&imported), at_origin(&pfm));
+        }
+
+        let long: Polycube = Polycube::new(300, Dist::Uniform);
+        assert!(long.export_vox(&Palette::Uniform).is_err());
+        assert!(Polycube::<CellSet>::import_vox(b"VOX \x96\0\0\0").is_err());
+        assert!(Polycube::<CellSet>::import_vox(b"VOXEL").is_err());
+    }
+
+    #[test]
+    fn binvox_round_trip_keeps_the_position() {
+        let mut pfm = shuffled(43);
+        // a shape off the origin and into negative coordinates
+        let cells: Vec<Cell> = pfm.complex.iter().map(|cell| (cell.0 - 7, cell.1 + 3, cell.2 - 11)).collect();
+        pfm.set_cells(cells);
+        let imported: Polycube = Polycube::import_binvox(&pfm.export_binvox()).unwrap();
+        assert_eq!(sorted(&imported), sorted(&pfm));
+    }
+
+    #[test]
+    fn rejects_malformed_binvox() {
+        let header = b"#binvox 1\ndim 2 2 2\ntranslate 0 0 0\nscale 2\ndata\n".to_vec();
+        let with = |data: &[u8]| [header.as_slice(), data].concat();
+        assert!(Polycube::<CellSet>::import_binvox(&with(&[1, 1, 0, 7])).is_ok());
+        assert!(Polycube::<CellSet>::import_binvox(&with(&[1, 1, 0])).is_err());
+        assert!(Polycube::<CellSet>::import_binvox(&with(&[0, 8, 1, 1])).is_err());
+        assert!(Polycube::<CellSet>::import_binvox(b"#binvox 1\ndim 1 1 1\n").is_err());
+        assert!(Polycube::<CellSet>::import_binvox(b"#binvox 1\ndim 99999999999 99999999999 99999999999\ndata\n\x01\x01").is_err());
+        // two cells that only share an edge aren't a polycube
+        assert!(Polycube::<CellSet>::import_binvox(&with(&[1, 1, 0, 2, 1, 1, 0, 4])).is_err());
+    }
+
+    #[test]
+    fn parses_palettes() {
+        assert_eq!("components".parse(), Ok(Palette::Components));
+        assert_eq!("inserted:1,-2,3".parse(), Ok(Palette::Inserted((1, -2, 3))));
+        assert!("rainbow".parse::<Palette>().is_err());
+    }
+}